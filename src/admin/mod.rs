@@ -0,0 +1,1419 @@
+//! admin operations which are not covered by tibco_ems::admin
+//!
+//! TIBCO does not publish the protocol of the admin queue. The only public source is
+//! tibco_ems 0.5.2 (src/admin/mod.rs), it defines the command codes 16, 18, 19, 120, 127,
+//! 220 and 221, the header keys JMS_TIBCO_MSG_EXT, code, save and arseq and the body keys
+//! dn, dt, mb, mm, global, pf, expy, st, sn, tt, tn, sel and pattern of its commands.
+//! It reads failsafe, secure, rdd, op, sname, snameenf and nm from destination listings.
+//!
+//! All other command codes, body keys and the code and text headers of the reply are not
+//! backed by a public source and marked as unverified. This covers the body keys user,
+//! password, desc, group, perm, durable, cid, nolocal, jndiname, ft, url, cac, cad, cat,
+//! rac, rad, rat, the ssl_* keys, route, zn, zt, insel, outsel, mr, excl, fc, store, trace,
+//! import and export, as well as connected in route listings. They are checked by the tests of
+//! the ems_tests module, which run against a real EMS with
+//! `cargo test --features ems_integration`, see the README
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+use tibco_ems::admin::BridgeInfo;
+use tibco_ems::{Destination, MapMessage, Message, Session, TypedValue};
+
+const ADMIN_QUEUE_NAME: &str = "$sys.admin";
+const DESTINATION_TYPE_QUEUE: i32 = 1;
+const DESTINATION_TYPE_TOPIC: i32 = 2;
+
+/// admin command codes used on the admin queue, with the source of each code
+#[derive(Debug, Clone, PartialEq)]
+enum AdminCommands {
+    /// delete a destination (tibco_ems 0.5.2)
+    DeleteDestination = 16,
+    /// update the properties of a destination (unverified)
+    UpdateDestination = 17,
+    /// create a destination (tibco_ems 0.5.2)
+    CreateDestination = 18,
+    /// grant permissions on a destination (unverified)
+    GrantPermission = 34,
+    /// revoke permissions on a destination (unverified)
+    RevokePermission = 35,
+    /// create a user (unverified)
+    CreateUser = 40,
+    /// update the password or description of a user (unverified)
+    UpdateUser = 41,
+    /// delete a user (unverified)
+    DeleteUser = 42,
+    /// create a group (unverified)
+    CreateGroup = 43,
+    /// update the description of a group (unverified)
+    UpdateGroup = 44,
+    /// delete a group (unverified)
+    DeleteGroup = 45,
+    /// add a user to a group (unverified)
+    AddGroupMember = 46,
+    /// remove a user from a group (unverified)
+    RemoveGroupMember = 47,
+    /// list the users of a group (unverified)
+    ListGroupMembers = 48,
+    /// create a durable subscription (unverified)
+    CreateDurable = 50,
+    /// delete a durable subscription (unverified)
+    DeleteDurable = 51,
+    /// list durable subscriptions (unverified)
+    ListDurables = 52,
+    /// create a connection factory (unverified)
+    CreateFactory = 60,
+    /// update the properties of a connection factory (unverified)
+    UpdateFactory = 61,
+    /// delete a connection factory (unverified)
+    DeleteFactory = 62,
+    /// bind an additional JNDI name to a destination (unverified)
+    CreateJndiName = 63,
+    /// remove a JNDI name (unverified)
+    DeleteJndiName = 64,
+    /// create a route to another server (unverified)
+    CreateRoute = 70,
+    /// update the url and selectors of a route (unverified)
+    UpdateRoute = 71,
+    /// delete a route (unverified)
+    DeleteRoute = 72,
+    /// list routes with their connection state (unverified)
+    ListRoutes = 73,
+    /// create a bridge between two destinations (tibco_ems 0.5.2)
+    CreateBridge = 220,
+    /// delete a bridge (tibco_ems 0.5.2)
+    DeleteBridge = 221,
+}
+
+/// user on the EMS
+#[derive(Clone, PartialEq)]
+pub struct UserInfo {
+    pub name: String,
+    pub description: Option<String>,
+    pub password: String,
+}
+
+/// keeps the password out of the logs
+impl fmt::Debug for UserInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UserInfo")
+            .field("name", &self.name)
+            .field("description", &self.description)
+            .finish_non_exhaustive()
+    }
+}
+
+/// group on the EMS
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupInfo {
+    pub name: String,
+    pub description: Option<String>,
+}
+
+/// durable subscription on the EMS
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DurableInfo {
+    pub topic: String,
+    pub name: String,
+    pub client_id: Option<String>,
+    pub selector: Option<String>,
+    pub no_local: bool,
+    /// only filled by list_durables
+    pub pending_messages: Option<i64>,
+}
+
+impl DurableInfo {
+    /// identifies the durable on the EMS, durable names are unique per client id
+    pub fn key(&self) -> String {
+        match &self.client_id {
+            Some(client_id) => format!("{}:{}", client_id, self.name),
+            None => self.name.clone(),
+        }
+    }
+}
+
+/// type of the destinations a connection factory creates connections for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FactoryType {
+    Generic = 0,
+    Queue = 1,
+    Topic = 2,
+}
+
+/// JNDI connection factory on the EMS
+#[derive(Debug, Clone, PartialEq)]
+pub struct FactoryInfo {
+    /// JNDI name the factory is bound to
+    pub name: String,
+    pub factory_type: FactoryType,
+    pub url: String,
+    pub client_id: Option<String>,
+    pub connect_attempt_count: Option<i32>,
+    pub connect_attempt_delay: Option<i64>,
+    pub connect_attempt_timeout: Option<i64>,
+    pub reconnect_attempt_count: Option<i32>,
+    pub reconnect_attempt_delay: Option<i64>,
+    pub reconnect_attempt_timeout: Option<i64>,
+    pub ssl: Option<FactorySslProperties>,
+}
+
+/// SSL parameters of a connection factory, files are paths on the client
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FactorySslProperties {
+    pub verify_host: Option<bool>,
+    pub verify_hostname: Option<bool>,
+    pub expected_hostname: Option<String>,
+    /// comma separated list of trusted certificate files
+    pub trusted_certificates: Option<String>,
+    pub ciphers: Option<String>,
+}
+
+/// route to another EMS server
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RouteInfo {
+    /// name of the remote server
+    pub name: String,
+    pub url: String,
+    pub zone_name: Option<String>,
+    /// either 1hop or mhop
+    pub zone_type: Option<String>,
+    pub selectors: Vec<RouteSelector>,
+    /// only filled by list_routes
+    pub connected: Option<bool>,
+}
+
+/// selector restricting the messages of a topic which are forwarded over a route
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteSelector {
+    /// messages received from the remote server if set, otherwise messages sent to it
+    pub incoming: bool,
+    pub topic: String,
+    pub selector: String,
+}
+
+/// destination permissions as bit flags
+pub const PERMISSION_SEND: i64 = 0x01;
+pub const PERMISSION_RECEIVE: i64 = 0x02;
+pub const PERMISSION_BROWSE: i64 = 0x04;
+pub const PERMISSION_PUBLISH: i64 = 0x08;
+pub const PERMISSION_SUBSCRIBE: i64 = 0x10;
+pub const PERMISSION_DURABLE: i64 = 0x20;
+
+/// user or group which is granted permissions
+#[derive(Debug, Clone, PartialEq)]
+pub enum Principal {
+    User(String),
+    Group(String),
+}
+
+/// permissions of a principal on a destination
+#[derive(Debug, Clone, PartialEq)]
+pub struct PermissionInfo {
+    pub destination: Destination,
+    pub principal: Principal,
+    /// combination of the PERMISSION_* flags
+    pub permissions: i64,
+}
+
+/// properties shared by queues and topics which tibco_ems::admin does not cover
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExtendedProperties {
+    pub failsafe: Option<bool>,
+    pub secure: Option<bool>,
+    /// flow control limit in bytes, 0 disables flow control
+    pub flow_control: Option<i64>,
+    pub store: Option<String>,
+    /// an empty value switches tracing off (unverified)
+    pub trace: Option<String>,
+    pub sender_name: Option<bool>,
+    pub sender_name_enforced: Option<bool>,
+    /// comma separated list of transports, an empty list removes all (unverified)
+    pub import: Option<String>,
+    /// comma separated list of transports, an empty list removes all (unverified)
+    pub export: Option<String>,
+}
+
+/// properties of a queue which can be set on the EMS
+///
+/// tibco_ems::admin::QueueInfo lacks some of them, e.g. the max redelivery count
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QueueProperties {
+    pub name: String,
+    pub max_bytes: Option<i64>,
+    pub max_messages: Option<i64>,
+    pub overflow_policy: Option<i32>,
+    pub global: Option<bool>,
+    pub prefetch: Option<i32>,
+    pub expiry_override: Option<i64>,
+    pub redelivery_delay: Option<i64>,
+    pub max_redelivery: Option<i32>,
+    pub exclusive: Option<bool>,
+    pub extended: ExtendedProperties,
+}
+
+/// properties of a topic which can be set on the EMS
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TopicProperties {
+    pub name: String,
+    pub max_bytes: Option<i64>,
+    pub max_messages: Option<i64>,
+    pub overflow_policy: Option<i32>,
+    pub global: Option<bool>,
+    pub prefetch: Option<i32>,
+    pub expiry_override: Option<i64>,
+    pub extended: ExtendedProperties,
+}
+
+/// creates a queue on the EMS with all properties which are set
+pub fn create_queue(
+    session: &Session,
+    queue: &QueueProperties,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let mut msg = queue_message(queue);
+    msg.header = Some(admin_header(AdminCommands::CreateDestination));
+    admin_request(session, msg, timeout).inspect_err(|err| {
+        error!("error while creating queue {}: {}", queue.name, err);
+    })
+}
+
+/// updates the properties of an existing queue on the EMS
+///
+/// only properties which are set on the QueueProperties are sent to the server
+pub fn update_queue(
+    session: &Session,
+    queue: &QueueProperties,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let mut msg = queue_message(queue);
+    msg.header = Some(admin_header(AdminCommands::UpdateDestination));
+    admin_request(session, msg, timeout).inspect_err(|err| {
+        error!("error while updating queue {}: {}", queue.name, err);
+    })
+}
+
+/// deletes a queue from the EMS, fails with NotFound if the queue does not exist
+pub fn delete_queue(session: &Session, name: &str, timeout: Duration) -> Result<(), Error> {
+    let mut msg: MapMessage = Default::default();
+    msg.body
+        .insert("dn".to_string(), TypedValue::String(name.to_string()));
+    msg.body.insert(
+        "dt".to_string(),
+        TypedValue::Integer(DESTINATION_TYPE_QUEUE),
+    );
+    msg.header = Some(admin_header(AdminCommands::DeleteDestination));
+    admin_request(session, msg, timeout).inspect_err(|err| {
+        error!("error while deleting queue {}: {}", name, err);
+    })
+}
+
+/// creates a topic on the EMS with all properties which are set
+pub fn create_topic(
+    session: &Session,
+    topic: &TopicProperties,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let mut msg = topic_message(topic);
+    msg.header = Some(admin_header(AdminCommands::CreateDestination));
+    admin_request(session, msg, timeout).inspect_err(|err| {
+        error!("error while creating topic {}: {}", topic.name, err);
+    })
+}
+
+/// updates the properties of an existing topic on the EMS
+///
+/// only properties which are set on the TopicProperties are sent to the server
+pub fn update_topic(
+    session: &Session,
+    topic: &TopicProperties,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let mut msg = topic_message(topic);
+    msg.header = Some(admin_header(AdminCommands::UpdateDestination));
+    admin_request(session, msg, timeout).inspect_err(|err| {
+        error!("error while updating topic {}: {}", topic.name, err);
+    })
+}
+
+/// deletes a topic from the EMS, fails with NotFound if the topic does not exist
+pub fn delete_topic(session: &Session, name: &str, timeout: Duration) -> Result<(), Error> {
+    let mut msg: MapMessage = Default::default();
+    msg.body
+        .insert("dn".to_string(), TypedValue::String(name.to_string()));
+    msg.body.insert(
+        "dt".to_string(),
+        TypedValue::Integer(DESTINATION_TYPE_TOPIC),
+    );
+    msg.header = Some(admin_header(AdminCommands::DeleteDestination));
+    admin_request(session, msg, timeout).inspect_err(|err| {
+        error!("error while deleting topic {}: {}", name, err);
+    })
+}
+
+/// grants the permissions to the principal, existing permissions are kept
+pub fn grant_permission(
+    session: &Session,
+    permission: &PermissionInfo,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let mut msg = permission_message(permission);
+    msg.header = Some(admin_header(AdminCommands::GrantPermission));
+    admin_request(session, msg, timeout).inspect_err(|err| {
+        error!("error while granting {:?}: {}", permission, err);
+    })
+}
+
+/// revokes the permissions from the principal
+pub fn revoke_permission(
+    session: &Session,
+    permission: &PermissionInfo,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let mut msg = permission_message(permission);
+    msg.header = Some(admin_header(AdminCommands::RevokePermission));
+    admin_request(session, msg, timeout).inspect_err(|err| {
+        error!("error while revoking {:?}: {}", permission, err);
+    })
+}
+
+/// creates a user on the EMS, fails with AlreadyExists if the user exists
+pub fn create_user(session: &Session, user: &UserInfo, timeout: Duration) -> Result<(), Error> {
+    let mut msg = user_message(user);
+    msg.header = Some(admin_header(AdminCommands::CreateUser));
+    admin_request(session, msg, timeout)
+}
+
+/// sets the password and description of an existing user on the EMS
+pub fn update_user(session: &Session, user: &UserInfo, timeout: Duration) -> Result<(), Error> {
+    let mut msg = user_message(user);
+    msg.header = Some(admin_header(AdminCommands::UpdateUser));
+    admin_request(session, msg, timeout).inspect_err(|err| {
+        error!("error while updating user {}: {}", user.name, err);
+    })
+}
+
+/// deletes a user from the EMS, fails with NotFound if the user does not exist
+pub fn delete_user(session: &Session, user: &str, timeout: Duration) -> Result<(), Error> {
+    let mut msg: MapMessage = Default::default();
+    msg.body
+        .insert("user".to_string(), TypedValue::String(user.to_string()));
+    msg.header = Some(admin_header(AdminCommands::DeleteUser));
+    admin_request(session, msg, timeout).inspect_err(|err| {
+        error!("error while deleting user {}: {}", user, err);
+    })
+}
+
+/// creates a group on the EMS, fails with AlreadyExists if the group exists
+pub fn create_group(session: &Session, group: &GroupInfo, timeout: Duration) -> Result<(), Error> {
+    let mut msg = group_message(group);
+    msg.header = Some(admin_header(AdminCommands::CreateGroup));
+    admin_request(session, msg, timeout)
+}
+
+/// sets the description of an existing group on the EMS
+pub fn update_group(session: &Session, group: &GroupInfo, timeout: Duration) -> Result<(), Error> {
+    let mut msg = group_message(group);
+    msg.header = Some(admin_header(AdminCommands::UpdateGroup));
+    admin_request(session, msg, timeout).inspect_err(|err| {
+        error!("error while updating group {}: {}", group.name, err);
+    })
+}
+
+/// deletes a group from the EMS, fails with NotFound if the group does not exist
+pub fn delete_group(session: &Session, group: &str, timeout: Duration) -> Result<(), Error> {
+    let mut msg: MapMessage = Default::default();
+    msg.body
+        .insert("group".to_string(), TypedValue::String(group.to_string()));
+    msg.header = Some(admin_header(AdminCommands::DeleteGroup));
+    admin_request(session, msg, timeout).inspect_err(|err| {
+        error!("error while deleting group {}: {}", group, err);
+    })
+}
+
+/// adds the user to the group
+pub fn add_group_member(
+    session: &Session,
+    group: &str,
+    user: &str,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let mut msg = member_message(group, user);
+    msg.header = Some(admin_header(AdminCommands::AddGroupMember));
+    admin_request(session, msg, timeout).inspect_err(|err| {
+        error!("error while adding {} to group {}: {}", user, group, err);
+    })
+}
+
+/// removes the user from the group
+pub fn remove_group_member(
+    session: &Session,
+    group: &str,
+    user: &str,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let mut msg = member_message(group, user);
+    msg.header = Some(admin_header(AdminCommands::RemoveGroupMember));
+    admin_request(session, msg, timeout).inspect_err(|err| {
+        error!(
+            "error while removing {} from group {}: {}",
+            user, group, err
+        );
+    })
+}
+
+/// lists the users of a group as reported by the EMS
+pub fn list_group_members(
+    session: &Session,
+    group: &str,
+    timeout: Duration,
+) -> Result<Vec<String>, Error> {
+    let mut msg: MapMessage = Default::default();
+    msg.body
+        .insert("group".to_string(), TypedValue::String(group.to_string()));
+    msg.header = Some(admin_header(AdminCommands::ListGroupMembers));
+
+    let admin_queue = Destination::Queue(ADMIN_QUEUE_NAME.to_string());
+    let response = session
+        .request_reply(&admin_queue, msg, timeout.as_millis() as i64)
+        .inspect_err(|err| error!("error while listing members of group {}: {}", group, err))?;
+    let mut members = Vec::new();
+    match &response {
+        Some(Message::MapMessage(map_message)) => {
+            check_reply(map_message)?;
+            for val in map_message.body.values() {
+                let TypedValue::Map(u_info) = val else {
+                    warn!("unknown entry in group member information");
+                    continue;
+                };
+                match string_value(u_info, "user") {
+                    Some(user) => members.push(user),
+                    None => warn!("group member information without name"),
+                }
+            }
+        }
+        Some(_) => warn!("unknown response from group member request"),
+        None => {
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                format!("no reply to group member request within {timeout:?}"),
+            ));
+        }
+    }
+    Ok(members)
+}
+
+/// creates a durable subscription on the EMS
+pub fn create_durable(
+    session: &Session,
+    durable: &DurableInfo,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let mut msg: MapMessage = Default::default();
+    msg.body
+        .insert("dn".to_string(), TypedValue::String(durable.topic.clone()));
+    msg.body.insert(
+        "durable".to_string(),
+        TypedValue::String(durable.name.clone()),
+    );
+    if let Some(val) = &durable.client_id {
+        msg.body
+            .insert("cid".to_string(), TypedValue::String(val.clone()));
+    }
+    if let Some(val) = &durable.selector {
+        msg.body
+            .insert("sel".to_string(), TypedValue::String(val.clone()));
+    }
+    msg.body
+        .insert("nolocal".to_string(), TypedValue::Boolean(durable.no_local));
+    msg.header = Some(admin_header(AdminCommands::CreateDurable));
+    admin_request(session, msg, timeout).inspect_err(|err| {
+        error!("error while creating durable {}: {}", durable.name, err);
+    })
+}
+
+/// unsubscribes a durable subscription on the EMS
+pub fn delete_durable(
+    session: &Session,
+    durable: &DurableInfo,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let mut msg: MapMessage = Default::default();
+    msg.body.insert(
+        "durable".to_string(),
+        TypedValue::String(durable.name.clone()),
+    );
+    if let Some(val) = &durable.client_id {
+        msg.body
+            .insert("cid".to_string(), TypedValue::String(val.clone()));
+    }
+    msg.header = Some(admin_header(AdminCommands::DeleteDurable));
+    admin_request(session, msg, timeout).inspect_err(|err| {
+        error!("error while deleting durable {}: {}", durable.name, err);
+    })
+}
+
+/// lists all durable subscriptions present on the EMS
+pub fn list_durables(session: &Session, timeout: Duration) -> Result<Vec<DurableInfo>, Error> {
+    let mut msg: MapMessage = Default::default();
+    msg.body
+        .insert("pattern".to_string(), TypedValue::String(">".to_string()));
+    msg.header = Some(admin_header(AdminCommands::ListDurables));
+
+    let admin_queue = Destination::Queue(ADMIN_QUEUE_NAME.to_string());
+    let response = session
+        .request_reply(&admin_queue, msg, timeout.as_millis() as i64)
+        .inspect_err(|err| error!("error while listing durables: {}", err))?;
+    let mut durables = Vec::new();
+    match &response {
+        Some(Message::MapMessage(map_message)) => {
+            for val in map_message.body.values() {
+                let TypedValue::Map(d_info) = val else {
+                    warn!("unknown entry in durable information");
+                    continue;
+                };
+                let (Some(name), Some(topic)) =
+                    (string_value(d_info, "durable"), string_value(d_info, "dn"))
+                else {
+                    warn!("durable information without name or topic");
+                    continue;
+                };
+                durables.push(DurableInfo {
+                    topic,
+                    name,
+                    client_id: string_value(d_info, "cid"),
+                    selector: string_value(d_info, "sel"),
+                    no_local: string_value(d_info, "nolocal").as_deref() == Some("1"),
+                    pending_messages: string_value(d_info, "nm").and_then(|val| val.parse().ok()),
+                });
+            }
+        }
+        _ => warn!("unknown response from durable information request"),
+    }
+    Ok(durables)
+}
+
+/// creates a connection factory on the EMS, fails with AlreadyExists if the factory exists
+pub fn create_factory(
+    session: &Session,
+    factory: &FactoryInfo,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let mut msg = factory_message(factory);
+    msg.header = Some(admin_header(AdminCommands::CreateFactory));
+    admin_request(session, msg, timeout)
+}
+
+/// sets the properties of an existing connection factory on the EMS
+pub fn update_factory(
+    session: &Session,
+    factory: &FactoryInfo,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let mut msg = factory_message(factory);
+    msg.header = Some(admin_header(AdminCommands::UpdateFactory));
+    admin_request(session, msg, timeout).inspect_err(|err| {
+        error!("error while updating factory {}: {}", factory.name, err);
+    })
+}
+
+/// deletes a connection factory from the EMS, fails with NotFound if the factory does not exist
+pub fn delete_factory(session: &Session, name: &str, timeout: Duration) -> Result<(), Error> {
+    let mut msg: MapMessage = Default::default();
+    msg.body
+        .insert("jndiname".to_string(), TypedValue::String(name.to_string()));
+    msg.header = Some(admin_header(AdminCommands::DeleteFactory));
+    admin_request(session, msg, timeout).inspect_err(|err| {
+        error!("error while deleting factory {}: {}", name, err);
+    })
+}
+
+/// binds an additional JNDI name to the destination, fails with AlreadyExists if the name is bound
+pub fn create_jndi_name(
+    session: &Session,
+    name: &str,
+    destination: &Destination,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let mut msg: MapMessage = Default::default();
+    let (destination_name, destination_type) = match destination {
+        Destination::Queue(name) => (name, DESTINATION_TYPE_QUEUE),
+        Destination::Topic(name) => (name, DESTINATION_TYPE_TOPIC),
+    };
+    msg.body
+        .insert("jndiname".to_string(), TypedValue::String(name.to_string()));
+    msg.body.insert(
+        "dn".to_string(),
+        TypedValue::String(destination_name.clone()),
+    );
+    msg.body
+        .insert("dt".to_string(), TypedValue::Integer(destination_type));
+    msg.header = Some(admin_header(AdminCommands::CreateJndiName));
+    admin_request(session, msg, timeout)
+}
+
+/// removes a JNDI name, the destination it is bound to is kept
+pub fn delete_jndi_name(session: &Session, name: &str, timeout: Duration) -> Result<(), Error> {
+    let mut msg: MapMessage = Default::default();
+    msg.body
+        .insert("jndiname".to_string(), TypedValue::String(name.to_string()));
+    msg.header = Some(admin_header(AdminCommands::DeleteJndiName));
+    admin_request(session, msg, timeout).inspect_err(|err| {
+        error!("error while deleting JNDI name {}: {}", name, err);
+    })
+}
+
+/// creates a route on the EMS
+pub fn create_route(session: &Session, route: &RouteInfo, timeout: Duration) -> Result<(), Error> {
+    let mut msg = route_message(route);
+    msg.header = Some(admin_header(AdminCommands::CreateRoute));
+    admin_request(session, msg, timeout).inspect_err(|err| {
+        error!("error while creating route {}: {}", route.name, err);
+    })
+}
+
+/// sets the url and selectors of an existing route, the zone cannot be changed
+pub fn update_route(session: &Session, route: &RouteInfo, timeout: Duration) -> Result<(), Error> {
+    let mut msg = route_message(route);
+    msg.header = Some(admin_header(AdminCommands::UpdateRoute));
+    admin_request(session, msg, timeout).inspect_err(|err| {
+        error!("error while updating route {}: {}", route.name, err);
+    })
+}
+
+/// deletes a route from the EMS
+pub fn delete_route(session: &Session, name: &str, timeout: Duration) -> Result<(), Error> {
+    let mut msg: MapMessage = Default::default();
+    msg.body
+        .insert("route".to_string(), TypedValue::String(name.to_string()));
+    msg.header = Some(admin_header(AdminCommands::DeleteRoute));
+    admin_request(session, msg, timeout).inspect_err(|err| {
+        error!("error while deleting route {}: {}", name, err);
+    })
+}
+
+/// lists all routes of the EMS, selectors are not reported
+pub fn list_routes(session: &Session, timeout: Duration) -> Result<Vec<RouteInfo>, Error> {
+    let msg = MapMessage {
+        header: Some(admin_header(AdminCommands::ListRoutes)),
+        ..Default::default()
+    };
+
+    let admin_queue = Destination::Queue(ADMIN_QUEUE_NAME.to_string());
+    let response = session
+        .request_reply(&admin_queue, msg, timeout.as_millis() as i64)
+        .inspect_err(|err| error!("error while listing routes: {}", err))?;
+    let mut routes = Vec::new();
+    match &response {
+        Some(Message::MapMessage(map_message)) => {
+            for val in map_message.body.values() {
+                let TypedValue::Map(r_info) = val else {
+                    warn!("unknown entry in route information");
+                    continue;
+                };
+                let Some(name) = string_value(r_info, "route") else {
+                    warn!("route information without name");
+                    continue;
+                };
+                routes.push(RouteInfo {
+                    name,
+                    url: string_value(r_info, "url").unwrap_or_default(),
+                    zone_name: string_value(r_info, "zn"),
+                    zone_type: string_value(r_info, "zt"),
+                    selectors: Vec::new(),
+                    connected: string_value(r_info, "connected").map(|val| val == "1"),
+                });
+            }
+        }
+        _ => warn!("unknown response from route information request"),
+    }
+    Ok(routes)
+}
+
+/// creates a bridge on the EMS, fails if the EMS rejects it
+pub fn create_bridge(
+    session: &Session,
+    bridge: &BridgeInfo,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let mut msg = bridge_message(bridge);
+    msg.header = Some(admin_header(AdminCommands::CreateBridge));
+    admin_request(session, msg, timeout).inspect_err(|err| {
+        error!(
+            "error while creating bridge {:?}->{:?}: {}",
+            bridge.source, bridge.target, err
+        );
+    })
+}
+
+/// deletes a bridge from the EMS, fails if the EMS rejects it
+pub fn delete_bridge(
+    session: &Session,
+    bridge: &BridgeInfo,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let mut msg = bridge_message(bridge);
+    msg.header = Some(admin_header(AdminCommands::DeleteBridge));
+    admin_request(session, msg, timeout).inspect_err(|err| {
+        error!(
+            "error while deleting bridge {:?}->{:?}: {}",
+            bridge.source, bridge.target, err
+        );
+    })
+}
+
+/// reads a value of a map message as string, the EMS sends most values as strings
+fn string_value(msg: &MapMessage, key: &str) -> Option<String> {
+    match msg.body.get(key)? {
+        TypedValue::String(val) => Some(val.clone()),
+        TypedValue::Boolean(val) => Some(if *val { "1" } else { "0" }.to_string()),
+        TypedValue::Integer(val) => Some(val.to_string()),
+        TypedValue::Long(val) => Some(val.to_string()),
+        _ => None,
+    }
+}
+
+/// creates the message body holding source, target and selector of the bridge
+fn bridge_message(bridge: &BridgeInfo) -> MapMessage {
+    let mut msg: MapMessage = Default::default();
+    let (source_name, source_type) = match &bridge.source {
+        Destination::Queue(name) => (name, DESTINATION_TYPE_QUEUE),
+        Destination::Topic(name) => (name, DESTINATION_TYPE_TOPIC),
+    };
+    msg.body
+        .insert("st".to_string(), TypedValue::Integer(source_type));
+    msg.body
+        .insert("sn".to_string(), TypedValue::String(source_name.clone()));
+    let (target_name, target_type) = match &bridge.target {
+        Destination::Queue(name) => (name, DESTINATION_TYPE_QUEUE),
+        Destination::Topic(name) => (name, DESTINATION_TYPE_TOPIC),
+    };
+    msg.body
+        .insert("tt".to_string(), TypedValue::Integer(target_type));
+    msg.body
+        .insert("tn".to_string(), TypedValue::String(target_name.clone()));
+    if let Some(val) = &bridge.selector {
+        msg.body
+            .insert("sel".to_string(), TypedValue::String(val.clone()));
+    }
+    msg
+}
+
+/// creates the message body holding the user
+fn user_message(user: &UserInfo) -> MapMessage {
+    let mut msg: MapMessage = Default::default();
+    msg.body
+        .insert("user".to_string(), TypedValue::String(user.name.clone()));
+    msg.body.insert(
+        "password".to_string(),
+        TypedValue::String(user.password.clone()),
+    );
+    if let Some(val) = &user.description {
+        msg.body
+            .insert("desc".to_string(), TypedValue::String(val.clone()));
+    }
+    msg
+}
+
+/// creates the message body holding the group
+fn group_message(group: &GroupInfo) -> MapMessage {
+    let mut msg: MapMessage = Default::default();
+    msg.body
+        .insert("group".to_string(), TypedValue::String(group.name.clone()));
+    if let Some(val) = &group.description {
+        msg.body
+            .insert("desc".to_string(), TypedValue::String(val.clone()));
+    }
+    msg
+}
+
+/// creates the message body holding a group membership
+fn member_message(group: &str, user: &str) -> MapMessage {
+    let mut msg: MapMessage = Default::default();
+    msg.body
+        .insert("group".to_string(), TypedValue::String(group.to_string()));
+    msg.body
+        .insert("user".to_string(), TypedValue::String(user.to_string()));
+    msg
+}
+
+/// creates the message body holding the connection factory
+fn factory_message(factory: &FactoryInfo) -> MapMessage {
+    let mut msg: MapMessage = Default::default();
+    msg.body.insert(
+        "jndiname".to_string(),
+        TypedValue::String(factory.name.clone()),
+    );
+    msg.body.insert(
+        "ft".to_string(),
+        TypedValue::Integer(factory.factory_type as i32),
+    );
+    msg.body
+        .insert("url".to_string(), TypedValue::String(factory.url.clone()));
+    if let Some(val) = &factory.client_id {
+        msg.body
+            .insert("cid".to_string(), TypedValue::String(val.clone()));
+    }
+    if let Some(val) = factory.connect_attempt_count {
+        msg.body.insert("cac".to_string(), TypedValue::Integer(val));
+    }
+    if let Some(val) = factory.connect_attempt_delay {
+        msg.body.insert("cad".to_string(), TypedValue::Long(val));
+    }
+    if let Some(val) = factory.connect_attempt_timeout {
+        msg.body.insert("cat".to_string(), TypedValue::Long(val));
+    }
+    if let Some(val) = factory.reconnect_attempt_count {
+        msg.body.insert("rac".to_string(), TypedValue::Integer(val));
+    }
+    if let Some(val) = factory.reconnect_attempt_delay {
+        msg.body.insert("rad".to_string(), TypedValue::Long(val));
+    }
+    if let Some(val) = factory.reconnect_attempt_timeout {
+        msg.body.insert("rat".to_string(), TypedValue::Long(val));
+    }
+    if let Some(ssl) = &factory.ssl {
+        if let Some(val) = ssl.verify_host {
+            msg.body
+                .insert("ssl_vh".to_string(), TypedValue::Boolean(val));
+        }
+        if let Some(val) = ssl.verify_hostname {
+            msg.body
+                .insert("ssl_vhn".to_string(), TypedValue::Boolean(val));
+        }
+        if let Some(val) = &ssl.expected_hostname {
+            msg.body
+                .insert("ssl_ehn".to_string(), TypedValue::String(val.clone()));
+        }
+        if let Some(val) = &ssl.trusted_certificates {
+            msg.body
+                .insert("ssl_trusted".to_string(), TypedValue::String(val.clone()));
+        }
+        if let Some(val) = &ssl.ciphers {
+            msg.body
+                .insert("ssl_ciphers".to_string(), TypedValue::String(val.clone()));
+        }
+    }
+    msg
+}
+
+/// creates the message body holding the route
+///
+/// selectors are sent as a map per direction, keyed by topic
+fn route_message(route: &RouteInfo) -> MapMessage {
+    let mut msg: MapMessage = Default::default();
+    msg.body
+        .insert("route".to_string(), TypedValue::String(route.name.clone()));
+    msg.body
+        .insert("url".to_string(), TypedValue::String(route.url.clone()));
+    if let Some(val) = &route.zone_name {
+        msg.body
+            .insert("zn".to_string(), TypedValue::String(val.clone()));
+    }
+    if let Some(val) = &route.zone_type {
+        msg.body
+            .insert("zt".to_string(), TypedValue::String(val.clone()));
+    }
+    let mut incoming: MapMessage = Default::default();
+    let mut outgoing: MapMessage = Default::default();
+    for selector in &route.selectors {
+        let selectors = if selector.incoming {
+            &mut incoming
+        } else {
+            &mut outgoing
+        };
+        selectors.body.insert(
+            selector.topic.clone(),
+            TypedValue::String(selector.selector.clone()),
+        );
+    }
+    msg.body
+        .insert("insel".to_string(), TypedValue::Map(incoming));
+    msg.body
+        .insert("outsel".to_string(), TypedValue::Map(outgoing));
+    msg
+}
+
+/// creates the message body holding the permission
+fn permission_message(permission: &PermissionInfo) -> MapMessage {
+    let mut msg: MapMessage = Default::default();
+    let (name, destination_type) = match &permission.destination {
+        Destination::Queue(name) => (name, DESTINATION_TYPE_QUEUE),
+        Destination::Topic(name) => (name, DESTINATION_TYPE_TOPIC),
+    };
+    msg.body
+        .insert("dn".to_string(), TypedValue::String(name.clone()));
+    msg.body
+        .insert("dt".to_string(), TypedValue::Integer(destination_type));
+    match &permission.principal {
+        Principal::User(user) => msg
+            .body
+            .insert("user".to_string(), TypedValue::String(user.clone())),
+        Principal::Group(group) => msg
+            .body
+            .insert("group".to_string(), TypedValue::String(group.clone())),
+    };
+    msg.body
+        .insert("perm".to_string(), TypedValue::Long(permission.permissions));
+    msg
+}
+
+/// creates the message body holding the queue properties
+fn queue_message(queue: &QueueProperties) -> MapMessage {
+    let mut msg: MapMessage = Default::default();
+    msg.body
+        .insert("dn".to_string(), TypedValue::String(queue.name.clone()));
+    msg.body.insert(
+        "dt".to_string(),
+        TypedValue::Integer(DESTINATION_TYPE_QUEUE),
+    );
+    if let Some(val) = queue.max_bytes {
+        msg.body.insert("mb".to_string(), TypedValue::Long(val));
+    }
+    if let Some(val) = queue.max_messages {
+        msg.body.insert("mm".to_string(), TypedValue::Long(val));
+    }
+    if let Some(val) = queue.overflow_policy {
+        msg.body.insert("op".to_string(), TypedValue::Integer(val));
+    }
+    if let Some(val) = queue.global {
+        msg.body
+            .insert("global".to_string(), TypedValue::Boolean(val));
+    }
+    if let Some(val) = queue.prefetch {
+        msg.body.insert("pf".to_string(), TypedValue::Integer(val));
+    }
+    if let Some(val) = queue.expiry_override {
+        msg.body.insert("expy".to_string(), TypedValue::Long(val));
+    }
+    if let Some(val) = queue.redelivery_delay {
+        msg.body.insert("rdd".to_string(), TypedValue::Long(val));
+    }
+    if let Some(val) = queue.max_redelivery {
+        msg.body.insert("mr".to_string(), TypedValue::Integer(val));
+    }
+    if let Some(val) = queue.exclusive {
+        msg.body
+            .insert("excl".to_string(), TypedValue::Boolean(val));
+    }
+    insert_extended_properties(&mut msg, &queue.extended);
+    msg
+}
+
+/// creates the message body holding the topic properties
+fn topic_message(topic: &TopicProperties) -> MapMessage {
+    let mut msg: MapMessage = Default::default();
+    msg.body
+        .insert("dn".to_string(), TypedValue::String(topic.name.clone()));
+    msg.body.insert(
+        "dt".to_string(),
+        TypedValue::Integer(DESTINATION_TYPE_TOPIC),
+    );
+    if let Some(val) = topic.max_bytes {
+        msg.body.insert("mb".to_string(), TypedValue::Long(val));
+    }
+    if let Some(val) = topic.max_messages {
+        msg.body.insert("mm".to_string(), TypedValue::Long(val));
+    }
+    if let Some(val) = topic.overflow_policy {
+        msg.body.insert("op".to_string(), TypedValue::Integer(val));
+    }
+    if let Some(val) = topic.global {
+        msg.body
+            .insert("global".to_string(), TypedValue::Boolean(val));
+    }
+    if let Some(val) = topic.prefetch {
+        msg.body.insert("pf".to_string(), TypedValue::Integer(val));
+    }
+    if let Some(val) = topic.expiry_override {
+        msg.body.insert("expy".to_string(), TypedValue::Long(val));
+    }
+    insert_extended_properties(&mut msg, &topic.extended);
+    msg
+}
+
+/// adds the properties shared by queues and topics to the message body
+fn insert_extended_properties(msg: &mut MapMessage, extended: &ExtendedProperties) {
+    if let Some(val) = extended.failsafe {
+        msg.body
+            .insert("failsafe".to_string(), TypedValue::Boolean(val));
+    }
+    if let Some(val) = extended.secure {
+        msg.body
+            .insert("secure".to_string(), TypedValue::Boolean(val));
+    }
+    if let Some(val) = extended.flow_control {
+        msg.body.insert("fc".to_string(), TypedValue::Long(val));
+    }
+    if let Some(val) = &extended.store {
+        msg.body
+            .insert("store".to_string(), TypedValue::String(val.clone()));
+    }
+    if let Some(val) = &extended.trace {
+        msg.body
+            .insert("trace".to_string(), TypedValue::String(val.clone()));
+    }
+    if let Some(val) = extended.sender_name {
+        msg.body
+            .insert("sname".to_string(), TypedValue::Boolean(val));
+    }
+    if let Some(val) = extended.sender_name_enforced {
+        msg.body
+            .insert("snameenf".to_string(), TypedValue::Boolean(val));
+    }
+    if let Some(val) = &extended.import {
+        msg.body
+            .insert("import".to_string(), TypedValue::String(val.clone()));
+    }
+    if let Some(val) = &extended.export {
+        msg.body
+            .insert("export".to_string(), TypedValue::String(val.clone()));
+    }
+}
+
+/// sends the admin message to the admin queue and waits for the reply of the EMS
+///
+/// the reply carries the result in its code header, 0 stands for success, and the reason
+/// of a failure in its text header (unverified)
+fn admin_request(session: &Session, msg: MapMessage, timeout: Duration) -> Result<(), Error> {
+    let admin_queue = Destination::Queue(ADMIN_QUEUE_NAME.to_string());
+    let reply = session.request_reply(&admin_queue, msg, timeout.as_millis() as i64)?;
+    match &reply {
+        Some(Message::MapMessage(reply)) => check_reply(reply),
+        Some(_) => Err(Error::new(
+            ErrorKind::InvalidData,
+            "unknown reply to admin command",
+        )),
+        None => Err(Error::new(
+            ErrorKind::TimedOut,
+            format!("no reply to admin command within {timeout:?}"),
+        )),
+    }
+}
+
+/// turns an error reply of the EMS into an error
+///
+/// objects which already exist or do not exist are reported with their own kind,
+/// so callers can fall back to an update or treat a deletion as done. A reply without
+/// a numeric code is not understood, so it is an error rather than a success
+fn check_reply(reply: &MapMessage) -> Result<(), Error> {
+    let code = match reply.header.as_ref().and_then(|header| header.get("code")) {
+        Some(TypedValue::Integer(code)) => *code as i64,
+        Some(TypedValue::Long(code)) => *code,
+        Some(code) => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown result code {code:?} in reply to admin command"),
+            ));
+        }
+        None => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "reply to admin command carries no result code",
+            ));
+        }
+    };
+    if code == 0 {
+        return Ok(());
+    }
+    let text = match reply.header.as_ref().and_then(|header| header.get("text")) {
+        Some(TypedValue::String(text)) => text.clone(),
+        _ => format!("admin command failed with code {code}"),
+    };
+    let lower = text.to_lowercase();
+    let kind = if lower.contains("already exists") {
+        ErrorKind::AlreadyExists
+    } else if lower.contains("not found") || lower.contains("does not exist") {
+        ErrorKind::NotFound
+    } else {
+        ErrorKind::Other
+    };
+    Err(Error::new(kind, text))
+}
+
+/// creates the message header for an admin command
+fn admin_header(command: AdminCommands) -> HashMap<String, TypedValue> {
+    let mut header: HashMap<String, TypedValue> = HashMap::new();
+    header.insert("JMS_TIBCO_MSG_EXT".to_string(), TypedValue::Boolean(true));
+    header.insert("code".to_string(), TypedValue::Integer(command as i32));
+    header.insert("save".to_string(), TypedValue::Boolean(true));
+    header.insert("arseq".to_string(), TypedValue::Integer(1));
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(code: Option<TypedValue>, text: Option<&str>) -> MapMessage {
+        let mut header = HashMap::new();
+        if let Some(code) = code {
+            header.insert("code".to_string(), code);
+        }
+        if let Some(text) = text {
+            header.insert("text".to_string(), TypedValue::String(text.to_string()));
+        }
+        MapMessage {
+            header: Some(header),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn reply_with_code_zero_succeeds() {
+        assert!(check_reply(&reply(Some(TypedValue::Integer(0)), None)).is_ok());
+        assert!(check_reply(&reply(Some(TypedValue::Long(0)), Some("ok"))).is_ok());
+    }
+
+    #[test]
+    fn reply_without_known_code_fails() {
+        let err = check_reply(&MapMessage::default()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let err = check_reply(&reply(None, Some("ok"))).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let err = check_reply(&reply(Some(TypedValue::String("0".into())), None)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn error_code_is_reported_with_its_kind() {
+        let err = check_reply(&reply(
+            Some(TypedValue::Integer(1)),
+            Some("Queue 'q.test' already exists"),
+        ))
+        .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(err.to_string(), "Queue 'q.test' already exists");
+        let err = check_reply(&reply(
+            Some(TypedValue::Long(2)),
+            Some("Bridge does not exist"),
+        ))
+        .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        let err = check_reply(&reply(Some(TypedValue::Integer(3)), None)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Other);
+        assert_eq!(err.to_string(), "admin command failed with code 3");
+    }
+}
+
+/// checks the unverified command codes, body keys and replies against a real EMS
+///
+/// the server is taken from EMS_TEST_URL, EMS_TEST_USERNAME and EMS_TEST_PASSWORD. The
+/// tests create objects prefixed with operator.test and remove them again
+#[cfg(all(test, feature = "ems_integration"))]
+mod ems_tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn session() -> Session {
+        let var = |name: &str| std::env::var(name).unwrap_or_else(|_| panic!("{name} must be set"));
+        let conn = tibco_ems::admin::connect(
+            &var("EMS_TEST_URL"),
+            &var("EMS_TEST_USERNAME"),
+            &var("EMS_TEST_PASSWORD"),
+        )
+        .expect("connect to the test EMS");
+        conn.session().expect("create admin session")
+    }
+
+    fn queue(name: &str) -> QueueProperties {
+        QueueProperties {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn queue_commands() {
+        let session = session();
+        let name = "operator.test.queue";
+        create_queue(&session, &queue(name), TIMEOUT).unwrap();
+        let err = create_queue(&session, &queue(name), TIMEOUT).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        let update = QueueProperties {
+            max_redelivery: Some(3),
+            exclusive: Some(true),
+            extended: ExtendedProperties {
+                flow_control: Some(65536),
+                trace: Some("trace".to_string()),
+                ..Default::default()
+            },
+            ..queue(name)
+        };
+        update_queue(&session, &update, TIMEOUT).unwrap();
+        let reset = QueueProperties {
+            max_redelivery: Some(0),
+            exclusive: Some(false),
+            extended: ExtendedProperties {
+                flow_control: Some(0),
+                store: Some("$sys.nonfailsafe".to_string()),
+                trace: Some(String::new()),
+                import: Some(String::new()),
+                export: Some(String::new()),
+                ..Default::default()
+            },
+            ..queue(name)
+        };
+        update_queue(&session, &reset, TIMEOUT).unwrap();
+        delete_queue(&session, name, TIMEOUT).unwrap();
+        let err = delete_queue(&session, name, TIMEOUT).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn jndi_name_commands() {
+        let session = session();
+        let name = "operator.test.jndi";
+        let destination = Destination::Queue("operator.test.jndi".to_string());
+        create_queue(&session, &queue(name), TIMEOUT).unwrap();
+        create_jndi_name(&session, name, &destination, TIMEOUT).unwrap();
+        let err = create_jndi_name(&session, name, &destination, TIMEOUT).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        delete_jndi_name(&session, name, TIMEOUT).unwrap();
+        let err = delete_jndi_name(&session, name, TIMEOUT).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        delete_queue(&session, name, TIMEOUT).unwrap();
+    }
+
+    #[test]
+    fn user_group_and_permission_commands() {
+        let session = session();
+        let user = UserInfo {
+            name: "operator.test.user".to_string(),
+            description: None,
+            password: "secret".to_string(),
+        };
+        let group = GroupInfo {
+            name: "operator.test.group".to_string(),
+            description: Some("test".to_string()),
+        };
+        create_user(&session, &user, TIMEOUT).unwrap();
+        let err = create_user(&session, &user, TIMEOUT).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        update_user(&session, &user, TIMEOUT).unwrap();
+        create_group(&session, &group, TIMEOUT).unwrap();
+        update_group(&session, &group, TIMEOUT).unwrap();
+        add_group_member(&session, &group.name, &user.name, TIMEOUT).unwrap();
+        let members = list_group_members(&session, &group.name, TIMEOUT).unwrap();
+        assert_eq!(members, vec![user.name.clone()]);
+        remove_group_member(&session, &group.name, &user.name, TIMEOUT).unwrap();
+        assert!(list_group_members(&session, &group.name, TIMEOUT)
+            .unwrap()
+            .is_empty());
+
+        create_queue(&session, &queue("operator.test.permission"), TIMEOUT).unwrap();
+        let permission = PermissionInfo {
+            destination: Destination::Queue("operator.test.permission".to_string()),
+            principal: Principal::User(user.name.clone()),
+            permissions: PERMISSION_SEND | PERMISSION_RECEIVE,
+        };
+        grant_permission(&session, &permission, TIMEOUT).unwrap();
+        revoke_permission(&session, &permission, TIMEOUT).unwrap();
+        delete_queue(&session, "operator.test.permission", TIMEOUT).unwrap();
+
+        delete_group(&session, &group.name, TIMEOUT).unwrap();
+        delete_user(&session, &user.name, TIMEOUT).unwrap();
+        let err = delete_user(&session, &user.name, TIMEOUT).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn durable_commands() {
+        let session = session();
+        let topic = TopicProperties {
+            name: "operator.test.durable".to_string(),
+            ..Default::default()
+        };
+        let durable = DurableInfo {
+            topic: topic.name.clone(),
+            name: "operator-test".to_string(),
+            client_id: Some("operator-test".to_string()),
+            selector: Some("priority > 4".to_string()),
+            no_local: true,
+            pending_messages: None,
+        };
+        create_topic(&session, &topic, TIMEOUT).unwrap();
+        create_durable(&session, &durable, TIMEOUT).unwrap();
+        let listed = list_durables(&session, TIMEOUT).unwrap();
+        let listed = listed.iter().find(|d| d.key() == durable.key()).unwrap();
+        assert_eq!(listed.topic, durable.topic);
+        assert_eq!(listed.selector, durable.selector);
+        assert!(listed.no_local);
+        delete_durable(&session, &durable, TIMEOUT).unwrap();
+        let err = delete_durable(&session, &durable, TIMEOUT).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        delete_topic(&session, &topic.name, TIMEOUT).unwrap();
+    }
+
+    #[test]
+    fn factory_commands() {
+        let session = session();
+        let factory = FactoryInfo {
+            name: "operator.test.factory".to_string(),
+            factory_type: FactoryType::Queue,
+            url: "tcp://ems1:7222,tcp://ems2:7222".to_string(),
+            client_id: None,
+            connect_attempt_count: Some(3),
+            connect_attempt_delay: Some(500),
+            connect_attempt_timeout: None,
+            reconnect_attempt_count: Some(10),
+            reconnect_attempt_delay: Some(1000),
+            reconnect_attempt_timeout: None,
+            ssl: None,
+        };
+        create_factory(&session, &factory, TIMEOUT).unwrap();
+        let err = create_factory(&session, &factory, TIMEOUT).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        update_factory(&session, &factory, TIMEOUT).unwrap();
+        delete_factory(&session, &factory.name, TIMEOUT).unwrap();
+        let err = delete_factory(&session, &factory.name, TIMEOUT).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn bridge_commands() {
+        let session = session();
+        let bridge = BridgeInfo {
+            source: Destination::Queue("operator.test.bridge.source".to_string()),
+            target: Destination::Queue("operator.test.bridge.target".to_string()),
+            selector: Some("priority > 4".to_string()),
+        };
+        create_queue(&session, &queue("operator.test.bridge.source"), TIMEOUT).unwrap();
+        create_queue(&session, &queue("operator.test.bridge.target"), TIMEOUT).unwrap();
+        create_bridge(&session, &bridge, TIMEOUT).unwrap();
+        //the bridge controller relies on this to detect bridges deleted out-of-band
+        let err = create_bridge(&session, &bridge, TIMEOUT).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        delete_bridge(&session, &bridge, TIMEOUT).unwrap();
+        let err = delete_bridge(&session, &bridge, TIMEOUT).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        delete_queue(&session, "operator.test.bridge.source", TIMEOUT).unwrap();
+        delete_queue(&session, "operator.test.bridge.target", TIMEOUT).unwrap();
+    }
+
+    #[test]
+    fn route_commands() {
+        let session = session();
+        let route = RouteInfo {
+            name: "operator-test".to_string(),
+            url: "tcp://operator-test:7222".to_string(),
+            zone_name: None,
+            zone_type: None,
+            selectors: vec![RouteSelector {
+                incoming: false,
+                topic: "operator.test.route".to_string(),
+                selector: "priority > 4".to_string(),
+            }],
+            connected: None,
+        };
+        create_route(&session, &route, TIMEOUT).unwrap();
+        let listed = list_routes(&session, TIMEOUT).unwrap();
+        let listed = listed.iter().find(|r| r.name == route.name).unwrap();
+        assert_eq!(listed.url, route.url);
+        assert_eq!(listed.connected, Some(false));
+        update_route(&session, &route, TIMEOUT).unwrap();
+        delete_route(&session, &route.name, TIMEOUT).unwrap();
+        let err = delete_route(&session, &route.name, TIMEOUT).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+}
@@ -1,23 +1,27 @@
-use env_var::env_var;
-use futures::{StreamExt, TryStreamExt};
+use super::admin;
+use super::config::Config;
+use super::controller::{self, Condition, Context, Error, HasConditions, SyncResult};
+use super::server::{self, ServerSessions};
+use futures::StreamExt;
+use kube::api::{Api, ResourceExt};
+use kube::runtime::controller::Action;
+use kube::runtime::finalizer::{finalizer, Event};
 use kube::CustomResource;
-use kube::{
-    api::{Api, ResourceExt, WatchEvent, WatchParams},
-    Client,
-};
-use once_cell::sync::Lazy;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::sync::Mutex;
+use std::io::ErrorKind;
+use std::sync::Arc;
 use tibco_ems::admin::BridgeInfo;
-use tibco_ems::{Destination, Session};
+use tibco_ems::Destination;
+use tokio::time::Duration;
+use tokio_util::sync::CancellationToken;
 
-#[derive(CustomResource, Serialize, Deserialize, Default, Clone, Debug, JsonSchema)]
+#[derive(CustomResource, Serialize, Deserialize, Default, Clone, Debug, PartialEq, JsonSchema)]
 #[kube(
     group = "tibcoems.apimeister.com",
     version = "v1",
     kind = "Bridge",
+    status = "BridgeStatus",
     namespaced
 )]
 #[allow(non_snake_case)]
@@ -27,158 +31,333 @@ pub struct BridgeSpec {
     pub target_type: String,
     pub target_name: String,
     pub selector: Option<String>,
+    /// name of the EmsServer object within the namespace, defaults to the server of the operator
+    pub server: Option<String>,
 }
 
-pub static KNOWN_BRIDGES: Lazy<Mutex<HashMap<String, Bridge>>> =
-    Lazy::new(|| Mutex::new(HashMap::new()));
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[allow(non_snake_case)]
+#[serde(default)]
+pub struct BridgeStatus {
+    pub conditions: Vec<Condition>,
+    /// generation of the spec which was last reconciled
+    pub observedGeneration: Option<i64>,
+    /// last time the bridge was successfully reconciled with the EMS
+    pub lastSyncTime: Option<String>,
+    /// result of the last failed admin operation
+    pub message: Option<String>,
+    /// spec of the bridge last applied to the EMS, removed or replaced on the next change
+    pub applied: Option<BridgeSpec>,
+}
 
-///used for sending admin operations
-static ADMIN_CONNECTION: Lazy<Mutex<Session>> =
-    Lazy::new(|| Mutex::new(super::init_admin_connection()));
+impl HasConditions for Bridge {
+    fn conditions(&self) -> &[Condition] {
+        match &self.status {
+            Some(status) => &status.conditions,
+            None => &[],
+        }
+    }
+}
 
-pub async fn watch_bridges() -> Result<(), ()> {
-    let crds: Api<Bridge> = get_bridge_client().await;
-    let mut lp = WatchParams::default();
+/// bridge objects within kubernetes, filled by the controller
+pub static BRIDGE_STORE: controller::Stores<Bridge> = controller::Stores::new();
 
-    let responsible_for = super::RESPONSIBLE_FOR.lock().unwrap().clone();
-    if !responsible_for.is_empty() {
-        info!("subscribing to events of type bridges.tibcoems.apimeister.com/v1 for instance {responsible_for}");
-        lp = lp.labels(format!("tibcoems.apimeister.com/owner={responsible_for}").as_str());
-    } else {
-        lp = lp.labels("!tibcoems.apimeister.com/owner");
-        info!("subscribing to events of type bridges.tibcoems.apimeister.com/v1");
-    }
+pub async fn watch_bridges(config: Arc<Config>, token: CancellationToken) -> Result<(), ()> {
+    let client = controller::client().await?;
+    let ctx = controller::Context::new(client, config, token);
+    let controllers = controller::new_controllers(&ctx, "bridges");
+    BRIDGE_STORE.register(&controllers);
+    controller::run_controllers(controllers, reconcile, ctx)
+        .for_each(|result| async move {
+            match result {
+                Ok((bridge, _action)) => trace!("reconciled bridge {}", bridge.name),
+                Err(err) => debug!("bridge reconciliation failed: {:?}", err),
+            }
+        })
+        .await;
+    Ok(())
+}
 
-    let mut last_version = String::from("0");
-    loop {
-        debug!("new loop iteration with offset {}", last_version);
-        let watch_result = crds.watch(&lp, &last_version).await;
-        let str_result = match watch_result {
-            Ok(x) => x,
-            Err(_) => continue,
-        };
-        let mut stream = str_result.boxed();
-        loop {
-            let stream_result = stream.try_next().await;
-            let status_obj = match stream_result {
-                Ok(x) => x,
-                Err(e) => {
-                    debug!("error on request loop {:?}", e);
-                    break;
-                }
-            };
-            let status = match status_obj {
-                Some(x) => x,
-                None => {
-                    debug!("request loop returned empty");
-                    break;
-                }
-            };
-            debug!("new stream item");
+/// brings the bridge on the EMS in line with the bridge object
+async fn reconcile(bridge: Arc<Bridge>, ctx: Arc<Context>) -> Result<Action, Error> {
+    let key = controller::object_key(bridge.as_ref());
+    let api: Api<Bridge> = Api::namespaced(ctx.client.clone(), &bridge.namespace().unwrap());
+    let action = finalizer(&api, controller::FINALIZER, bridge, |event| async {
+        match event {
+            Event::Apply(bridge) => apply_bridge(&api, &bridge, &ctx).await,
+            Event::Cleanup(bridge) => cleanup_bridge(&api, &bridge, &ctx).await,
+        }
+    })
+    .await?;
+    ctx.reset_backoff(&key);
+    Ok(action)
+}
 
-            match status {
-                WatchEvent::Added(bridge) => {
-                    let mut res = KNOWN_BRIDGES.lock().unwrap();
-                    let bridge_name = ResourceExt::name_any(&bridge);
-                    match res.get(&bridge_name) {
-                        Some(_bridge) => debug!("bridge already known {}", &bridge_name),
-                        None => {
-                            info!("adding bridge {}", &bridge_name);
-                            create_bridge(&bridge);
-                            res.insert(bridge_name, bridge.clone());
-                        }
-                    }
-                    last_version = ResourceExt::resource_version(&bridge).unwrap();
+/// creates the bridge on the EMS or replaces it, if the object was changed
+///
+/// the bridge last applied is kept in the status, so it is replaced after a restart too.
+/// An unchanged bridge is created again, which recreates a bridge deleted on the EMS.
+/// A bridge moved to another server is created there before it is removed from
+/// the previous server
+async fn apply_bridge(api: &Api<Bridge>, bridge: &Bridge, ctx: &Context) -> Result<Action, Error> {
+    let key = controller::object_key(bridge);
+    let namespace = bridge.namespace().unwrap_or_default();
+    let server = get_server(&namespace, &bridge.spec);
+    let sessions = match server::get_sessions(&server) {
+        Ok(sessions) => sessions,
+        Err(message) => {
+            warn!("bridge {} cannot be applied: {}", key, message);
+            let result = SyncResult::Failed {
+                exists: false,
+                message,
+            };
+            controller::patch_sync_status(api, bridge, result).await;
+            return Ok(Action::requeue(Duration::from_secs(10)));
+        }
+    };
+    let timeout = ctx.config.admin_command_timeout;
+    let bridge_info = create_bridge_object(&bridge.spec);
+    let applied = bridge
+        .status
+        .as_ref()
+        .and_then(|status| status.applied.as_ref());
+    let result = match applied {
+        //the EMS offers no listing of bridges, so the bridge is created again
+        //to find out whether it was deleted out-of-band
+        Some(old) if old == &bridge.spec => match create_bridge(&sessions, &bridge_info, timeout) {
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                debug!("bridge {} is up to date", &key);
+                Ok(None)
+            }
+            Ok(_) => {
+                warn!("bridge {} was missing on the EMS, recreated it", &key);
+                Ok(Some("recreated bridge".to_owned()))
+            }
+            Err(err) => Err((true, err)),
+        },
+        Some(old) if get_server(&namespace, old) != server => {
+            info!(
+                "moving bridge {} to {}",
+                &key,
+                server::display_name(&server)
+            );
+            match create_bridge(&sessions, &bridge_info, timeout) {
+                Ok(_) => {
+                    remove_moved_bridge(&get_server(&namespace, old), old, timeout);
+                    controller::patch_applied(api, bridge, Some(&bridge.spec)).await;
+                    Ok(Some("moved bridge".to_owned()))
                 }
-                WatchEvent::Modified(bridge) => {
-                    let bridge_name = ResourceExt::name_any(&bridge);
-                    info!("Modified {}", bridge_name);
-                    create_bridge(&bridge);
-                    last_version = ResourceExt::resource_version(&bridge).unwrap();
+                Err(err) => Err((false, err)),
+            }
+        }
+        Some(old) => {
+            info!("replacing bridge {}", &key);
+            let old_info = create_bridge_object(old);
+            match replace_bridge(&sessions, &old_info, &bridge_info, timeout) {
+                Ok(_) => {
+                    controller::patch_applied(api, bridge, Some(&bridge.spec)).await;
+                    Ok(Some("replaced bridge".to_owned()))
                 }
-                WatchEvent::Deleted(bridge) => {
-                    let bridge_name = ResourceExt::name_any(&bridge);
-                    let do_not_delete = env_var!(optional "DO_NOT_DELETE_OBJECTS", default:"FALSE");
-                    if do_not_delete == "TRUE" {
-                        warn!("delete event for {} (not executed because of DO_NOT_DELETE_OBJECTS setting)", bridge_name);
-                    } else {
-                        info!("deleting bridge {}", &bridge_name);
-                        delete_bridge(&bridge);
+                Err((restored, err)) => {
+                    if !restored {
+                        controller::patch_applied::<_, BridgeSpec>(api, bridge, None).await;
                     }
-                    let mut res = KNOWN_BRIDGES.lock().unwrap();
-                    res.remove(&bridge_name);
-                    last_version = ResourceExt::resource_version(&bridge).unwrap();
+                    Err((restored, err))
                 }
-                WatchEvent::Error(e) => {
-                    if e.code == 410 && e.reason == "Expired" {
-                        //fail silently
-                        trace!("resource_version too old, resetting offset to 0");
-                    } else {
-                        error!("Error {:?}", e);
-                        error!("resetting offset to 0");
-                    }
-                    "0".clone_into(&mut last_version);
+            }
+        }
+        None => {
+            info!("adding bridge {}", &key);
+            match create_bridge(&sessions, &bridge_info, timeout) {
+                //bridges applied by releases which did not keep them in the status
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                    debug!("bridge {} already exists", &key);
+                    controller::patch_applied(api, bridge, Some(&bridge.spec)).await;
+                    Ok(None)
                 }
-                _ => {}
-            };
+                Ok(_) => {
+                    controller::patch_applied(api, bridge, Some(&bridge.spec)).await;
+                    Ok(Some("created bridge".to_owned()))
+                }
+                Err(err) => Err((false, err)),
+            }
+        }
+    };
+    match result {
+        Ok(note) => {
+            controller::patch_sync_status(api, bridge, SyncResult::Synced(note)).await;
+            Ok(Action::requeue(ctx.requeue_interval()))
+        }
+        Err((exists, err)) => {
+            let message = format!("failed to apply bridge: {err}");
+            let result = SyncResult::Failed { exists, message };
+            controller::patch_sync_status(api, bridge, result).await;
+            Err(Error::Ems(err))
         }
     }
 }
 
-async fn get_bridge_client() -> Api<Bridge> {
-    let client = Client::try_default().await.expect("getting default client");
-    let namespace = env_var!(required "KUBERNETES_NAMESPACE");
-    Api::namespaced(client, &namespace)
+/// removes the bridge from the EMS before kubernetes deletes the object
+///
+/// a failed deletion keeps the finalizer, so the object stays until the bridge is gone
+async fn cleanup_bridge(
+    api: &Api<Bridge>,
+    bridge: &Bridge,
+    ctx: &Context,
+) -> Result<Action, Error> {
+    let key = controller::object_key(bridge);
+    if ctx.config.do_not_delete_objects {
+        warn!(
+            "delete event for {} (not executed because of DO_NOT_DELETE_OBJECTS setting)",
+            key
+        );
+        return Ok(Action::await_change());
+    }
+    let spec = match bridge
+        .status
+        .as_ref()
+        .and_then(|status| status.applied.as_ref())
+    {
+        Some(applied) => applied,
+        None => &bridge.spec,
+    };
+    let server = get_server(&bridge.namespace().unwrap_or_default(), spec);
+    let sessions = match server::get_sessions(&server) {
+        Ok(sessions) => sessions,
+        Err(message) => {
+            warn!("bridge {} cannot be deleted: {}", key, message);
+            let result = SyncResult::Failed {
+                exists: true,
+                message,
+            };
+            controller::patch_sync_status(api, bridge, result).await;
+            return Ok(Action::requeue(Duration::from_secs(10)));
+        }
+    };
+    info!("deleting bridge {}", &key);
+    let bridge_info = create_bridge_object(spec);
+    match delete_bridge(&sessions, &bridge_info, ctx.config.admin_command_timeout) {
+        Ok(_) => Ok(Action::await_change()),
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            debug!("bridge {} does not exist", &key);
+            Ok(Action::await_change())
+        }
+        Err(err) => {
+            let message = format!("failed to delete bridge: {err}");
+            let result = SyncResult::Failed {
+                exists: true,
+                message,
+            };
+            controller::patch_sync_status(api, bridge, result).await;
+            Err(Error::Ems(err))
+        }
+    }
 }
 
-fn create_bridge(bridge: &Bridge) {
-    let bridge_object = create_bridge_object(bridge);
+fn create_bridge(
+    sessions: &ServerSessions,
+    bridge_object: &BridgeInfo,
+    timeout: Duration,
+) -> Result<(), std::io::Error> {
     // create bridge on server
-    let session = ADMIN_CONNECTION.lock().unwrap();
-    let result = tibco_ems::admin::create_bridge(&session, &bridge_object);
-    match result {
-        Ok(_) => debug!("bridge created successfully"),
+    let session = sessions.admin()?;
+    admin::create_bridge(&session, bridge_object, timeout)?;
+    debug!("bridge created successfully");
+    Ok(())
+}
+
+/// replaces a bridge on the ems
+///
+/// the old bridge is restored if the EMS rejects the new one,
+/// on failure the error tells whether the old bridge is still present on the ems
+fn replace_bridge(
+    sessions: &ServerSessions,
+    old_bridge: &BridgeInfo,
+    new_bridge: &BridgeInfo,
+    timeout: Duration,
+) -> Result<(), (bool, std::io::Error)> {
+    let session = sessions.admin().map_err(|err| (true, err))?;
+    match admin::delete_bridge(&session, old_bridge, timeout) {
+        Ok(_) => {}
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            debug!("previous bridge does not exist anymore");
+        }
         Err(err) => {
-            error!("failed to create bridge: {:?}", err);
-            panic!("failed to create bridge");
+            error!("failed to delete bridge, keeping previous bridge: {err:?}");
+            return Err((true, err));
         }
     }
-}
-
-fn delete_bridge(bridge: &Bridge) {
-    let bridge_object = create_bridge_object(bridge);
-    let session = ADMIN_CONNECTION.lock().unwrap();
-    let result = tibco_ems::admin::delete_bridge(&session, &bridge_object);
-    match result {
-        Ok(_) => debug!("bridge deleted"),
+    match admin::create_bridge(&session, new_bridge, timeout) {
+        Ok(_) => {
+            debug!("bridge replaced successfully");
+            Ok(())
+        }
         Err(err) => {
-            error!("failed to delete bridge: {:?}", err);
-            panic!("failed to delete bridge");
+            error!("failed to create bridge, restoring previous bridge: {err:?}");
+            match admin::create_bridge(&session, old_bridge, timeout) {
+                Ok(_) => Err((true, err)),
+                Err(restore_err) => {
+                    error!("failed to restore bridge: {:?}", restore_err);
+                    Err((false, err))
+                }
+            }
         }
     }
 }
 
-fn create_bridge_object(bridge: &Bridge) -> BridgeInfo {
+/// removes a bridge from the server it was moved away from
+///
+/// a failure only leaves the bridge behind on the previous server, so it is logged
+fn remove_moved_bridge(server: &str, old: &BridgeSpec, timeout: Duration) {
+    let result = server::get_sessions(server)
+        .map_err(std::io::Error::other)
+        .and_then(|sessions| delete_bridge(&sessions, &create_bridge_object(old), timeout));
+    if let Err(err) = result {
+        warn!(
+            "failed to remove bridge from previous server {}: {}",
+            server::display_name(server),
+            err
+        );
+    }
+}
+
+fn delete_bridge(
+    sessions: &ServerSessions,
+    bridge_object: &BridgeInfo,
+    timeout: Duration,
+) -> Result<(), std::io::Error> {
+    let session = sessions.admin()?;
+    admin::delete_bridge(&session, bridge_object, timeout)?;
+    debug!("bridge deleted");
+    Ok(())
+}
+
+/// key of the EMS server the bridge belongs to
+fn get_server(namespace: &str, spec: &BridgeSpec) -> String {
+    server::server_key(namespace, &spec.server)
+}
+
+fn create_bridge_object(spec: &BridgeSpec) -> BridgeInfo {
     // generate default bridge -> T:Q
     let mut bridge_info = BridgeInfo {
-        source: Destination::Topic(bridge.spec.source_name.clone()),
-        target: Destination::Queue(bridge.spec.target_name.clone()),
+        source: Destination::Topic(spec.source_name.clone()),
+        target: Destination::Queue(spec.target_name.clone()),
         selector: None,
     };
     // if source is not a topic -> Q:Q
-    let mut source_type = bridge.spec.source_type.clone();
+    let mut source_type = spec.source_type.clone();
     source_type.make_ascii_uppercase();
     if source_type.starts_with('Q') {
-        bridge_info.source = Destination::Queue(bridge.spec.source_name.clone());
+        bridge_info.source = Destination::Queue(spec.source_name.clone());
     }
     // if target is not a queue -> T:T or Q:T depending if above set to Q
-    let mut target_type = bridge.spec.target_type.clone();
+    let mut target_type = spec.target_type.clone();
     target_type.make_ascii_uppercase();
     if target_type.starts_with('T') {
-        bridge_info.target = Destination::Topic(bridge.spec.target_name.clone());
+        bridge_info.target = Destination::Topic(spec.target_name.clone());
     }
     // add selector if given
-    if let Some(sel) = &bridge.spec.selector {
+    if let Some(sel) = &spec.selector {
         bridge_info.selector = Some(sel.clone())
     }
 
@@ -0,0 +1,390 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use tokio::time::Duration;
+
+/// settings of the operator, loaded and validated once at startup
+///
+/// every setting is read from the environment variable of the same name, or from
+/// the JSON file given by CONFIG_FILE, the environment takes precedence.
+/// Credentials are not part of it, they are read by the server module so they can be rotated.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// url of the default server, the urls of a fault tolerant pair are separated by comma
+    pub server_url: String,
+    /// file holding the username of the default server
+    pub username_file: Option<String>,
+    /// file holding the password of the default server
+    pub password_file: Option<String>,
+    /// only statistics are collected, no objects are managed
+    pub read_only: bool,
+    /// objects are not deleted from the EMS
+    pub do_not_delete_objects: bool,
+    /// deployments are scaled by the queues they are labeled with
+    pub enable_scaling: bool,
+    /// how often statistics are refreshed
+    pub status_refresh: Duration,
+    /// interval in which every object is reconciled, even without changes
+    pub drift_reconcile_interval: Duration,
+    /// timeout of admin requests which expect a reply
+    pub admin_command_timeout: Duration,
+    /// address of the metrics server
+    pub listen_address: SocketAddr,
+    /// server label of the metrics of the default server
+    pub metrics_server_label: String,
+    /// metrics are additionally reported with the names of previous releases
+    pub legacy_metrics: bool,
+    /// owner label of the objects managed by this instance, empty for objects without owner
+    pub responsible_for: String,
+    /// namespaces watched by the operator, None stands for all namespaces
+    pub namespaces: Option<Vec<String>>,
+    /// only the replica holding the lease manages objects
+    pub leader_election: bool,
+    /// name of the lease used for leader election
+    pub lease_name: String,
+    /// namespace of the lease
+    pub lease_namespace: String,
+    /// holder identity of this replica within the lease
+    pub identity: String,
+}
+
+/// invalid or missing setting
+#[derive(Debug)]
+pub struct ConfigError(String);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// loads the settings from the environment and the optional CONFIG_FILE
+    pub fn load() -> Result<Config, ConfigError> {
+        Config::from_settings(&Settings::load()?)
+    }
+
+    fn from_settings(settings: &Settings) -> Result<Config, ConfigError> {
+        let watch_namespaces = settings.optional("WATCH_NAMESPACES");
+        let namespaces = match watch_namespaces.as_deref().map(str::trim) {
+            Some("*") => None,
+            _ => {
+                let namespaces: Vec<String> = split_list(watch_namespaces.as_deref());
+                if namespaces.is_empty() {
+                    Some(vec![settings.required("KUBERNETES_NAMESPACE").map_err(
+                        |_| {
+                            ConfigError(
+                                "KUBERNETES_NAMESPACE is required if WATCH_NAMESPACES is not set"
+                                    .to_owned(),
+                            )
+                        },
+                    )?])
+                } else {
+                    Some(namespaces)
+                }
+            }
+        };
+        let listen_address = settings
+            .optional("LISTEN_ADDRESS")
+            .unwrap_or_else(|| "0.0.0.0:8080".to_owned());
+        let listen_address = listen_address.parse().map_err(|_| {
+            ConfigError(format!(
+                "LISTEN_ADDRESS must be an address like 0.0.0.0:8080, got '{listen_address}'"
+            ))
+        })?;
+        let leader_election = settings.flag("LEADER_ELECTION", false)?;
+        let lease_namespace = settings
+            .optional("LEASE_NAMESPACE")
+            .or_else(|| settings.optional("KUBERNETES_NAMESPACE"))
+            .unwrap_or_default();
+        if leader_election && lease_namespace.is_empty() {
+            return Err(ConfigError(
+                "LEASE_NAMESPACE or KUBERNETES_NAMESPACE is required for LEADER_ELECTION"
+                    .to_owned(),
+            ));
+        }
+        let responsible_for = settings.optional("RESPONSIBLE_FOR").unwrap_or_default();
+        //instances responsible for different objects elect their leaders independently
+        let default_lease_name = match responsible_for.as_str() {
+            "" => "tibco-ems-operator".to_owned(),
+            owner => format!(
+                "tibco-ems-operator-{}",
+                owner.to_lowercase().replace('_', "-")
+            ),
+        };
+        Ok(Config {
+            server_url: settings.required("SERVER_URL")?,
+            username_file: settings.optional("USERNAME_FILE"),
+            password_file: settings.optional("PASSWORD_FILE"),
+            read_only: settings.flag("READ_ONLY", false)?,
+            do_not_delete_objects: settings.flag("DO_NOT_DELETE_OBJECTS", false)?,
+            enable_scaling: settings.flag("ENABLE_SCALING", false)?,
+            status_refresh: settings.millis("STATUS_REFRESH_IN_MS", 10000)?,
+            drift_reconcile_interval: settings.millis("DRIFT_RECONCILE_INTERVAL_IN_MS", 300000)?,
+            admin_command_timeout: settings.millis("ADMIN_COMMAND_TIMEOUT_MS", 60000)?,
+            listen_address,
+            metrics_server_label: settings
+                .optional("METRICS_SERVER_LABEL")
+                .unwrap_or_else(|| "EMS-ESB".to_owned()),
+            legacy_metrics: settings.flag("LEGACY_METRICS", true)?,
+            responsible_for,
+            namespaces,
+            leader_election,
+            lease_name: settings
+                .optional("LEASE_NAME")
+                .unwrap_or(default_lease_name),
+            lease_namespace,
+            identity: settings
+                .optional("POD_NAME")
+                .or_else(|| settings.optional("HOSTNAME"))
+                .unwrap_or_else(|| format!("tibco-ems-operator-{}", std::process::id())),
+        })
+    }
+}
+
+/// raw settings of the environment and the config file
+struct Settings {
+    env: HashMap<String, String>,
+    file: HashMap<String, String>,
+}
+
+impl Settings {
+    fn load() -> Result<Settings, ConfigError> {
+        //variables which are not valid unicode are ignored, as by std::env::var
+        let env = std::env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+            .collect();
+        Settings::from_env(env)
+    }
+
+    /// settings of the given environment and the CONFIG_FILE it refers to
+    fn from_env(env: HashMap<String, String>) -> Result<Settings, ConfigError> {
+        let path = match env.get("CONFIG_FILE") {
+            Some(path) if !path.is_empty() => path.clone(),
+            _ => {
+                return Ok(Settings {
+                    env,
+                    file: HashMap::new(),
+                });
+            }
+        };
+        let content = std::fs::read_to_string(&path)
+            .map_err(|err| ConfigError(format!("cannot read CONFIG_FILE {path}: {err}")))?;
+        let file = Settings::parse(&path, &content)?;
+        info!("loaded settings from {path}");
+        Ok(Settings { env, file })
+    }
+
+    /// settings of the JSON object in the config file, values are converted to strings
+    fn parse(path: &str, content: &str) -> Result<HashMap<String, String>, ConfigError> {
+        let values: HashMap<String, Value> = serde_json::from_str(content).map_err(|err| {
+            ConfigError(format!(
+                "CONFIG_FILE {path} must be a JSON object of settings: {err}"
+            ))
+        })?;
+        let mut file = HashMap::new();
+        for (name, value) in values {
+            let value = match value {
+                Value::String(value) => value,
+                Value::Number(value) => value.to_string(),
+                Value::Bool(true) => "TRUE".to_owned(),
+                Value::Bool(false) => "FALSE".to_owned(),
+                _ => {
+                    return Err(ConfigError(format!(
+                        "{name} in CONFIG_FILE {path} must be a string, number or boolean"
+                    )));
+                }
+            };
+            file.insert(name, value);
+        }
+        Ok(file)
+    }
+
+    /// value of the environment variable, or of the config file, empty values are ignored
+    fn optional(&self, name: &str) -> Option<String> {
+        self.env
+            .get(name)
+            .or_else(|| self.file.get(name))
+            .cloned()
+            .filter(|value| !value.trim().is_empty())
+    }
+
+    fn required(&self, name: &str) -> Result<String, ConfigError> {
+        self.optional(name)
+            .ok_or_else(|| ConfigError(format!("{name} is required")))
+    }
+
+    /// TRUE or FALSE, in any case
+    fn flag(&self, name: &str, default: bool) -> Result<bool, ConfigError> {
+        let value = match self.optional(name) {
+            Some(value) => value,
+            None => return Ok(default),
+        };
+        match value.trim() {
+            flag if flag.eq_ignore_ascii_case("TRUE") => Ok(true),
+            flag if flag.eq_ignore_ascii_case("FALSE") => Ok(false),
+            _ => Err(ConfigError(format!(
+                "{name} must be TRUE or FALSE, got '{value}'"
+            ))),
+        }
+    }
+
+    /// positive number of milliseconds
+    fn millis(&self, name: &str, default: u64) -> Result<Duration, ConfigError> {
+        let value = match self.optional(name) {
+            Some(value) => value,
+            None => return Ok(Duration::from_millis(default)),
+        };
+        match value.trim().parse::<u64>() {
+            Ok(millis) if millis > 0 => Ok(Duration::from_millis(millis)),
+            _ => Err(ConfigError(format!(
+                "{name} must be a positive number of milliseconds, got '{value}'"
+            ))),
+        }
+    }
+}
+
+/// entries of a comma separated list without blanks
+fn split_list(list: Option<&str>) -> Vec<String> {
+    list.unwrap_or_default()
+        .split(',')
+        .map(|entry| entry.trim().to_owned())
+        .filter(|entry| !entry.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(values: &[(&str, &str)]) -> HashMap<String, String> {
+        values
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn settings(env: &[(&str, &str)]) -> Settings {
+        Settings {
+            env: values(env),
+            file: HashMap::new(),
+        }
+    }
+
+    fn error(values: &[(&str, &str)]) -> String {
+        Config::from_settings(&settings(values))
+            .unwrap_err()
+            .to_string()
+    }
+
+    #[test]
+    fn flag_accepts_only_true_and_false() {
+        let settings = settings(&[
+            ("TEST_ON", "TRUE"),
+            ("TEST_OFF", "FALSE"),
+            ("TEST_LOWER", "true"),
+            ("TEST_MIXED", " False "),
+            ("TEST_BAD", "yes"),
+        ]);
+        assert!(settings.flag("TEST_ON", false).unwrap());
+        assert!(!settings.flag("TEST_OFF", true).unwrap());
+        assert!(settings.flag("TEST_LOWER", false).unwrap());
+        assert!(!settings.flag("TEST_MIXED", true).unwrap());
+        assert!(settings.flag("TEST_MISSING", true).unwrap());
+        assert_eq!(
+            settings.flag("TEST_BAD", false).unwrap_err().to_string(),
+            "TEST_BAD must be TRUE or FALSE, got 'yes'"
+        );
+    }
+
+    #[test]
+    fn environment_takes_precedence_over_config_file() {
+        let settings = Settings {
+            env: values(&[("SERVER_URL", "tcp://env:7222"), ("LEASE_NAME", " ")]),
+            file: values(&[
+                ("SERVER_URL", "tcp://file:7222"),
+                ("LEASE_NAME", "lease"),
+                ("METRICS_SERVER_LABEL", "EMS"),
+            ]),
+        };
+        assert_eq!(settings.optional("SERVER_URL").unwrap(), "tcp://env:7222");
+        assert_eq!(settings.optional("LEASE_NAME"), None);
+        assert_eq!(settings.optional("METRICS_SERVER_LABEL").unwrap(), "EMS");
+        assert_eq!(settings.optional("TEST_MISSING"), None);
+        let settings = Settings::from_env(values(&[("CONFIG_FILE", "")])).unwrap();
+        assert!(settings.file.is_empty());
+        assert!(Settings::from_env(values(&[("CONFIG_FILE", "/nonexistent.json")])).is_err());
+    }
+
+    #[test]
+    fn millis_must_be_positive() {
+        let settings = settings(&[("TEST_MS", " 250 "), ("TEST_ZERO", "0"), ("TEST_BAD", "1s")]);
+        assert_eq!(
+            settings.millis("TEST_MS", 1).unwrap(),
+            Duration::from_millis(250)
+        );
+        assert_eq!(
+            settings.millis("TEST_MISSING", 1000).unwrap(),
+            Duration::from_secs(1)
+        );
+        assert!(settings.millis("TEST_ZERO", 1).is_err());
+        assert!(settings.millis("TEST_BAD", 1).is_err());
+    }
+
+    #[test]
+    fn split_list_skips_blanks() {
+        assert_eq!(split_list(Some(" a, b,,c ,")), vec!["a", "b", "c"]);
+        assert!(split_list(Some(" , ")).is_empty());
+        assert!(split_list(None).is_empty());
+    }
+
+    #[test]
+    fn config_file_values_are_converted() {
+        let file = Settings::parse(
+            "config.json",
+            r#"{"SERVER_URL": "tcp://ems:7222", "STATUS_REFRESH_IN_MS": 500, "READ_ONLY": true}"#,
+        )
+        .unwrap();
+        assert_eq!(file["STATUS_REFRESH_IN_MS"], "500");
+        assert_eq!(file["READ_ONLY"], "TRUE");
+        assert!(Settings::parse("config.json", "[]").is_err());
+        assert!(Settings::parse("config.json", r#"{"WATCH_NAMESPACES": ["a"]}"#).is_err());
+    }
+
+    #[test]
+    fn load_reports_invalid_settings() {
+        assert_eq!(
+            error(&[("WATCH_NAMESPACES", "a")]),
+            "SERVER_URL is required"
+        );
+        let valid = [("WATCH_NAMESPACES", "a"), ("SERVER_URL", "tcp://ems:7222")];
+        assert_eq!(
+            error(&[valid[0], valid[1], ("LISTEN_ADDRESS", "localhost")]),
+            "LISTEN_ADDRESS must be an address like 0.0.0.0:8080, got 'localhost'"
+        );
+        assert_eq!(
+            error(&[valid[0], valid[1], ("READ_ONLY", "yes")]),
+            "READ_ONLY must be TRUE or FALSE, got 'yes'"
+        );
+    }
+
+    #[test]
+    fn watch_namespaces_selects_all_or_a_list() {
+        let all = settings(&[
+            ("WATCH_NAMESPACES", " * "),
+            ("SERVER_URL", "tcp://ems:7222"),
+        ]);
+        assert_eq!(Config::from_settings(&all).unwrap().namespaces, None);
+        let list = settings(&[
+            ("WATCH_NAMESPACES", "a, b"),
+            ("SERVER_URL", "tcp://ems:7222"),
+        ]);
+        assert_eq!(
+            Config::from_settings(&list).unwrap().namespaces,
+            Some(vec!["a".to_string(), "b".to_string()])
+        );
+    }
+}
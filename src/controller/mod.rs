@@ -0,0 +1,396 @@
+use super::config::Config;
+use chrono::{SecondsFormat, Utc};
+use futures::{stream, Stream, StreamExt};
+use k8s_openapi::NamespaceResourceScope;
+use kube::api::{Api, Patch, PatchParams};
+use kube::runtime::controller::{self, Action, Controller};
+use kube::runtime::reflector::{ObjectRef, Store};
+use kube::runtime::{finalizer, predicates, reflector, watcher, Predicate, WatchStreamExt};
+use kube::{Client, Resource, ResourceExt};
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Debug;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// finalizer which blocks the deletion of an object until it is removed from the EMS
+pub const FINALIZER: &str = "tibcoems.apimeister.com/finalizer";
+
+/// first delay after a failed reconciliation
+const BACKOFF_BASE_SECONDS: u64 = 5;
+/// upper limit for the delay between two failed reconciliations
+const BACKOFF_MAX_SECONDS: u64 = 300;
+
+/// errors which can occur while reconciling an object
+#[derive(Debug)]
+pub enum Error {
+    /// request against the kubernetes api failed
+    Kube(kube::Error),
+    /// admin operation on the EMS failed
+    Ems(std::io::Error),
+    /// adding, running or removing the finalizer failed
+    Finalizer(Box<finalizer::Error<Error>>),
+    /// a referenced secret cannot be used
+    Secret(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Kube(err) => write!(f, "kubernetes error: {err}"),
+            Error::Ems(err) => write!(f, "EMS error: {err}"),
+            Error::Finalizer(err) => write!(f, "{err}"),
+            Error::Secret(msg) => write!(f, "secret error: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<kube::Error> for Error {
+    fn from(err: kube::Error) -> Self {
+        Error::Kube(err)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Ems(err)
+    }
+}
+
+impl From<finalizer::Error<Error>> for Error {
+    fn from(err: finalizer::Error<Error>) -> Self {
+        Error::Finalizer(Box::new(err))
+    }
+}
+
+/// condition of an object, modelled after the kubernetes metav1.Condition
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[allow(non_snake_case)]
+pub struct Condition {
+    /// one of Ready, Synced or Degraded
+    pub r#type: String,
+    /// True, False or Unknown
+    pub status: String,
+    pub reason: String,
+    pub message: Option<String>,
+    pub lastTransitionTime: String,
+    pub observedGeneration: Option<i64>,
+}
+
+/// objects which report conditions within their status
+pub trait HasConditions {
+    fn conditions(&self) -> &[Condition];
+}
+
+/// outcome of a reconciliation, written to the status of the object
+pub enum SyncResult {
+    /// the spec is applied to the EMS, optionally describing what was changed
+    Synced(Option<String>),
+    /// the spec could not be applied, exists tells if the object is present on the EMS
+    Failed { exists: bool, message: String },
+}
+
+/// state shared between all reconciliations of a controller
+pub struct Context {
+    pub client: Client,
+    pub config: Arc<Config>,
+    /// cancelled on shutdown, the controllers finish the running reconciliations and stop
+    shutdown: CancellationToken,
+    /// number of consecutive failures per object
+    failures: Mutex<HashMap<String, u32>>,
+    /// generation of the spec which was last applied to the EMS per object
+    generations: Mutex<HashMap<String, i64>>,
+}
+
+impl Context {
+    pub fn new(client: Client, config: Arc<Config>, shutdown: CancellationToken) -> Arc<Context> {
+        Arc::new(Context {
+            client,
+            config,
+            shutdown,
+            failures: Mutex::new(HashMap::new()),
+            generations: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// registers a failure and returns the delay before the next attempt
+    pub fn backoff(&self, key: &str) -> Duration {
+        let mut failures = self.failures.lock().unwrap();
+        let count = failures.entry(key.to_owned()).or_insert(0);
+        let delay = BACKOFF_BASE_SECONDS.saturating_mul(1 << (*count).min(16));
+        *count += 1;
+        Duration::from_secs(delay.min(BACKOFF_MAX_SECONDS))
+    }
+
+    /// interval in which every object is reconciled, even without changes
+    ///
+    /// this corrects drift between the EMS and the custom resource objects
+    pub fn requeue_interval(&self) -> Duration {
+        self.config.drift_reconcile_interval
+    }
+
+    /// forgets all failures after a successful reconciliation
+    pub fn reset_backoff(&self, key: &str) {
+        let mut failures = self.failures.lock().unwrap();
+        failures.remove(key);
+    }
+
+    /// true while the last reconciliation of the object failed
+    pub fn is_failing(&self, key: &str) -> bool {
+        let failures = self.failures.lock().unwrap();
+        failures.contains_key(key)
+    }
+
+    /// generation which was last applied for the object
+    pub fn applied_generation(&self, key: &str) -> Option<i64> {
+        let generations = self.generations.lock().unwrap();
+        generations.get(key).cloned()
+    }
+
+    /// remembers the generation which was applied for the object
+    pub fn set_applied_generation(&self, key: &str, generation: Option<i64>) {
+        let mut generations = self.generations.lock().unwrap();
+        match generation {
+            Some(generation) => generations.insert(key.to_owned(), generation),
+            None => generations.remove(key),
+        };
+    }
+}
+
+/// retries a failed reconciliation with an exponential backoff per object
+pub fn error_policy<K: Resource>(obj: Arc<K>, err: &Error, ctx: Arc<Context>) -> Action {
+    let key = object_key(obj.as_ref());
+    let delay = ctx.backoff(&key);
+    warn!(
+        "reconciling {} failed, retrying in {:?}: {}",
+        key, delay, err
+    );
+    Action::requeue(delay)
+}
+
+/// creates a controller for the objects of each watched namespace
+///
+/// status updates do not trigger a reconciliation, only changes of the spec or the finalizers do.
+/// Once the shutdown token of the context is cancelled, the controllers stop taking new
+/// reconciliations and end after the running ones are finished
+pub fn new_controllers<K>(ctx: &Context, plural: &str) -> Vec<Controller<K>>
+where
+    K: Resource<DynamicType = (), Scope = NamespaceResourceScope>
+        + Clone
+        + DeserializeOwned
+        + Debug
+        + Send
+        + Sync
+        + 'static,
+{
+    namespaced_apis(ctx.client.clone(), &ctx.config)
+        .into_iter()
+        .map(|api| {
+            let (reader, writer) = reflector::store();
+            let objects = watcher(api, watcher_config(plural, &ctx.config.responsible_for))
+                .default_backoff()
+                .reflect(writer)
+                .applied_objects()
+                .predicate_filter(
+                    predicates::generation.combine(predicates::finalizers),
+                    Default::default(),
+                );
+            Controller::for_stream(objects, reader)
+                .graceful_shutdown_on(ctx.shutdown.clone().cancelled_owned())
+        })
+        .collect()
+}
+
+/// runs the controllers with a shared context and merges their results
+pub fn run_controllers<K, ReconcilerFn, ReconcilerFut>(
+    controllers: Vec<Controller<K>>,
+    reconcile: ReconcilerFn,
+    ctx: Arc<Context>,
+) -> impl Stream<Item = ControllerResult<K>>
+where
+    K: Resource<DynamicType = ()> + Clone + DeserializeOwned + Debug + Send + Sync + 'static,
+    ReconcilerFn: FnMut(Arc<K>, Arc<Context>) -> ReconcilerFut + Clone + Send + 'static,
+    ReconcilerFut: Future<Output = Result<Action, Error>> + Send + 'static,
+{
+    stream::select_all(controllers.into_iter().map(|controller| {
+        controller
+            .run(reconcile.clone(), error_policy, ctx.clone())
+            .boxed()
+    }))
+}
+
+/// result of a single reconciliation
+pub type ControllerResult<K> =
+    Result<(ObjectRef<K>, Action), controller::Error<Error, watcher::Error>>;
+
+/// object caches of the controllers of one kind, one per watched namespace
+pub struct Stores<K: Resource<DynamicType = ()> + Clone + 'static>(Mutex<Vec<Store<K>>>);
+
+impl<K> Stores<K>
+where
+    K: Resource<DynamicType = ()> + Clone + DeserializeOwned + Debug + Send + Sync + 'static,
+{
+    pub const fn new() -> Stores<K> {
+        Stores(Mutex::new(Vec::new()))
+    }
+
+    /// keeps the stores of the controllers
+    ///
+    /// replaces the stores of a previous run, the supervisor restarts the watchers
+    /// and a reload creates new controllers, their stale caches must not be kept
+    pub fn register(&self, controllers: &[Controller<K>]) {
+        let mut stores = self.0.lock().unwrap();
+        *stores = controllers
+            .iter()
+            .map(|controller| controller.store())
+            .collect();
+    }
+
+    /// waits until every store received the initial list of its namespace
+    pub async fn wait_until_ready(&self) {
+        let stores: Vec<Store<K>> = self.0.lock().unwrap().clone();
+        for store in stores {
+            //a dropped writer means the controller stopped, its store stays empty
+            let _ignore = store.wait_until_ready().await;
+        }
+    }
+
+    /// all objects of all watched namespaces
+    pub fn state(&self) -> Vec<Arc<K>> {
+        let stores = self.0.lock().unwrap();
+        stores.iter().flat_map(|store| store.state()).collect()
+    }
+}
+
+/// creates the kubernetes client of a task
+///
+/// the error is logged and ends the task, so the supervisor restarts it with increasing delay
+pub async fn client() -> Result<Client, ()> {
+    Client::try_default()
+        .await
+        .map_err(|err| error!("failed to create the kubernetes client: {err}"))
+}
+
+/// creates an api for each watched namespace, or a single api for all namespaces
+pub fn namespaced_apis<K>(client: Client, config: &Config) -> Vec<Api<K>>
+where
+    K: Resource<DynamicType = (), Scope = NamespaceResourceScope>,
+{
+    match &config.namespaces {
+        None => vec![Api::all(client)],
+        Some(namespaces) => namespaces
+            .iter()
+            .map(|namespace| Api::namespaced(client.clone(), namespace))
+            .collect(),
+    }
+}
+
+/// writes the conditions, observedGeneration, lastSyncTime and message to the status
+///
+/// errors are only logged, the reconciliation result is more important than the status
+pub async fn patch_sync_status<K>(api: &Api<K>, obj: &K, result: SyncResult)
+where
+    K: Resource + HasConditions + Clone + DeserializeOwned + Debug,
+{
+    let now = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+    let generation = obj.meta().generation;
+    let (ready, synced, degraded, message) = match result {
+        SyncResult::Synced(note) => (
+            ("True", "Available", None),
+            ("True", "Applied", note),
+            ("False", "Applied", None),
+            None,
+        ),
+        SyncResult::Failed { exists, message } => (
+            if exists {
+                ("True", "Available", None)
+            } else {
+                ("False", "Missing", Some(message.clone()))
+            },
+            ("False", "ApplyFailed", Some(message.clone())),
+            ("True", "ApplyFailed", Some(message.clone())),
+            Some(message),
+        ),
+    };
+    let conditions: Vec<Condition> = [("Ready", ready), ("Synced", synced), ("Degraded", degraded)]
+        .into_iter()
+        .map(|(condition_type, (status, reason, message))| {
+            //keep the transition time as long as the status does not change
+            let last_transition_time = obj
+                .conditions()
+                .iter()
+                .find(|c| c.r#type == condition_type && c.status == status)
+                .map(|c| c.lastTransitionTime.clone())
+                .unwrap_or_else(|| now.clone());
+            Condition {
+                r#type: condition_type.to_owned(),
+                status: status.to_owned(),
+                reason: reason.to_owned(),
+                message,
+                lastTransitionTime: last_transition_time,
+                observedGeneration: generation,
+            }
+        })
+        .collect();
+    let mut status = serde_json::json!({
+        "conditions": conditions,
+        "observedGeneration": generation,
+        "message": message,
+    });
+    if message.is_none() {
+        status["lastSyncTime"] = serde_json::json!(now);
+    }
+    let status = serde_json::json!({ "status": status });
+    let pp = PatchParams::default();
+    if let Err(err) = api
+        .patch_status(&obj.name_any(), &pp, &Patch::Merge(&status))
+        .await
+    {
+        error!("error while updating status of {}", object_key(obj));
+        error!("{:?}", err);
+    }
+}
+
+/// writes what was last applied to the EMS to the applied field of the status, None removes it
+///
+/// the status survives restarts, so a later change or deletion knows what to undo.
+/// Errors are only logged like in patch_sync_status
+pub async fn patch_applied<K, T>(api: &Api<K>, obj: &K, applied: Option<&T>)
+where
+    K: Resource + Clone + DeserializeOwned + Debug,
+    T: Serialize,
+{
+    let status = serde_json::json!({ "status": { "applied": applied } });
+    let pp = PatchParams::default();
+    if let Err(err) = api
+        .patch_status(&obj.name_any(), &pp, &Patch::Merge(&status))
+        .await
+    {
+        error!("error while updating applied state of {}", object_key(obj));
+        error!("{:?}", err);
+    }
+}
+
+/// creates the watcher config, honoring the RESPONSIBLE_FOR setting
+fn watcher_config(plural: &str, responsible_for: &str) -> watcher::Config {
+    if !responsible_for.is_empty() {
+        info!("subscribing to events of type {plural}.tibcoems.apimeister.com/v1 for instance {responsible_for}");
+        watcher::Config::default()
+            .labels(format!("tibcoems.apimeister.com/owner={responsible_for}").as_str())
+    } else {
+        info!("subscribing to events of type {plural}.tibcoems.apimeister.com/v1");
+        watcher::Config::default().labels("!tibcoems.apimeister.com/owner")
+    }
+}
+
+/// unique key of an object within the cluster
+pub fn object_key<K: Resource>(obj: &K) -> String {
+    format!("{}/{}", obj.namespace().unwrap_or_default(), obj.name_any())
+}
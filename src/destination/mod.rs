@@ -0,0 +1,280 @@
+//! properties shared by the queue and topic spec
+use super::admin::{self, ExtendedProperties};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::io::ErrorKind;
+use std::time::Duration;
+use tibco_ems::{Destination, Session};
+
+/// extended destination properties, flattened into the queue and topic spec
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, JsonSchema)]
+#[allow(non_snake_case)]
+pub struct ExtendedSpec {
+    pub failsafe: Option<bool>,
+    pub secure: Option<bool>,
+    /// pending message size at which producers are blocked, e.g. 256KB
+    pub flowControl: Option<String>,
+    /// name of the store holding persistent messages
+    pub store: Option<String>,
+    /// either "trace" or "trace,body"
+    pub trace: Option<String>,
+    pub senderName: Option<bool>,
+    pub senderNameEnforced: Option<bool>,
+    /// transports from which messages are imported
+    pub import: Option<Vec<String>>,
+    /// transports to which messages are exported
+    pub export: Option<Vec<String>>,
+}
+
+impl ExtendedSpec {
+    /// validates the spec and converts it into the properties sent to the EMS
+    pub fn properties(&self) -> Result<ExtendedProperties, String> {
+        let flow_control = match &self.flowControl {
+            Some(val) => Some(parse_size(val).ok_or(format!(
+                "flowControl must be a size like 256KB, got '{val}'"
+            ))?),
+            None => None,
+        };
+        if let Some(store) = &self.store
+            && (store.is_empty() || store.contains(char::is_whitespace))
+        {
+            return Err(format!("store must be a store name, got '{store}'"));
+        }
+        if let Some(trace) = &self.trace
+            && trace != "trace"
+            && trace != "trace,body"
+        {
+            return Err(format!(
+                "trace must be either 'trace' or 'trace,body', got '{trace}'"
+            ));
+        }
+        Ok(ExtendedProperties {
+            failsafe: self.failsafe,
+            secure: self.secure,
+            flow_control,
+            store: self.store.clone(),
+            trace: self.trace.clone(),
+            sender_name: self.senderName,
+            sender_name_enforced: self.senderNameEnforced,
+            import: transport_list("import", &self.import)?,
+            export: transport_list("export", &self.export)?,
+        })
+    }
+}
+
+/// checks that the overflow policy is one of default (0), discardOld (1) or rejectIncoming (2)
+pub fn validate_overflow_policy(overflow_policy: Option<u8>) -> Result<(), String> {
+    match overflow_policy {
+        Some(val) if val > 2 => Err(format!("overflowPolicy must be 0, 1 or 2, got {val}")),
+        _ => Ok(()),
+    }
+}
+
+/// names of the properties which differ between a spec and the destination on the EMS
+#[derive(Default)]
+pub struct Changes(pub Vec<&'static str>);
+
+impl Changes {
+    /// value to send for a property the EMS reports, None if the EMS already has it
+    ///
+    /// a property missing from the spec stands for the EMS default, so removing it from
+    /// the spec restores the default
+    pub fn reported<T: Clone + PartialEq>(
+        &mut self,
+        name: &'static str,
+        spec: &Option<T>,
+        ems: &Option<T>,
+        default: T,
+    ) -> Option<T> {
+        let wanted = spec.clone().unwrap_or_else(|| default.clone());
+        if wanted == ems.clone().unwrap_or(default) {
+            None
+        } else {
+            self.0.push(name);
+            Some(wanted)
+        }
+    }
+
+    /// value to send for a property the EMS does not report, None if it was already applied
+    ///
+    /// the spec is compared with the value last applied, so a property removed from the
+    /// spec is reset by sending the given reset value
+    pub fn unreported<T: Clone + PartialEq>(
+        &mut self,
+        name: &'static str,
+        spec: &Option<T>,
+        applied: &Option<T>,
+        reset: T,
+    ) -> Option<T> {
+        if spec == applied {
+            None
+        } else {
+            self.0.push(name);
+            Some(spec.clone().unwrap_or(reset))
+        }
+    }
+}
+
+/// parses sizes like 512, 64KB, 10MB or 1GB into bytes
+fn parse_size(size: &str) -> Option<i64> {
+    let size = size.trim().to_ascii_uppercase();
+    let (number, factor) = if let Some(number) = size.strip_suffix("KB") {
+        (number, 1024)
+    } else if let Some(number) = size.strip_suffix("MB") {
+        (number, 1024 * 1024)
+    } else if let Some(number) = size.strip_suffix("GB") {
+        (number, 1024 * 1024 * 1024)
+    } else {
+        (size.as_str(), 1)
+    };
+    number.trim().parse::<i64>().ok()?.checked_mul(factor)
+}
+
+/// joins the transport names into the comma separated list expected by the EMS
+fn transport_list(field: &str, transports: &Option<Vec<String>>) -> Result<Option<String>, String> {
+    let Some(transports) = transports else {
+        return Ok(None);
+    };
+    for transport in transports {
+        if transport.is_empty() || transport.contains(|c: char| c == ',' || c.is_whitespace()) {
+            return Err(format!(
+                "{field} contains the invalid transport '{transport}'"
+            ));
+        }
+    }
+    Ok(Some(transports.join(",")))
+}
+
+/// validates the additional JNDI names of a queue or topic
+pub fn jndi_names(names: &Option<Vec<String>>) -> Result<BTreeSet<String>, String> {
+    let Some(names) = names else {
+        return Ok(BTreeSet::new());
+    };
+    for name in names {
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(format!("jndiNames contains the invalid name '{name}'"));
+        }
+    }
+    Ok(names.iter().cloned().collect())
+}
+
+/// what was last applied to the EMS for a queue or topic, kept in its status
+///
+/// the status survives restarts, so names and properties dropped from the spec are
+/// unbound or reset afterwards too
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, JsonSchema)]
+#[allow(non_snake_case)]
+pub struct AppliedDestination {
+    /// name of the destination the JNDI names are bound to
+    pub destination: String,
+    pub jndiNames: BTreeSet<String>,
+    /// properties the EMS does not report, as last sent to it
+    #[serde(default)]
+    pub unreported: UnreportedProperties,
+}
+
+/// properties of a queue or topic which the EMS does not report
+///
+/// queues only use maxRedelivery, exclusive and the extended properties the EMS does not
+/// report for queues
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, JsonSchema)]
+#[allow(non_snake_case)]
+pub struct UnreportedProperties {
+    pub maxRedelivery: Option<i32>,
+    pub exclusive: Option<bool>,
+    pub failsafe: Option<bool>,
+    pub secure: Option<bool>,
+    pub senderName: Option<bool>,
+    pub senderNameEnforced: Option<bool>,
+    /// flow control limit in bytes
+    pub flowControl: Option<i64>,
+    pub store: Option<String>,
+    pub trace: Option<String>,
+    pub import: Option<String>,
+    pub export: Option<String>,
+}
+
+/// flow control limit which disables flow control
+pub const NO_FLOW_CONTROL: i64 = 0;
+/// store of persistent messages when no store is set
+pub const DEFAULT_STORE: &str = "$sys.nonfailsafe";
+
+/// binds the JNDI names to the destination and removes names dropped from the spec
+///
+/// applied is updated with every name bound or unbound, even if a later operation failed.
+/// A name bound to another destination or factory is reported as conflict and left alone
+pub fn sync_jndi_names(
+    session: &Session,
+    applied: &mut AppliedDestination,
+    destination: &Destination,
+    desired: &BTreeSet<String>,
+    timeout: Duration,
+) -> Result<Option<String>, std::io::Error> {
+    let mut changes = Vec::new();
+    //names still pointing to a renamed destination are bound to the new name
+    if applied.destination != destination_name(destination) {
+        for name in applied.jndiNames.clone() {
+            unbind_jndi_name(session, &name, timeout)?;
+            applied.jndiNames.remove(&name);
+            if !desired.contains(&name) {
+                changes.push(format!("unbound {name}"));
+            }
+        }
+        applied.destination = destination_name(destination);
+    }
+    for name in applied.jndiNames.clone().difference(desired) {
+        unbind_jndi_name(session, name, timeout)?;
+        applied.jndiNames.remove(name);
+        changes.push(format!("unbound {name}"));
+    }
+    for name in desired.difference(&applied.jndiNames.clone()) {
+        match admin::create_jndi_name(session, name, destination, timeout) {
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                return Err(std::io::Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("JNDI name {name} is already bound to another destination or factory"),
+                ));
+            }
+            result => result?,
+        }
+        applied.jndiNames.insert(name.clone());
+        changes.push(format!("bound {name}"));
+    }
+    if changes.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(changes.join(", ")))
+    }
+}
+
+/// removes the JNDI names bound to a queue or topic before the destination is deleted
+pub fn remove_jndi_names(
+    session: &Session,
+    applied: Option<&AppliedDestination>,
+    timeout: Duration,
+) -> Result<(), std::io::Error> {
+    for name in applied.iter().flat_map(|applied| &applied.jndiNames) {
+        unbind_jndi_name(session, name, timeout)?;
+    }
+    Ok(())
+}
+
+/// unbinds a JNDI name, a name which is already gone counts as unbound
+fn unbind_jndi_name(
+    session: &Session,
+    name: &str,
+    timeout: Duration,
+) -> Result<(), std::io::Error> {
+    match admin::delete_jndi_name(session, name, timeout) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// name of a queue or topic
+fn destination_name(destination: &Destination) -> String {
+    match destination {
+        Destination::Queue(name) | Destination::Topic(name) => name.clone(),
+    }
+}
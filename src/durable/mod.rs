@@ -0,0 +1,449 @@
+use super::admin::{self, DurableInfo};
+use super::config::Config;
+use super::controller::{self, Condition, Context, Error, HasConditions, SyncResult};
+use super::health;
+use super::leader;
+use super::server;
+use super::supervisor;
+use futures::StreamExt;
+use kube::api::{Api, Patch, PatchParams, ResourceExt};
+use kube::runtime::controller::Action;
+use kube::runtime::finalizer::{finalizer, Event};
+use kube::{Client, CustomResource};
+use once_cell::sync::Lazy;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+use tokio::time::{self, Duration};
+use tokio_util::sync::CancellationToken;
+
+#[derive(CustomResource, Serialize, Deserialize, Default, Clone, Debug, PartialEq, JsonSchema)]
+#[kube(
+    group = "tibcoems.apimeister.com",
+    version = "v1",
+    kind = "Durable",
+    status = "DurableStatus",
+    namespaced
+)]
+#[allow(non_snake_case)]
+pub struct DurableSpec {
+    /// topic the durable subscribes to
+    pub topic: String,
+    /// name of the durable on the EMS
+    pub durableName: String,
+    /// client id of the connection which consumes the durable
+    pub clientId: Option<String>,
+    pub selector: Option<String>,
+    /// do not receive messages published by the consuming connection
+    pub noLocal: Option<bool>,
+    /// name of the EmsServer object within the namespace, defaults to the server of the operator
+    pub server: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[allow(non_snake_case)]
+#[serde(default)]
+pub struct DurableStatus {
+    pub pendingMessages: i64,
+    pub conditions: Vec<Condition>,
+    /// generation of the spec which was last reconciled
+    pub observedGeneration: Option<i64>,
+    /// last time the durable was successfully reconciled with the EMS
+    pub lastSyncTime: Option<String>,
+    /// result of the last failed admin operation
+    pub message: Option<String>,
+    /// spec of the durable last applied to the EMS, used to rename, move and delete it
+    pub applied: Option<DurableSpec>,
+}
+
+impl HasConditions for Durable {
+    fn conditions(&self) -> &[Condition] {
+        match &self.status {
+            Some(status) => &status.conditions,
+            None => &[],
+        }
+    }
+}
+
+/// durable objects within kubernetes, filled by the controller
+pub static DURABLE_STORE: controller::Stores<Durable> = controller::Stores::new();
+/// all durables present on the EMS servers, keyed by server, client id and durable name
+///
+/// a server is missing until its durables were retrieved
+static DURABLES: Lazy<Mutex<HashMap<String, HashMap<String, DurableInfo>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub async fn watch_durables(config: Arc<Config>, token: CancellationToken) -> Result<(), ()> {
+    let client = controller::client().await?;
+    let ctx = controller::Context::new(client, config, token);
+    let controllers = controller::new_controllers(&ctx, "durables");
+    DURABLE_STORE.register(&controllers);
+    controller::run_controllers(controllers, reconcile, ctx)
+        .for_each(|result| async move {
+            match result {
+                Ok((durable, _action)) => trace!("reconciled durable {}", durable.name),
+                Err(err) => debug!("durable reconciliation failed: {:?}", err),
+            }
+        })
+        .await;
+    Ok(())
+}
+
+/// brings the durable on the EMS in line with the durable object
+async fn reconcile(durable: Arc<Durable>, ctx: Arc<Context>) -> Result<Action, Error> {
+    let key = controller::object_key(durable.as_ref());
+    let api: Api<Durable> = Api::namespaced(ctx.client.clone(), &durable.namespace().unwrap());
+    let action = finalizer(&api, controller::FINALIZER, durable, |event| async {
+        match event {
+            Event::Apply(durable) => apply_durable(&api, &durable, &ctx).await,
+            Event::Cleanup(durable) => cleanup_durable(&api, &durable, &ctx).await,
+        }
+    })
+    .await?;
+    ctx.reset_backoff(&key);
+    Ok(action)
+}
+
+/// creates the durable on the EMS
+///
+/// durables cannot be altered, so a durable with a different topic, selector or
+/// noLocal flag is unsubscribed and created again, which drops its pending messages.
+/// The durable last applied is kept in the status, so a renamed or moved durable is
+/// removed after a restart too
+async fn apply_durable(
+    api: &Api<Durable>,
+    durable: &Durable,
+    ctx: &Context,
+) -> Result<Action, Error> {
+    let key = controller::object_key(durable);
+    let namespace = durable.namespace().unwrap_or_default();
+    let applied = durable
+        .status
+        .as_ref()
+        .and_then(|status| status.applied.as_ref());
+    let server = get_server(&namespace, &durable.spec);
+    let sessions = match server::get_sessions(&server) {
+        Ok(sessions) => sessions,
+        Err(message) => {
+            warn!("durable {} cannot be applied: {}", key, message);
+            let result = SyncResult::Failed {
+                exists: applied.is_some(),
+                message,
+            };
+            controller::patch_sync_status(api, durable, result).await;
+            return Ok(Action::requeue(Duration::from_secs(10)));
+        }
+    };
+    let durable_info = match create_durable_object(&durable.spec) {
+        Ok(durable_info) => durable_info,
+        Err(message) => {
+            warn!("durable {} has an invalid spec: {}", key, message);
+            let result = SyncResult::Failed {
+                exists: applied.is_some(),
+                message: format!("invalid spec: {message}"),
+            };
+            controller::patch_sync_status(api, durable, result).await;
+            return Ok(Action::await_change());
+        }
+    };
+    let durable_key = durable_info.key();
+    let current = {
+        let d_map = DURABLES.lock().unwrap();
+        match d_map.get(&server) {
+            Some(durables) => durables.get(&durable_key).cloned(),
+            None => {
+                debug!("waiting for durable information from EMS");
+                return Ok(Action::requeue(Duration::from_secs(5)));
+            }
+        }
+    };
+    let previous = applied.and_then(|old| {
+        let previous = create_durable_object(old).ok()?;
+        Some((get_server(&namespace, old), previous))
+    });
+    let timeout = ctx.config.admin_command_timeout;
+    let result = match sessions.admin() {
+        Ok(session) => {
+            let mut note = None;
+            let mut result = Ok(());
+            //the durable was renamed or moved, the new durable is created below
+            match previous {
+                Some((previous_server, previous)) if previous_server != server => {
+                    info!(
+                        "moving durable {} to {}",
+                        durable_key,
+                        server::display_name(&server)
+                    );
+                    remove_moved_durable(&previous_server, &previous, timeout);
+                }
+                Some((_, previous)) if previous.key() != durable_key => {
+                    info!("removing durable {} after rename", previous.key());
+                    result = match admin::delete_durable(&session, &previous, timeout) {
+                        Err(err) if err.kind() != ErrorKind::NotFound => Err((true, err)),
+                        _ => Ok(()),
+                    };
+                }
+                _ => {}
+            }
+            if result.is_ok() {
+                result = match &current {
+                    Some(current) if is_same_subscription(current, &durable_info) => Ok(()),
+                    Some(_) => {
+                        info!("replacing durable {}", durable_key);
+                        note = Some(format!("replaced durable {durable_key}"));
+                        admin::delete_durable(&session, &durable_info, timeout)
+                            .map_err(|err| (true, err))
+                            .and_then(|_| {
+                                admin::create_durable(&session, &durable_info, timeout)
+                                    .map_err(|err| (false, err))
+                            })
+                    }
+                    None => {
+                        info!("adding durable {}", durable_key);
+                        note = Some(format!("created durable {durable_key}"));
+                        admin::create_durable(&session, &durable_info, timeout)
+                            .map_err(|err| (false, err))
+                    }
+                };
+            }
+            result.map(|_| note)
+        }
+        Err(err) => Err((current.is_some(), err)),
+    };
+    match result {
+        Ok(note) => {
+            if note.is_some() {
+                //reflect new durable until the next statistics refresh
+                let mut d_map = DURABLES.lock().unwrap();
+                let durables = d_map.entry(server).or_default();
+                durables.insert(durable_key, durable_info);
+            }
+            if applied != Some(&durable.spec) {
+                controller::patch_applied(api, durable, Some(&durable.spec)).await;
+            }
+            controller::patch_sync_status(api, durable, SyncResult::Synced(note)).await;
+            Ok(Action::requeue(ctx.requeue_interval()))
+        }
+        Err((exists, err)) => {
+            let message = format!("failed to apply durable {durable_key}: {err}");
+            let result = SyncResult::Failed { exists, message };
+            controller::patch_sync_status(api, durable, result).await;
+            Err(Error::Ems(err))
+        }
+    }
+}
+
+/// removes a durable from the server it was moved away from
+///
+/// a failure only leaves the durable behind on the previous server, so it is logged
+fn remove_moved_durable(server: &str, durable_info: &DurableInfo, timeout: Duration) {
+    let result = server::get_sessions(server)
+        .map_err(std::io::Error::other)
+        .and_then(|sessions| {
+            let session = sessions.admin()?;
+            admin::delete_durable(&session, durable_info, timeout)
+        });
+    match result {
+        Err(err) if err.kind() != ErrorKind::NotFound => warn!(
+            "failed to remove durable {} from previous server {}: {}",
+            durable_info.key(),
+            server::display_name(server),
+            err
+        ),
+        _ => {}
+    }
+}
+
+/// unsubscribes the durable last applied on the EMS before kubernetes deletes the object
+///
+/// a failed unsubscribe keeps the finalizer, so the object stays until the durable is gone
+async fn cleanup_durable(
+    api: &Api<Durable>,
+    durable: &Durable,
+    ctx: &Context,
+) -> Result<Action, Error> {
+    let key = controller::object_key(durable);
+    if ctx.config.do_not_delete_objects {
+        warn!(
+            "delete event for {} (not executed because of DO_NOT_DELETE_OBJECTS setting)",
+            key
+        );
+        return Ok(Action::await_change());
+    }
+    let spec = match durable
+        .status
+        .as_ref()
+        .and_then(|status| status.applied.as_ref())
+    {
+        Some(applied) => applied,
+        None => &durable.spec,
+    };
+    //an invalid spec was never created
+    let Ok(durable_info) = create_durable_object(spec) else {
+        return Ok(Action::await_change());
+    };
+    let server = get_server(&durable.namespace().unwrap_or_default(), spec);
+    let sessions = match server::get_sessions(&server) {
+        Ok(sessions) => sessions,
+        Err(message) => {
+            warn!("durable {} cannot be deleted: {}", key, message);
+            let result = SyncResult::Failed {
+                exists: true,
+                message,
+            };
+            controller::patch_sync_status(api, durable, result).await;
+            return Ok(Action::requeue(Duration::from_secs(10)));
+        }
+    };
+    let durable_key = durable_info.key();
+    info!("deleting durable {}", durable_key);
+    let result = sessions.admin().and_then(|session| {
+        admin::delete_durable(&session, &durable_info, ctx.config.admin_command_timeout)
+    });
+    match result {
+        Err(err) if err.kind() != ErrorKind::NotFound => {
+            let message = format!("failed to delete durable {durable_key}: {err}");
+            let result = SyncResult::Failed {
+                exists: true,
+                message,
+            };
+            controller::patch_sync_status(api, durable, result).await;
+            Err(Error::Ems(err))
+        }
+        _ => {
+            if let Some(durables) = DURABLES.lock().unwrap().get_mut(&server) {
+                durables.remove(&durable_key);
+            }
+            Ok(Action::await_change())
+        }
+    }
+}
+
+pub async fn watch_durables_status(
+    config: Arc<Config>,
+    token: CancellationToken,
+) -> Result<(), ()> {
+    let client = controller::client().await?;
+    let mut interval = time::interval(config.status_refresh);
+    loop {
+        if !supervisor::tick(&mut interval, &token).await {
+            return Ok(());
+        }
+        health::heartbeat("durables_status");
+        let all_sessions = server::all_sessions();
+        for (server, sessions) in &all_sessions {
+            let result = sessions.statistics().and_then(|session| {
+                admin::list_durables(&session, config.admin_command_timeout)
+                    .inspect_err(|err| sessions.mark_broken(&session, err))
+            });
+            let res = match result {
+                Ok(res) => res,
+                Err(err) => {
+                    error!(
+                        "failed to retrieve durable information from {}: {}",
+                        server::display_name(server),
+                        err
+                    );
+                    continue;
+                }
+            };
+            let durables: HashMap<String, DurableInfo> =
+                res.into_iter().map(|d| (d.key(), d)).collect();
+            DURABLES
+                .lock()
+                .unwrap()
+                .insert(server.clone(), durables.clone());
+            if !config.read_only && leader::is_leader() {
+                update_durables_status(&client, server, &durables).await;
+            }
+        }
+        //forget the durables of removed servers
+        {
+            let mut d_map = DURABLES.lock().unwrap();
+            d_map.retain(|server, _| all_sessions.iter().any(|(key, _)| key == server));
+        }
+    }
+}
+
+/// updates the pending messages of the durable objects of a server
+async fn update_durables_status(
+    client: &Client,
+    server: &str,
+    durables: &HashMap<String, DurableInfo>,
+) {
+    for durable in DURABLE_STORE.state() {
+        if get_server(&durable.namespace().unwrap_or_default(), &durable.spec) != server {
+            continue;
+        }
+        let Ok(durable_info) = create_durable_object(&durable.spec) else {
+            continue;
+        };
+        let Some(dinfo) = durables.get(&durable_info.key()) else {
+            continue;
+        };
+        let pending_messages = dinfo.pending_messages.unwrap_or(0);
+        let update = match &durable.status {
+            Some(status) => status.pendingMessages != pending_messages,
+            None => true,
+        };
+        if !update {
+            continue;
+        }
+        let obj_name = durable.name_any();
+        debug!("updating durable status for {}", obj_name);
+        let updater: Api<Durable> = Api::namespaced(client.clone(), &durable.namespace().unwrap());
+        let status = serde_json::json!({
+            "status": {
+                "pendingMessages": pending_messages,
+            }
+        });
+        let pp = PatchParams::default();
+        if let Err(err) = updater
+            .patch_status(&obj_name, &pp, &Patch::Merge(&status))
+            .await
+        {
+            error!("error while updating durable object");
+            error!("{:?}", err);
+        }
+    }
+}
+
+/// key of the EMS server the durable belongs to
+fn get_server(namespace: &str, spec: &DurableSpec) -> String {
+    server::server_key(namespace, &spec.server)
+}
+
+/// whether the durable on the EMS receives the same messages as the desired durable
+fn is_same_subscription(current: &DurableInfo, desired: &DurableInfo) -> bool {
+    current.topic == desired.topic
+        && current.selector == desired.selector
+        && current.no_local == desired.no_local
+}
+
+/// validates the spec and creates the durable sent to the EMS
+fn create_durable_object(spec: &DurableSpec) -> Result<DurableInfo, String> {
+    if spec.topic.is_empty() {
+        return Err("topic must be set".to_owned());
+    }
+    if spec.durableName.is_empty() {
+        return Err("durableName must be set".to_owned());
+    }
+    if let Some(client_id) = &spec.clientId
+        && client_id.is_empty()
+    {
+        return Err("clientId must not be empty".to_owned());
+    }
+    Ok(DurableInfo {
+        topic: spec.topic.clone(),
+        name: spec.durableName.clone(),
+        client_id: spec.clientId.clone(),
+        selector: spec
+            .selector
+            .clone()
+            .filter(|selector| !selector.is_empty()),
+        no_local: spec.noLocal.unwrap_or(false),
+        pending_messages: None,
+    })
+}
@@ -0,0 +1,356 @@
+use super::admin::{self, FactoryInfo, FactorySslProperties, FactoryType};
+use super::config::Config;
+use super::controller::{self, Condition, Context, Error, HasConditions, SyncResult};
+use super::server;
+use futures::StreamExt;
+use kube::api::{Api, ResourceExt};
+use kube::runtime::controller::Action;
+use kube::runtime::finalizer::{finalizer, Event};
+use kube::CustomResource;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::io::ErrorKind;
+use std::sync::Arc;
+use tibco_ems::Session;
+use tokio::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+#[derive(CustomResource, Serialize, Deserialize, Default, Clone, Debug, PartialEq, JsonSchema)]
+#[kube(
+    group = "tibcoems.apimeister.com",
+    version = "v1",
+    kind = "ConnectionFactory",
+    plural = "connectionfactories",
+    status = "ConnectionFactoryStatus",
+    namespaced
+)]
+#[allow(non_snake_case)]
+pub struct ConnectionFactorySpec {
+    /// JNDI name of the factory, defaults to the object name
+    pub jndiName: Option<String>,
+    /// either generic, queue or topic, defaults to generic
+    pub r#type: Option<String>,
+    /// server url, multiple urls are separated by comma, e.g. tcp://ems1:7222,tcp://ems2:7222
+    pub url: String,
+    pub clientId: Option<String>,
+    pub connectAttemptCount: Option<u32>,
+    /// delay between connect attempts in ms
+    pub connectAttemptDelay: Option<u32>,
+    /// timeout of a connect attempt in ms
+    pub connectAttemptTimeout: Option<u32>,
+    pub reconnectAttemptCount: Option<u32>,
+    /// delay between reconnect attempts in ms
+    pub reconnectAttemptDelay: Option<u32>,
+    /// timeout of a reconnect attempt in ms
+    pub reconnectAttemptTimeout: Option<u32>,
+    pub ssl: Option<SslSpec>,
+}
+
+/// SSL parameters handed to the clients of the factory
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, JsonSchema)]
+#[allow(non_snake_case)]
+pub struct SslSpec {
+    pub verifyHost: Option<bool>,
+    pub verifyHostName: Option<bool>,
+    pub expectedHostName: Option<String>,
+    /// trusted certificate files on the client
+    pub trustedCertificates: Option<Vec<String>>,
+    /// OpenSSL cipher list
+    pub ciphers: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[allow(non_snake_case)]
+#[serde(default)]
+pub struct ConnectionFactoryStatus {
+    pub conditions: Vec<Condition>,
+    /// generation of the spec which was last reconciled
+    pub observedGeneration: Option<i64>,
+    /// last time the factory was successfully reconciled with the EMS
+    pub lastSyncTime: Option<String>,
+    /// result of the last failed admin operation
+    pub message: Option<String>,
+    /// spec of the factory last applied to the EMS, used to rename and delete it
+    pub applied: Option<ConnectionFactorySpec>,
+}
+
+impl HasConditions for ConnectionFactory {
+    fn conditions(&self) -> &[Condition] {
+        match &self.status {
+            Some(status) => &status.conditions,
+            None => &[],
+        }
+    }
+}
+
+pub async fn watch_factories(config: Arc<Config>, token: CancellationToken) -> Result<(), ()> {
+    let client = controller::client().await?;
+    let ctx = controller::Context::new(client, config, token);
+    let controllers = controller::new_controllers(&ctx, "connectionfactories");
+    controller::run_controllers(controllers, reconcile, ctx)
+        .for_each(|result| async move {
+            match result {
+                Ok((factory, _action)) => trace!("reconciled factory {}", factory.name),
+                Err(err) => debug!("factory reconciliation failed: {:?}", err),
+            }
+        })
+        .await;
+    Ok(())
+}
+
+/// brings the factory on the EMS in line with the factory object
+async fn reconcile(factory: Arc<ConnectionFactory>, ctx: Arc<Context>) -> Result<Action, Error> {
+    let key = controller::object_key(factory.as_ref());
+    let api: Api<ConnectionFactory> =
+        Api::namespaced(ctx.client.clone(), &factory.namespace().unwrap());
+    let action = finalizer(&api, controller::FINALIZER, factory, |event| async {
+        match event {
+            Event::Apply(factory) => apply_factory(&api, &factory, &ctx).await,
+            Event::Cleanup(factory) => cleanup_factory(&api, &factory, &ctx).await,
+        }
+    })
+    .await?;
+    ctx.reset_backoff(&key);
+    Ok(action)
+}
+
+/// creates the factory on the EMS or updates its properties
+///
+/// the properties are sent again on every reconciliation to correct drift, as the
+/// factories of the EMS are not listed. The factory last applied is kept in the status,
+/// so a renamed factory is removed after a restart too
+async fn apply_factory(
+    api: &Api<ConnectionFactory>,
+    factory: &ConnectionFactory,
+    ctx: &Context,
+) -> Result<Action, Error> {
+    let key = controller::object_key(factory);
+    let applied = factory
+        .status
+        .as_ref()
+        .and_then(|status| status.applied.as_ref());
+    let factory_info = match create_factory_object(factory) {
+        Ok(factory_info) => factory_info,
+        Err(message) => {
+            warn!("factory {} has an invalid spec: {}", key, message);
+            let result = SyncResult::Failed {
+                exists: applied.is_some(),
+                message: format!("invalid spec: {message}"),
+            };
+            controller::patch_sync_status(api, factory, result).await;
+            return Ok(Action::await_change());
+        }
+    };
+    let timeout = ctx.config.admin_command_timeout;
+    let result = match server::default_sessions().admin() {
+        Ok(session) => match applied {
+            Some(old) if get_jndi_name(factory, old) != factory_info.name => {
+                info!("renaming factory {}", &key);
+                create_or_update_factory(&session, &factory_info, timeout)
+                    .and_then(|_| {
+                        let old_name = get_jndi_name(factory, old);
+                        match admin::delete_factory(&session, &old_name, timeout) {
+                            Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+                            _ => Ok(Some("replaced factory".to_owned())),
+                        }
+                    })
+                    .map_err(|err| (true, err))
+            }
+            Some(old) => {
+                debug!("updating factory {}", &key);
+                let note = if old == &factory.spec {
+                    None
+                } else {
+                    Some("updated factory".to_owned())
+                };
+                admin::update_factory(&session, &factory_info, timeout)
+                    .map(|_| note)
+                    .map_err(|err| (true, err))
+            }
+            None => {
+                info!("adding factory {}", &key);
+                create_or_update_factory(&session, &factory_info, timeout)
+                    .map_err(|err| (false, err))
+            }
+        },
+        Err(err) => Err((applied.is_some(), err)),
+    };
+    match result {
+        Ok(note) => {
+            if applied != Some(&factory.spec) {
+                controller::patch_applied(api, factory, Some(&factory.spec)).await;
+            }
+            controller::patch_sync_status(api, factory, SyncResult::Synced(note)).await;
+            Ok(Action::requeue(ctx.requeue_interval()))
+        }
+        Err((exists, err)) => {
+            let message = format!("failed to apply factory: {err}");
+            let result = SyncResult::Failed { exists, message };
+            controller::patch_sync_status(api, factory, result).await;
+            Err(Error::Ems(err))
+        }
+    }
+}
+
+/// creates the factory, a factory which already exists is updated instead
+///
+/// the factory might exist from a failed rename or a status update which got lost
+fn create_or_update_factory(
+    session: &Session,
+    factory_info: &FactoryInfo,
+    timeout: Duration,
+) -> Result<Option<String>, std::io::Error> {
+    match admin::create_factory(session, factory_info, timeout) {
+        Ok(_) => Ok(Some("created factory".to_owned())),
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+            debug!("factory {} already exists, updating it", factory_info.name);
+            admin::update_factory(session, factory_info, timeout)
+                .map(|_| Some("updated factory".to_owned()))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// removes the factory last applied from the EMS before kubernetes deletes the object
+async fn cleanup_factory(
+    api: &Api<ConnectionFactory>,
+    factory: &ConnectionFactory,
+    ctx: &Context,
+) -> Result<Action, Error> {
+    let key = controller::object_key(factory);
+    if ctx.config.do_not_delete_objects {
+        warn!(
+            "delete event for {} (not executed because of DO_NOT_DELETE_OBJECTS setting)",
+            key
+        );
+        return Ok(Action::await_change());
+    }
+    let applied = factory
+        .status
+        .as_ref()
+        .and_then(|status| status.applied.as_ref());
+    let Some(applied) = applied else {
+        debug!("factory {} was never applied, nothing to delete", key);
+        return Ok(Action::await_change());
+    };
+    let jndi_name = get_jndi_name(factory, applied);
+    info!("deleting factory {}", jndi_name);
+    let result = server::default_sessions().admin().and_then(|session| {
+        admin::delete_factory(&session, &jndi_name, ctx.config.admin_command_timeout)
+    });
+    match result {
+        Err(err) if err.kind() != ErrorKind::NotFound => {
+            let message = format!("failed to delete factory {jndi_name}: {err}");
+            let result = SyncResult::Failed {
+                exists: true,
+                message,
+            };
+            controller::patch_sync_status(api, factory, result).await;
+            Err(Error::Ems(err))
+        }
+        _ => Ok(Action::await_change()),
+    }
+}
+
+/// JNDI name of the factory for the given spec, defaults to the object name
+fn get_jndi_name(factory: &ConnectionFactory, spec: &ConnectionFactorySpec) -> String {
+    match &spec.jndiName {
+        Some(name) => name.clone(),
+        None => factory.name_any(),
+    }
+}
+
+/// validates the spec and creates the factory sent to the EMS
+fn create_factory_object(factory: &ConnectionFactory) -> Result<FactoryInfo, String> {
+    let spec = &factory.spec;
+    let factory_type = match spec.r#type.as_deref().map(str::to_ascii_lowercase) {
+        None => FactoryType::Generic,
+        Some(val) if val == "generic" => FactoryType::Generic,
+        Some(val) if val == "queue" => FactoryType::Queue,
+        Some(val) if val == "topic" => FactoryType::Topic,
+        Some(val) => return Err(format!("type must be generic, queue or topic, got '{val}'")),
+    };
+    if spec.url.trim().is_empty() {
+        return Err("url must be set".to_owned());
+    }
+    let url = server::normalize_url(&spec.url)?;
+    let ssl = spec.ssl.as_ref().map(|ssl| FactorySslProperties {
+        verify_host: ssl.verifyHost,
+        verify_hostname: ssl.verifyHostName,
+        expected_hostname: ssl.expectedHostName.clone(),
+        trusted_certificates: ssl
+            .trustedCertificates
+            .as_ref()
+            .map(|files| files.join(",")),
+        ciphers: ssl.ciphers.clone(),
+    });
+    let factory_info = FactoryInfo {
+        name: get_jndi_name(factory, spec),
+        factory_type,
+        url,
+        client_id: spec.clientId.clone(),
+        connect_attempt_count: spec.connectAttemptCount.map(|val| val as i32),
+        connect_attempt_delay: spec.connectAttemptDelay.map(|val| val as i64),
+        connect_attempt_timeout: spec.connectAttemptTimeout.map(|val| val as i64),
+        reconnect_attempt_count: spec.reconnectAttemptCount.map(|val| val as i32),
+        reconnect_attempt_delay: spec.reconnectAttemptDelay.map(|val| val as i64),
+        reconnect_attempt_timeout: spec.reconnectAttemptTimeout.map(|val| val as i64),
+        ssl,
+    };
+    // show what we have created in debug mode
+    debug!("{:?}", factory_info);
+    Ok(factory_info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn factory(url: &str) -> ConnectionFactory {
+        ConnectionFactory::new(
+            "cf-test",
+            ConnectionFactorySpec {
+                url: url.to_string(),
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn fault_tolerant_urls_are_accepted() {
+        let factory_info =
+            create_factory_object(&factory("tcp://ems1:7222,ssl://ems2:7243")).unwrap();
+        assert_eq!(factory_info.name, "cf-test");
+        assert_eq!(factory_info.url, "tcp://ems1:7222,ssl://ems2:7243");
+        assert_eq!(factory_info.factory_type, FactoryType::Generic);
+    }
+
+    #[test]
+    fn urls_are_trimmed() {
+        let factory_info =
+            create_factory_object(&factory(" tcp://ems1:7222 , ssl://ems2:7243")).unwrap();
+        assert_eq!(factory_info.url, "tcp://ems1:7222,ssl://ems2:7243");
+    }
+
+    #[test]
+    fn invalid_urls_are_rejected() {
+        assert_eq!(
+            create_factory_object(&factory("")).unwrap_err(),
+            "url must be set"
+        );
+        assert_eq!(
+            create_factory_object(&factory("tcp://ems1:7222,ems2:7222")).unwrap_err(),
+            "url must start with tcp:// or ssl://, got 'ems2:7222'"
+        );
+    }
+
+    #[test]
+    fn invalid_type_is_rejected() {
+        let mut factory = factory("tcp://ems:7222");
+        factory.spec.r#type = Some("Durable".to_string());
+        assert_eq!(
+            create_factory_object(&factory).unwrap_err(),
+            "type must be generic, queue or topic, got 'durable'"
+        );
+    }
+}
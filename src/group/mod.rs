@@ -0,0 +1,221 @@
+use super::admin::{self, GroupInfo};
+use super::config::Config;
+use super::controller::{self, Condition, Context, Error, HasConditions, SyncResult};
+use super::server;
+use futures::StreamExt;
+use kube::api::{Api, ResourceExt};
+use kube::runtime::controller::Action;
+use kube::runtime::finalizer::{finalizer, Event};
+use kube::CustomResource;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use tibco_ems::Session;
+use tokio::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+#[derive(CustomResource, Serialize, Deserialize, Default, Clone, Debug, JsonSchema)]
+#[kube(
+    group = "tibcoems.apimeister.com",
+    version = "v1",
+    kind = "Group",
+    status = "GroupStatus",
+    namespaced
+)]
+#[allow(non_snake_case)]
+pub struct GroupSpec {
+    /// name of the group on the EMS, defaults to the object name
+    pub name: Option<String>,
+    pub description: Option<String>,
+    /// names of the EMS users which belong to the group
+    #[serde(default)]
+    pub members: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[allow(non_snake_case)]
+#[serde(default)]
+pub struct GroupStatus {
+    pub conditions: Vec<Condition>,
+    /// generation of the spec which was last reconciled
+    pub observedGeneration: Option<i64>,
+    /// last time the group was successfully reconciled with the EMS
+    pub lastSyncTime: Option<String>,
+    /// result of the last failed admin operation
+    pub message: Option<String>,
+    /// name of the group last applied to the EMS, the group is removed when the name changes
+    pub applied: Option<String>,
+}
+
+impl HasConditions for Group {
+    fn conditions(&self) -> &[Condition] {
+        match &self.status {
+            Some(status) => &status.conditions,
+            None => &[],
+        }
+    }
+}
+
+pub async fn watch_groups(config: Arc<Config>, token: CancellationToken) -> Result<(), ()> {
+    let client = controller::client().await?;
+    let ctx = controller::Context::new(client, config, token);
+    let controllers = controller::new_controllers(&ctx, "groups");
+    controller::run_controllers(controllers, reconcile, ctx)
+        .for_each(|result| async move {
+            match result {
+                Ok((group, _action)) => trace!("reconciled group {}", group.name),
+                Err(err) => debug!("group reconciliation failed: {:?}", err),
+            }
+        })
+        .await;
+    Ok(())
+}
+
+/// brings the group on the EMS in line with the group object
+async fn reconcile(group: Arc<Group>, ctx: Arc<Context>) -> Result<Action, Error> {
+    let key = controller::object_key(group.as_ref());
+    let api: Api<Group> = Api::namespaced(ctx.client.clone(), &group.namespace().unwrap());
+    let action = finalizer(&api, controller::FINALIZER, group, |event| async {
+        match event {
+            Event::Apply(group) => apply_group(&api, &group, &ctx).await,
+            Event::Cleanup(group) => cleanup_group(&api, &group, &ctx).await,
+        }
+    })
+    .await?;
+    ctx.reset_backoff(&key);
+    Ok(action)
+}
+
+/// creates the group on the EMS and reconciles its members
+///
+/// the members are compared with the members listed by the EMS, so users added or
+/// removed outside of the operator are reset to the spec
+async fn apply_group(api: &Api<Group>, group: &Group, ctx: &Context) -> Result<Action, Error> {
+    let timeout = ctx.config.admin_command_timeout;
+    let group_info = GroupInfo {
+        name: get_group_name(group),
+        description: group.spec.description.clone(),
+    };
+    let members: BTreeSet<String> = group.spec.members.iter().cloned().collect();
+    let applied = group
+        .status
+        .as_ref()
+        .and_then(|status| status.applied.clone());
+    let result = match server::default_sessions().admin() {
+        Ok(session) => {
+            if let Some(previous) = &applied
+                && previous != &group_info.name
+            {
+                //the group was renamed, the new group is created below
+                info!("removing group {} after rename", previous);
+                match admin::delete_group(&session, previous, timeout) {
+                    Err(err) if err.kind() != ErrorKind::NotFound => {
+                        warn!("failed to remove renamed group: {err}");
+                    }
+                    _ => {}
+                }
+            }
+            sync_group(&session, &group_info, &members, timeout)
+        }
+        Err(err) => Err((applied.is_some(), err)),
+    };
+    match result {
+        Ok(note) => {
+            if applied.as_ref() != Some(&group_info.name) {
+                controller::patch_applied(api, group, Some(&group_info.name)).await;
+            }
+            controller::patch_sync_status(api, group, SyncResult::Synced(note)).await;
+            Ok(Action::requeue(ctx.requeue_interval()))
+        }
+        Err((exists, err)) => {
+            let message = format!("failed to apply group: {err}");
+            let result = SyncResult::Failed { exists, message };
+            controller::patch_sync_status(api, group, result).await;
+            Err(Error::Ems(err))
+        }
+    }
+}
+
+/// creates or updates the group and adds or removes members until they match the spec
+///
+/// on failure the error tells whether the group is present on the EMS
+fn sync_group(
+    session: &Session,
+    group: &GroupInfo,
+    members: &BTreeSet<String>,
+    timeout: Duration,
+) -> Result<Option<String>, (bool, std::io::Error)> {
+    let mut changes = Vec::new();
+    match admin::create_group(session, group, timeout) {
+        Ok(_) => changes.push("created group".to_owned()),
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+            admin::update_group(session, group, timeout).map_err(|err| (true, err))?;
+        }
+        Err(err) => return Err((false, err)),
+    }
+    let current_members: BTreeSet<String> =
+        admin::list_group_members(session, &group.name, timeout)
+            .map_err(|err| (true, err))?
+            .into_iter()
+            .collect();
+    for user in members.difference(&current_members) {
+        admin::add_group_member(session, &group.name, user, timeout).map_err(|err| (true, err))?;
+        changes.push(format!("added {user}"));
+    }
+    for user in current_members.difference(members) {
+        admin::remove_group_member(session, &group.name, user, timeout)
+            .map_err(|err| (true, err))?;
+        changes.push(format!("removed {user}"));
+    }
+    if changes.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(changes.join(", ")))
+    }
+}
+
+/// removes the group from the EMS before kubernetes deletes the object
+async fn cleanup_group(api: &Api<Group>, group: &Group, ctx: &Context) -> Result<Action, Error> {
+    let key = controller::object_key(group);
+    if ctx.config.do_not_delete_objects {
+        warn!(
+            "delete event for {} (not executed because of DO_NOT_DELETE_OBJECTS setting)",
+            key
+        );
+        return Ok(Action::await_change());
+    }
+    let group_name = group
+        .status
+        .as_ref()
+        .and_then(|status| status.applied.clone())
+        .unwrap_or_else(|| get_group_name(group));
+    info!("deleting group {}", group_name);
+    let result = server::default_sessions().admin().and_then(|session| {
+        admin::delete_group(&session, &group_name, ctx.config.admin_command_timeout)
+    });
+    match result {
+        Ok(_) => Ok(Action::await_change()),
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            debug!("group {} does not exist", group_name);
+            Ok(Action::await_change())
+        }
+        Err(err) => {
+            let message = format!("failed to delete group {group_name}: {err}");
+            let result = SyncResult::Failed {
+                exists: true,
+                message,
+            };
+            controller::patch_sync_status(api, group, result).await;
+            Err(Error::Ems(err))
+        }
+    }
+}
+
+fn get_group_name(group: &Group) -> String {
+    match &group.spec.name {
+        Some(name) => name.clone(),
+        None => group.name_any(),
+    }
+}
@@ -0,0 +1,116 @@
+use super::config::Config;
+use super::server::{self, ConnectionState};
+use super::supervisor::{self, TaskState};
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::watch;
+use tokio::time::Duration;
+
+/// time the EMS client library waits for the reply of a list command
+const LIST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// consecutive failures after which a restarting task fails the liveness
+const MAX_TASK_FAILURES: u32 = 5;
+
+/// start of the last iteration of the polling tasks by name
+static HEARTBEATS: Lazy<Mutex<HashMap<&'static str, Instant>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// last successful listing of the default server by kind, e.g. queues
+static LISTINGS: Lazy<Mutex<HashMap<&'static str, Instant>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// marks another iteration of a polling task, a task which stops beating is considered wedged
+pub fn heartbeat(name: &'static str) {
+    let mut heartbeats = HEARTBEATS.lock().unwrap();
+    heartbeats.insert(name, Instant::now());
+}
+
+/// records a successful listing of the default server
+pub fn listed(kind: &'static str) {
+    let mut listings = LISTINGS.lock().unwrap();
+    listings.insert(kind, Instant::now());
+}
+
+/// liveness, fails if a task keeps failing or a polling task stopped
+///
+/// a list command may block for up to a minute per server, so a polling task is only
+/// considered wedged after several missed iterations
+pub async fn healthz(State(config): State<watch::Receiver<Arc<Config>>>) -> impl IntoResponse {
+    let stalled_after = config.borrow().status_refresh * 3 + LIST_TIMEOUT * 2;
+    let mut healthy = true;
+    let mut tasks = serde_json::Map::new();
+    {
+        let heartbeats = HEARTBEATS.lock().unwrap();
+        for (name, state) in supervisor::tasks() {
+            let (state, ok) = match state {
+                TaskState::Restarting { failures } => ("restarting", failures < MAX_TASK_FAILURES),
+                TaskState::Running
+                    if heartbeats
+                        .get(name)
+                        .is_some_and(|beat| beat.elapsed() > stalled_after) =>
+                {
+                    ("stalled", false)
+                }
+                TaskState::Running => ("running", true),
+            };
+            healthy &= ok;
+            tasks.insert(name.to_string(), serde_json::json!(state));
+        }
+    }
+    let body = serde_json::json!({
+        "status": if healthy { "ok" } else { "failing" },
+        "tasks": tasks,
+    });
+    (status_code(healthy), Json(body))
+}
+
+/// readiness, fails while the default server is not connected or its statistics are outdated
+pub async fn readyz(State(config): State<watch::Receiver<Arc<Config>>>) -> impl IntoResponse {
+    let outdated_after = config.borrow().status_refresh * 3 + LIST_TIMEOUT;
+    let (connected, connection) = match server::default_sessions().state() {
+        ConnectionState::Connected { active_url } => (
+            true,
+            serde_json::json!({ "state": "connected", "activeUrl": active_url }),
+        ),
+        ConnectionState::Pending => (false, serde_json::json!({ "state": "pending" })),
+        ConnectionState::Disconnected { error, failures } => (
+            false,
+            serde_json::json!({
+                "state": "disconnected",
+                "error": error,
+                "failures": failures,
+            }),
+        ),
+    };
+    let mut ready = connected;
+    let mut listings = serde_json::Map::new();
+    {
+        let last_listings = LISTINGS.lock().unwrap();
+        for kind in ["queues", "topics"] {
+            let elapsed = last_listings.get(kind).map(Instant::elapsed);
+            ready &= elapsed.is_some_and(|elapsed| elapsed <= outdated_after);
+            listings.insert(
+                kind.to_owned(),
+                serde_json::json!(elapsed.map(|elapsed| elapsed.as_secs())),
+            );
+        }
+    }
+    let body = serde_json::json!({
+        "status": if ready { "ok" } else { "failing" },
+        "ems": connection,
+        "secondsSinceLastList": listings,
+    });
+    (status_code(ready), Json(body))
+}
+
+fn status_code(ok: bool) -> StatusCode {
+    if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
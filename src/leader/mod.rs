@@ -0,0 +1,220 @@
+use super::config::Config;
+use super::controller;
+use k8s_openapi::api::coordination::v1::{Lease, LeaseSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::MicroTime;
+use k8s_openapi::jiff::Timestamp;
+use kube::api::{ObjectMeta, PostParams};
+use kube::Api;
+use once_cell::sync::Lazy;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::time::{self, Duration};
+use tokio_util::sync::CancellationToken;
+
+/// time after which a lease which was not renewed can be taken over
+const LEASE_DURATION_SECONDS: i32 = 15;
+/// time within which the leader has to renew the lease, otherwise it stops managing objects
+///
+/// shorter than the lease duration, so the leader stops before another replica can take over
+const RENEW_DEADLINE: Duration = Duration::from_secs(10);
+/// how often the leader renews the lease and the other replicas try to acquire it
+const RETRY_PERIOD: Duration = Duration::from_secs(5);
+
+/// whether this replica manages the objects
+static LEADING: Lazy<watch::Sender<bool>> = Lazy::new(|| watch::Sender::new(false));
+
+/// true if this replica is the leader, or leader election is disabled
+pub fn is_leader() -> bool {
+    *LEADING.borrow()
+}
+
+/// makes this replica the leader without a lease, used if LEADER_ELECTION is disabled
+pub fn assume() {
+    LEADING.send_replace(true);
+}
+
+/// waits until this replica is the leader
+pub async fn acquired() {
+    let mut leading = LEADING.subscribe();
+    let _ignore = leading.wait_for(|leading| *leading).await;
+}
+
+/// waits until this replica is no longer the leader
+pub async fn lost() {
+    let mut leading = LEADING.subscribe();
+    let _ignore = leading.wait_for(|leading| !*leading).await;
+}
+
+/// acquires the lease and keeps renewing it until the token is cancelled, then releases it
+///
+/// a leader which fails to renew the lease within RENEW_DEADLINE stops leading, which stops
+/// the leader-only tasks, and competes for the lease again. Releasing the lease on shutdown
+/// lets another replica take over without waiting for the lease to expire
+pub async fn run(config: Arc<Config>, token: CancellationToken) -> Result<(), ()> {
+    let client = controller::client().await?;
+    let leases: Api<Lease> = Api::namespaced(client, &config.lease_namespace);
+    let mut interval = time::interval(RETRY_PERIOD);
+    loop {
+        info!(
+            "waiting for lease {}/{} as {}",
+            config.lease_namespace, config.lease_name, config.identity
+        );
+        if !acquire(&leases, &config, &mut interval, &token).await {
+            return Ok(());
+        }
+        info!("acquired lease {}, managing objects", config.lease_name);
+        LEADING.send_replace(true);
+        let cancelled = renew(&leases, &config, &mut interval, &token).await;
+        LEADING.send_replace(false);
+        if cancelled {
+            break;
+        }
+    }
+    match release(&leases, &config.lease_name, &config.identity).await {
+        Ok(_) => info!("released lease {}", config.lease_name),
+        Err(err) => warn!("failed to release lease {}: {}", config.lease_name, err),
+    }
+    Ok(())
+}
+
+/// tries to acquire the lease on every tick, returns false if the token was cancelled before
+async fn acquire(
+    leases: &Api<Lease>,
+    config: &Config,
+    interval: &mut time::Interval,
+    token: &CancellationToken,
+) -> bool {
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = token.cancelled() => return false,
+        }
+        match try_acquire(leases, &config.lease_name, &config.identity).await {
+            Ok(true) => return true,
+            Ok(false) => trace!("lease {} is held by another replica", config.lease_name),
+            Err(err) => warn!("failed to acquire lease {}: {}", config.lease_name, err),
+        }
+    }
+}
+
+/// renews the lease on every tick until the token is cancelled or the lease is lost
+///
+/// returns true if the token was cancelled, false if the lease was lost or could not be
+/// renewed within RENEW_DEADLINE
+async fn renew(
+    leases: &Api<Lease>,
+    config: &Config,
+    interval: &mut time::Interval,
+    token: &CancellationToken,
+) -> bool {
+    let mut renewed_at = time::Instant::now();
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = token.cancelled() => return true,
+        }
+        //a hanging request must not keep this replica leading beyond the deadline
+        let deadline = renewed_at + RENEW_DEADLINE;
+        let result = time::timeout_at(
+            deadline,
+            try_acquire(leases, &config.lease_name, &config.identity),
+        )
+        .await;
+        match result {
+            Ok(Ok(true)) => renewed_at = time::Instant::now(),
+            Ok(Ok(false)) => {
+                error!(
+                    "lost lease {} to another replica, no longer managing objects",
+                    config.lease_name
+                );
+                return false;
+            }
+            Ok(Err(err)) if time::Instant::now() < deadline => {
+                warn!("failed to renew lease {}: {}", config.lease_name, err);
+            }
+            _ => {
+                error!(
+                    "could not renew lease {} within {:?}, no longer managing objects",
+                    config.lease_name, RENEW_DEADLINE
+                );
+                return false;
+            }
+        }
+    }
+}
+
+/// clears the holder of the lease if this replica still holds it
+async fn release(leases: &Api<Lease>, name: &str, identity: &str) -> Result<(), kube::Error> {
+    let lease = leases.get(name).await?;
+    let mut spec = lease.spec.clone().unwrap_or_default();
+    if spec.holder_identity.as_deref() != Some(identity) {
+        return Ok(());
+    }
+    spec.holder_identity = None;
+    spec.renew_time = Some(MicroTime(Timestamp::now()));
+    let lease = Lease {
+        metadata: lease.metadata,
+        spec: Some(spec),
+    };
+    leases.replace(name, &PostParams::default(), &lease).await?;
+    Ok(())
+}
+
+/// creates, renews or takes over the lease, returns false if another replica holds it
+///
+/// updates carry the resourceVersion, so only one of two competing replicas succeeds
+async fn try_acquire(leases: &Api<Lease>, name: &str, identity: &str) -> Result<bool, kube::Error> {
+    let now = Timestamp::now();
+    let lease = match leases.get_opt(name).await? {
+        Some(lease) => lease,
+        None => {
+            let lease = Lease {
+                metadata: ObjectMeta {
+                    name: Some(name.to_owned()),
+                    ..Default::default()
+                },
+                spec: Some(LeaseSpec {
+                    holder_identity: Some(identity.to_owned()),
+                    lease_duration_seconds: Some(LEASE_DURATION_SECONDS),
+                    acquire_time: Some(MicroTime(now)),
+                    renew_time: Some(MicroTime(now)),
+                    lease_transitions: Some(0),
+                    ..Default::default()
+                }),
+            };
+            return match leases.create(&PostParams::default(), &lease).await {
+                Ok(_) => Ok(true),
+                //created by another replica in the meantime
+                Err(kube::Error::Api(err)) if err.code == 409 => Ok(false),
+                Err(err) => Err(err),
+            };
+        }
+    };
+    let mut spec = lease.spec.clone().unwrap_or_default();
+    let holder = spec.holder_identity.clone().unwrap_or_default();
+    if holder != identity {
+        let duration = spec
+            .lease_duration_seconds
+            .unwrap_or(LEASE_DURATION_SECONDS) as i64;
+        let expired = match &spec.renew_time {
+            Some(MicroTime(renew_time)) => renew_time.as_second() + duration < now.as_second(),
+            None => true,
+        };
+        if !holder.is_empty() && !expired {
+            return Ok(false);
+        }
+        info!("taking over lease {} from '{}'", name, holder);
+        spec.holder_identity = Some(identity.to_owned());
+        spec.acquire_time = Some(MicroTime(now));
+        spec.lease_duration_seconds = Some(LEASE_DURATION_SECONDS);
+        spec.lease_transitions = Some(spec.lease_transitions.unwrap_or(0) + 1);
+    }
+    spec.renew_time = Some(MicroTime(now));
+    let lease = Lease {
+        metadata: lease.metadata,
+        spec: Some(spec),
+    };
+    //fails with a conflict if another replica changed the lease since it was read
+    leases.replace(name, &PostParams::default(), &lease).await?;
+    Ok(true)
+}
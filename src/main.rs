@@ -1,36 +1,48 @@
 use axum::{
-    http::HeaderMap,
     http::{StatusCode, Uri},
     response::IntoResponse,
     routing::get,
     Json, Router,
 };
-use once_cell::sync::Lazy;
-use std::panic;
+use config::Config;
+use std::collections::HashMap;
+use std::future::Future;
 use std::process;
-use std::sync::Mutex;
+use std::sync::Arc;
+use supervisor::TaskGroup;
 use tibco_ems::admin::{QueueInfo, TopicInfo};
-use tibco_ems::Session;
+use tokio::sync::{mpsc, watch};
+use tokio::time::Duration;
+use tokio_util::sync::CancellationToken;
 use urlencoding::decode;
 
+mod admin;
 mod bridge;
+mod config;
+mod controller;
+mod destination;
+mod durable;
+mod factory;
+mod group;
+mod health;
+mod leader;
+mod metrics;
+mod permission;
 mod queue;
+mod route;
 mod scaler;
+mod server;
+mod supervisor;
 mod topic;
+mod user;
 
 #[macro_use]
 extern crate log;
-#[macro_use]
-extern crate env_var;
 
-// look for responsible settings
-pub static RESPONSIBLE_FOR: Lazy<Mutex<String>> = Lazy::new(|| {
-    let rf = env_var!(required "RESPONSIBLE_FOR", default: "");
-    if !rf.is_empty() {
-        info!("RESPONSIBLE_FOR {rf} => only object for that instance will be monitored");
-    };
-    Mutex::new(rf)
-});
+/// time the tasks are given to finish their work after they were cancelled
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(20);
+/// time given to release the lease, after the other tasks are stopped
+const RELEASE_TIMEOUT: Duration = Duration::from_secs(5);
 
 async fn get_queue_stats(uri: Uri) -> impl IntoResponse {
     let uri = uri.path();
@@ -46,8 +58,10 @@ async fn get_queue_stats(uri: Uri) -> impl IntoResponse {
             consumer_count: Some(0),
             ..Default::default()
         };
-        //get queues
-        let c_map = queue::QUEUES.lock().unwrap();
+        //get queues of the default server
+        let server_queues = queue::QUEUES.lock().unwrap();
+        let empty = HashMap::new();
+        let c_map = server_queues.get(server::DEFAULT_SERVER).unwrap_or(&empty);
         let mut pending_messages = 0;
         let mut consumer_count = 0;
         for key in c_map.keys() {
@@ -63,8 +77,10 @@ async fn get_queue_stats(uri: Uri) -> impl IntoResponse {
         all_queues.consumer_count = Some(consumer_count);
         (StatusCode::OK, Json(all_queues.clone()))
     } else {
-        //get single queue
-        let c_map = queue::QUEUES.lock().unwrap();
+        //get single queue of the default server
+        let server_queues = queue::QUEUES.lock().unwrap();
+        let empty = HashMap::new();
+        let c_map = server_queues.get(server::DEFAULT_SERVER).unwrap_or(&empty);
         for key in c_map.keys() {
             let qinfo = c_map.get(key).unwrap();
             if qinfo.name == queue_name {
@@ -97,8 +113,10 @@ async fn get_topic_stats(uri: Uri) -> impl IntoResponse {
             durable_count: Some(0),
             ..Default::default()
         };
-        //get topics
-        let c_map = topic::TOPICS.lock().unwrap();
+        //get topics of the default server
+        let server_topics = topic::TOPICS.lock().unwrap();
+        let empty = HashMap::new();
+        let c_map = server_topics.get(server::DEFAULT_SERVER).unwrap_or(&empty);
         let mut pending_messages = 0;
         let mut subscriber_count = 0;
         let mut durable_count = 0;
@@ -117,8 +135,10 @@ async fn get_topic_stats(uri: Uri) -> impl IntoResponse {
         all_topics.durable_count = Some(durable_count);
         (StatusCode::OK, Json(all_topics.clone()))
     } else {
-        //get single topic
-        let c_map = topic::TOPICS.lock().unwrap();
+        //get single topic of the default server
+        let server_topics = topic::TOPICS.lock().unwrap();
+        let empty = HashMap::new();
+        let c_map = server_topics.get(server::DEFAULT_SERVER).unwrap_or(&empty);
         for key in c_map.keys() {
             let tinfo = c_map.get(key).unwrap();
             if tinfo.name == topic_name {
@@ -137,72 +157,6 @@ async fn get_topic_stats(uri: Uri) -> impl IntoResponse {
     }
 }
 
-async fn get_metrics(uri: Uri) -> impl IntoResponse {
-    let uri = uri.path();
-    trace!("{uri}");
-    let mut body = "".to_owned();
-    body.push_str("# TYPE Q:pendingMessages gauge\n");
-    body.push_str("# TYPE Q:consumers gauge\n");
-    body.push_str("# TYPE T:pendingMessages gauge\n");
-    body.push_str("# TYPE T:subscribers gauge\n");
-    body.push_str("# TYPE T:durables gauge\n");
-    //get queues
-    let c_map = queue::QUEUES.lock().unwrap();
-    for key in c_map.keys() {
-        let qinfo = c_map.get(key).unwrap();
-        let pending = format!(
-            "Q:pendingMessages{{queue=\"{}\" instance=\"EMS-ESB\"}} {}\n",
-            qinfo.name,
-            qinfo.pending_messages.unwrap()
-        );
-        let consumers = format!(
-            "Q:consumers{{queue=\"{}\" instance=\"EMS-ESB\"}} {}\n",
-            qinfo.name,
-            qinfo.consumer_count.unwrap()
-        );
-        body.push_str(&pending);
-        body.push_str(&consumers);
-    }
-    //get topics
-    let c_map = topic::TOPICS.lock().unwrap();
-    for key in c_map.keys() {
-        let tinfo = c_map.get(key).unwrap();
-        let pending = format!(
-            "T:pendingMessages{{topic=\"{}\" instance=\"EMS-ESB\"}} {}\n",
-            tinfo.name,
-            tinfo.pending_messages.unwrap()
-        );
-        let subscribers = format!(
-            "T:subscribers{{topic=\"{}\" instance=\"EMS-ESB\"}} {}\n",
-            tinfo.name,
-            tinfo.subscriber_count.unwrap()
-        );
-        let durables = format!(
-            "T:durables{{topic=\"{}\" instance=\"EMS-ESB\"}} {}\n",
-            tinfo.name,
-            tinfo.durable_count.unwrap()
-        );
-        body.push_str(&pending);
-        body.push_str(&subscribers);
-        body.push_str(&durables);
-    }
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        "Content-Type",
-        "text/plain; version=0.0.4; charset=utf-8".parse().unwrap(),
-    );
-    (StatusCode::OK, headers, body)
-}
-
-pub fn init_admin_connection() -> Session {
-    let username = env_var!(required "USERNAME");
-    let password = env_var!(required "PASSWORD");
-    let server_url = env_var!(required "SERVER_URL");
-    let conn = tibco_ems::admin::connect(&server_url, &username, &password).unwrap();
-    info!("creating admin connection");
-    conn.session().unwrap()
-}
-
 async fn api() -> String {
     "tibco-ems-operator".to_string()
 }
@@ -211,66 +165,270 @@ async fn api() -> String {
 async fn main() {
     env_logger::init();
     info!("starting tibco-ems-operator");
+    let config = match Config::load() {
+        Ok(config) => Arc::new(config),
+        Err(err) => {
+            error!("invalid configuration: {err}");
+            process::exit(1);
+        }
+    };
+    debug!("{:?}", config);
     // look for responsible settings
-    let _res_test = RESPONSIBLE_FOR.lock().unwrap().clone();
-
-    //add panic hook to shutdown engine on error
-    let orig_hook = panic::take_hook();
-    panic::set_hook(Box::new(move |panic_info| {
-        error!("receiving panic hook, shutting down engine");
-        orig_hook(panic_info);
+    if !config.responsible_for.is_empty() {
+        info!(
+            "RESPONSIBLE_FOR {} => only object for that instance will be monitored",
+            config.responsible_for
+        );
+    }
+    // validate the connection settings of the default server
+    if let Err(err) = server::configure(&config) {
+        error!("invalid connection settings: {err}");
         process::exit(1);
-    }));
-
-    let read_only = env_var!(optional "READ_ONLY", default:"FALSE");
-    if read_only == "FALSE" {
-        //watch custom resource objects
-        let _ignore = tokio::spawn(queue::watch_queues());
-        let _ignore = tokio::spawn(topic::watch_topics());
-        let _ignore = tokio::spawn(bridge::watch_bridges());
     }
 
-    //watch object statistics
-    let _ignore = tokio::spawn(queue::watch_queues_status());
-    let _ignore = tokio::spawn(topic::watch_topics_status());
+    //watch for shutdown and reload signals
+    let mut signals = listen_for_signals();
 
-    let scaling = env_var!(optional "ENABLE_SCALING", default:"FALSE");
-    if scaling == "TRUE" {
-        //watch custom resource objects
-        let _ignore = tokio::spawn(scaler::run());
+    //the lease is kept across reloads and released last on shutdown
+    let mut base = TaskGroup::default();
+    if config.leader_election {
+        spawn_task(&mut base, "lease", &config, false, leader::run);
+    } else {
+        leader::assume();
     }
+    let mut tasks = spawn_tasks(&config);
 
-    //watch for shutdown signal
-    tokio::spawn(sighup());
-
-    //spawn metrics server
-    let addr = "0.0.0.0:8080";
+    //spawn metrics server, the health checks follow reloaded settings
+    let (config_tx, config_rx) = watch::channel(config.clone());
+    let addr = config.listen_address;
     let app = Router::new()
         .route("/", get(api))
         .route("/queue/{queuename}", get(get_queue_stats))
         .route("/topic/{topicname}", get(get_topic_stats))
-        .route("/metrics", get(get_metrics));
+        .route(
+            "/metrics",
+            get(metrics::get_metrics).with_state(config_rx.clone()),
+        )
+        .route(
+            "/healthz",
+            get(health::healthz).with_state(config_rx.clone()),
+        )
+        .route("/readyz", get(health::readyz).with_state(config_rx));
 
     info!("listening on {}", addr);
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    let http_shutdown = CancellationToken::new();
+    let http = tokio::spawn(
+        axum::serve(listener, app)
+            .with_graceful_shutdown(http_shutdown.clone().cancelled_owned())
+            .into_future(),
+    );
+
+    while let Some(Signal::Reload) = signals.recv().await {
+        let current = config_tx.borrow().clone();
+        let reloaded = match Config::load() {
+            Ok(config) => Arc::new(config),
+            Err(err) => {
+                error!("keeping the current settings, reloaded configuration is invalid: {err}");
+                continue;
+            }
+        };
+        if let Err(err) = server::configure(&reloaded) {
+            error!("keeping the current settings, invalid connection settings: {err}");
+            continue;
+        }
+        if reloaded.listen_address != current.listen_address
+            || reloaded.leader_election != current.leader_election
+            || reloaded.lease_name != current.lease_name
+            || reloaded.lease_namespace != current.lease_namespace
+        {
+            warn!("LISTEN_ADDRESS, LEADER_ELECTION and the lease settings require a restart");
+        }
+        debug!("{:?}", reloaded);
+        tasks.stop(SHUTDOWN_TIMEOUT).await;
+        tasks = spawn_tasks(&reloaded);
+        config_tx.send_replace(reloaded);
+        info!("reloaded settings");
+    }
 
-    std::thread::park();
+    //finish running reconciliations, then give up the lease and disconnect
+    info!("shutting down");
+    tasks.stop(SHUTDOWN_TIMEOUT).await;
+    base.stop(RELEASE_TIMEOUT).await;
+    server::close_sessions();
+    http_shutdown.cancel();
+    if let Ok(Err(err)) = http.await {
+        error!("metrics server failed: {err}");
+    }
     info!("done");
 }
 
+/// starts the tasks which depend on the settings, they are restarted on reload
+///
+/// objects are only managed and deployments only scaled by the leader, without
+/// LEADER_ELECTION these tasks start right away
+fn spawn_tasks(config: &Arc<Config>) -> TaskGroup {
+    let mut tasks = TaskGroup::default();
+    //reconnect the default server when mounted credentials change
+    spawn_task(
+        &mut tasks,
+        "credentials",
+        config,
+        false,
+        server::watch_credentials,
+    );
+
+    //watch object statistics
+    spawn_task(
+        &mut tasks,
+        "queues_status",
+        config,
+        false,
+        queue::watch_queues_status,
+    );
+    spawn_task(
+        &mut tasks,
+        "topics_status",
+        config,
+        false,
+        topic::watch_topics_status,
+    );
+    spawn_task(
+        &mut tasks,
+        "durables_status",
+        config,
+        false,
+        durable::watch_durables_status,
+    );
+    spawn_task(
+        &mut tasks,
+        "routes_status",
+        config,
+        false,
+        route::watch_routes_status,
+    );
+
+    if !config.read_only {
+        //watch custom resource objects
+        spawn_task(&mut tasks, "queues", config, true, queue::watch_queues);
+        spawn_task(&mut tasks, "topics", config, true, topic::watch_topics);
+        spawn_task(&mut tasks, "bridges", config, true, bridge::watch_bridges);
+        spawn_task(
+            &mut tasks,
+            "permissions",
+            config,
+            true,
+            permission::watch_permissions,
+        );
+        spawn_task(&mut tasks, "users", config, true, user::watch_users);
+        spawn_task(&mut tasks, "groups", config, true, group::watch_groups);
+        spawn_task(
+            &mut tasks,
+            "durables",
+            config,
+            true,
+            durable::watch_durables,
+        );
+        spawn_task(
+            &mut tasks,
+            "factories",
+            config,
+            true,
+            factory::watch_factories,
+        );
+        spawn_task(&mut tasks, "routes", config, true, route::watch_routes);
+        spawn_task(&mut tasks, "servers", config, true, server::watch_servers);
+    }
+    if config.enable_scaling {
+        //watch deployments with scaling labels
+        spawn_task(&mut tasks, "scaler", config, true, scaler::run);
+    }
+    tasks
+}
+
+/// supervises a task which is given the settings, a leader task first waits for the leadership
+fn spawn_task<F, Fut>(
+    group: &mut TaskGroup,
+    name: &'static str,
+    config: &Arc<Config>,
+    leader_only: bool,
+    task: F,
+) where
+    F: Fn(Arc<Config>, CancellationToken) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future + Send + 'static,
+    Fut::Output: Send + 'static,
+{
+    let config = config.clone();
+    group.spawn(name, move |token: CancellationToken| {
+        let config = config.clone();
+        let task = task.clone();
+        async move {
+            if !leader_only {
+                let _ignore = task(config, token).await;
+                return;
+            }
+            loop {
+                tokio::select! {
+                    _ = leader::acquired() => {}
+                    _ = token.cancelled() => return,
+                }
+                let leading = token.child_token();
+                let run = task(config.clone(), leading.clone());
+                tokio::pin!(run);
+                tokio::select! {
+                    _ = &mut run => return,
+                    _ = leader::lost() => {
+                        //dropped instead of drained, a former leader must not act anymore
+                        info!("stopping task {name}, no longer the leader");
+                        leading.cancel();
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// signals handled by the operator
+enum Signal {
+    /// SIGTERM, stop the tasks and exit
+    Terminate,
+    /// SIGHUP, reload the settings and restart the tasks
+    Reload,
+}
+
 #[cfg(not(target_os = "windows"))]
-async fn sighup() {
-    let mut stream =
-        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).unwrap();
-    stream.recv().await;
-    info!("got SIGTERM, shutting down");
-    std::process::exit(0);
+fn listen_for_signals() -> mpsc::Receiver<Signal> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    let mut hangup = signal(SignalKind::hangup()).unwrap();
+    let (sender, receiver) = mpsc::channel(1);
+    tokio::spawn(async move {
+        loop {
+            let signal = tokio::select! {
+                _ = terminate.recv() => {
+                    info!("got SIGTERM, shutting down");
+                    Signal::Terminate
+                }
+                _ = hangup.recv() => {
+                    info!("got SIGHUP, reloading settings");
+                    Signal::Reload
+                }
+            };
+            if sender.send(signal).await.is_err() {
+                return;
+            }
+        }
+    });
+    receiver
 }
 
 #[cfg(target_os = "windows")]
-async fn sighup() {
-    tokio::signal::ctrl_c().await.unwrap();
-    info!("got SIGTERM, shutting down");
-    std::process::exit(0);
+fn listen_for_signals() -> mpsc::Receiver<Signal> {
+    let (sender, receiver) = mpsc::channel(1);
+    tokio::spawn(async move {
+        tokio::signal::ctrl_c().await.unwrap();
+        info!("got SIGTERM, shutting down");
+        let _ignore = sender.send(Signal::Terminate).await;
+    });
+    receiver
 }
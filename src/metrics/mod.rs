@@ -0,0 +1,316 @@
+use super::config::Config;
+use super::queue;
+use super::server::{self, ConnectionState};
+use super::topic;
+use axum::{extract::State, http::HeaderMap, http::StatusCode, response::IntoResponse};
+use std::collections::HashMap;
+use std::fmt::{Display, Write};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// statistics of a queue or topic as reported by the EMS
+struct Destination {
+    /// label of the server, see server_label
+    server: String,
+    /// namespace of the object managing the destination, empty for destinations without object
+    namespace: String,
+    name: String,
+    pending_messages: i64,
+    /// consumers of a queue, subscribers of a topic
+    consumers: i64,
+    /// durable subscriptions, always 0 for queues
+    durables: i64,
+}
+
+/// gauge reported for every queue or topic
+struct Gauge {
+    name: &'static str,
+    /// name of previous releases, reported with LEGACY_METRICS
+    legacy_name: &'static str,
+    help: &'static str,
+    value: fn(&Destination) -> i64,
+}
+
+const QUEUE_GAUGES: &[Gauge] = &[
+    Gauge {
+        name: "tibco_ems_queue_pending_messages",
+        legacy_name: "Q:pendingMessages",
+        help: "messages pending on the queue",
+        value: |queue| queue.pending_messages,
+    },
+    Gauge {
+        name: "tibco_ems_queue_consumers",
+        legacy_name: "Q:consumers",
+        help: "consumers of the queue",
+        value: |queue| queue.consumers,
+    },
+];
+
+const TOPIC_GAUGES: &[Gauge] = &[
+    Gauge {
+        name: "tibco_ems_topic_pending_messages",
+        legacy_name: "T:pendingMessages",
+        help: "messages pending on the topic",
+        value: |topic| topic.pending_messages,
+    },
+    Gauge {
+        name: "tibco_ems_topic_subscribers",
+        legacy_name: "T:subscribers",
+        help: "subscribers of the topic",
+        value: |topic| topic.consumers,
+    },
+    Gauge {
+        name: "tibco_ems_topic_durables",
+        legacy_name: "T:durables",
+        help: "durable subscriptions of the topic",
+        value: |topic| topic.durables,
+    },
+];
+
+/// metrics in the prometheus text exposition format
+struct Exposition {
+    body: String,
+}
+
+impl Exposition {
+    /// starts a metric family, its samples have to follow
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ignore = writeln!(self.body, "# HELP {name} {help}");
+        let _ignore = writeln!(self.body, "# TYPE {name} {kind}");
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        self.body.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(label, value)| format!("{label}=\"{}\"", escape(value)))
+                .collect();
+            let _ignore = write!(self.body, "{{{}}}", labels.join(","));
+        }
+        let _ignore = writeln!(self.body, " {value}");
+    }
+}
+
+/// escapes a label value, backslash, double quote and line feed must not appear unescaped
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// label of a server, METRICS_SERVER_LABEL for the default server and namespace/name
+/// for EmsServer objects
+fn server_label(config: &Config, server: &str) -> String {
+    if server == server::DEFAULT_SERVER {
+        config.metrics_server_label.clone()
+    } else {
+        server.to_owned()
+    }
+}
+
+/// statistics of all servers in prometheus format
+///
+/// with LEGACY_METRICS the metrics are additionally reported with the names of previous
+/// releases, like Q:pendingMessages, so dashboards can be migrated step by step
+pub async fn get_metrics(State(config): State<watch::Receiver<Arc<Config>>>) -> impl IntoResponse {
+    let config = config.borrow().clone();
+    let connections: Vec<(String, bool)> = server::all_sessions()
+        .into_iter()
+        .map(|(server, sessions)| {
+            let connected = matches!(sessions.state(), ConnectionState::Connected { .. });
+            (server_label(&config, &server), connected)
+        })
+        .collect();
+    let queues = queue_statistics(&config);
+    let topics = topic_statistics(&config);
+    let queue_drift_corrections = queue::QUEUE_DRIFT_CORRECTIONS.load(Ordering::Relaxed);
+    let topic_drift_corrections = topic::TOPIC_DRIFT_CORRECTIONS.load(Ordering::Relaxed);
+
+    let mut metrics = Exposition {
+        body: String::new(),
+    };
+    metrics.family(
+        "tibco_ems_server_connected",
+        "gauge",
+        "1 if the operator is connected to the EMS server",
+    );
+    for (server, connected) in &connections {
+        metrics.sample(
+            "tibco_ems_server_connected",
+            &[("server", server)],
+            *connected as u8,
+        );
+    }
+    destination_metrics(&mut metrics, "queue", QUEUE_GAUGES, &queues);
+    metrics.family(
+        "tibco_ems_queue_drift_corrections_total",
+        "counter",
+        "queues corrected by the drift reconciliation",
+    );
+    metrics.sample(
+        "tibco_ems_queue_drift_corrections_total",
+        &[],
+        queue_drift_corrections,
+    );
+    destination_metrics(&mut metrics, "topic", TOPIC_GAUGES, &topics);
+    metrics.family(
+        "tibco_ems_topic_drift_corrections_total",
+        "counter",
+        "topics corrected by the drift reconciliation",
+    );
+    metrics.sample(
+        "tibco_ems_topic_drift_corrections_total",
+        &[],
+        topic_drift_corrections,
+    );
+
+    if config.legacy_metrics {
+        legacy_destination_metrics(&mut metrics, "queue", QUEUE_GAUGES, &queues);
+        legacy_destination_metrics(&mut metrics, "topic", TOPIC_GAUGES, &topics);
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "Content-Type",
+        "text/plain; version=0.0.4; charset=utf-8".parse().unwrap(),
+    );
+    (StatusCode::OK, headers, metrics.body)
+}
+
+/// writes a gauge per destination, kind is either queue or topic
+fn destination_metrics(
+    metrics: &mut Exposition,
+    kind: &str,
+    gauges: &[Gauge],
+    destinations: &[Destination],
+) {
+    for gauge in gauges {
+        metrics.family(gauge.name, "gauge", gauge.help);
+        for destination in destinations {
+            metrics.sample(
+                gauge.name,
+                &[
+                    ("server", &destination.server),
+                    ("namespace", &destination.namespace),
+                    (kind, &destination.name),
+                ],
+                (gauge.value)(destination),
+            );
+        }
+    }
+}
+
+/// writes the gauges with the names and labels of previous releases, without namespace
+fn legacy_destination_metrics(
+    metrics: &mut Exposition,
+    kind: &str,
+    gauges: &[Gauge],
+    destinations: &[Destination],
+) {
+    for gauge in gauges {
+        let help = format!("deprecated, use {}", gauge.name);
+        metrics.family(gauge.legacy_name, "gauge", &help);
+        for destination in destinations {
+            metrics.sample(
+                gauge.legacy_name,
+                &[(kind, &destination.name), ("instance", &destination.server)],
+                (gauge.value)(destination),
+            );
+        }
+    }
+}
+
+/// statistics of the queues of all servers, the namespace is empty for queues without object
+fn queue_statistics(config: &Config) -> Vec<Destination> {
+    let all_queues = queue::QUEUES.lock().unwrap();
+    let mut destinations = Vec::new();
+    for (server, queues) in all_queues.iter() {
+        let namespaces: HashMap<String, String> = queue::get_queue_namespaces(server);
+        for qinfo in queues.values() {
+            destinations.push(Destination {
+                server: server_label(config, server),
+                namespace: namespaces.get(&qinfo.name).cloned().unwrap_or_default(),
+                name: qinfo.name.clone(),
+                pending_messages: qinfo.pending_messages.unwrap_or_default(),
+                consumers: qinfo.consumer_count.unwrap_or_default() as i64,
+                durables: 0,
+            });
+        }
+    }
+    destinations
+}
+
+/// statistics of the topics of all servers, the namespace is empty for topics without object
+fn topic_statistics(config: &Config) -> Vec<Destination> {
+    let all_topics = topic::TOPICS.lock().unwrap();
+    let mut destinations = Vec::new();
+    for (server, topics) in all_topics.iter() {
+        let namespaces: HashMap<String, String> = topic::get_topic_namespaces(server);
+        for tinfo in topics.values() {
+            destinations.push(Destination {
+                server: server_label(config, server),
+                namespace: namespaces.get(&tinfo.name).cloned().unwrap_or_default(),
+                name: tinfo.name.clone(),
+                pending_messages: tinfo.pending_messages.unwrap_or_default(),
+                consumers: tinfo.subscriber_count.unwrap_or_default() as i64,
+                durables: tinfo.durable_count.unwrap_or_default() as i64,
+            });
+        }
+    }
+    destinations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_quotes_backslashes_and_line_feeds() {
+        assert_eq!(escape(r#"a\b"c"#), r#"a\\b\"c"#);
+        assert_eq!(escape("a\nb"), r"a\nb");
+        assert_eq!(escape("q.test"), "q.test");
+    }
+
+    #[test]
+    fn sample_separates_labels_with_commas() {
+        let mut metrics = Exposition {
+            body: String::new(),
+        };
+        metrics.sample("tibco_ems_queue_consumers", &[], 1);
+        metrics.sample(
+            "Q:pendingMessages",
+            &[("queue", "q.\"test\""), ("instance", "EMS-ESB")],
+            42,
+        );
+        assert_eq!(
+            metrics.body,
+            "tibco_ems_queue_consumers 1\n\
+             Q:pendingMessages{queue=\"q.\\\"test\\\"\",instance=\"EMS-ESB\"} 42\n"
+        );
+    }
+
+    #[test]
+    fn legacy_metrics_carry_only_the_destination_and_instance() {
+        let mut metrics = Exposition {
+            body: String::new(),
+        };
+        let queues = [Destination {
+            server: "EMS-ESB".to_string(),
+            namespace: "team-a".to_string(),
+            name: "q.test".to_string(),
+            pending_messages: 3,
+            consumers: 1,
+            durables: 0,
+        }];
+        legacy_destination_metrics(&mut metrics, "queue", &QUEUE_GAUGES[..1], &queues);
+        assert_eq!(
+            metrics.body,
+            "# HELP Q:pendingMessages deprecated, use tibco_ems_queue_pending_messages\n\
+             # TYPE Q:pendingMessages gauge\n\
+             Q:pendingMessages{queue=\"q.test\",instance=\"EMS-ESB\"} 3\n"
+        );
+    }
+}
@@ -0,0 +1,372 @@
+use super::admin::{self, PermissionInfo, Principal};
+use super::config::Config;
+use super::controller::{self, Condition, Context, Error, HasConditions, SyncResult};
+use super::server;
+use futures::StreamExt;
+use kube::runtime::controller::Action;
+use kube::runtime::finalizer::{finalizer, Event};
+use kube::CustomResource;
+use kube::{
+    api::{Api, ResourceExt},
+    Resource,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tibco_ems::{Destination, Session};
+use tokio::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+#[derive(CustomResource, Serialize, Deserialize, Default, Clone, Debug, PartialEq, JsonSchema)]
+#[kube(
+    group = "tibcoems.apimeister.com",
+    version = "v1",
+    kind = "Permission",
+    status = "PermissionStatus",
+    namespaced
+)]
+#[allow(non_snake_case)]
+pub struct PermissionSpec {
+    /// either queue or topic
+    pub destinationType: String,
+    /// name of the destination on the EMS, wildcards are allowed
+    pub destinationName: String,
+    /// user which is granted the permissions, either user or group must be set
+    pub user: Option<String>,
+    /// group which is granted the permissions, either user or group must be set
+    pub group: Option<String>,
+    /// send, receive and browse for queues, publish, subscribe and durable for topics
+    pub permissions: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[allow(non_snake_case)]
+#[serde(default)]
+pub struct PermissionStatus {
+    pub conditions: Vec<Condition>,
+    /// generation of the spec which was last reconciled
+    pub observedGeneration: Option<i64>,
+    /// last time the permission was successfully reconciled with the EMS
+    pub lastSyncTime: Option<String>,
+    /// result of the last failed admin operation
+    pub message: Option<String>,
+    /// spec last granted on the EMS, revoked when the spec changes or the object is deleted
+    pub applied: Option<PermissionSpec>,
+}
+
+impl HasConditions for Permission {
+    fn conditions(&self) -> &[Condition] {
+        match &self.status {
+            Some(status) => &status.conditions,
+            None => &[],
+        }
+    }
+}
+
+/// permission objects within kubernetes, filled by the controller
+static PERMISSION_STORE: controller::Stores<Permission> = controller::Stores::new();
+
+pub async fn watch_permissions(config: Arc<Config>, token: CancellationToken) -> Result<(), ()> {
+    let client = controller::client().await?;
+    let ctx = controller::Context::new(client, config, token);
+    let controllers = controller::new_controllers(&ctx, "permissions");
+    PERMISSION_STORE.register(&controllers);
+    controller::run_controllers(controllers, reconcile, ctx)
+        .for_each(|result| async move {
+            match result {
+                Ok((permission, _action)) => {
+                    trace!("reconciled permission {}", permission.name)
+                }
+                Err(err) => debug!("permission reconciliation failed: {:?}", err),
+            }
+        })
+        .await;
+    Ok(())
+}
+
+/// brings the permission on the EMS in line with the permission object
+async fn reconcile(permission: Arc<Permission>, ctx: Arc<Context>) -> Result<Action, Error> {
+    let key = controller::object_key(permission.as_ref());
+    let api: Api<Permission> =
+        Api::namespaced(ctx.client.clone(), &permission.namespace().unwrap());
+    let action = finalizer(&api, controller::FINALIZER, permission, |event| async {
+        match event {
+            Event::Apply(permission) => apply_permission(&api, &permission, &ctx).await,
+            Event::Cleanup(permission) => cleanup_permission(&api, &permission, &ctx).await,
+        }
+    })
+    .await?;
+    ctx.reset_backoff(&key);
+    Ok(action)
+}
+
+/// grants the permission on the EMS
+///
+/// grants on the EMS are additive, so permissions of the previous spec, kept in the status,
+/// are revoked unless the new spec or another permission object grants them.
+/// Unchanged permissions are granted again on every reconciliation to correct drift.
+async fn apply_permission(
+    api: &Api<Permission>,
+    permission: &Permission,
+    ctx: &Context,
+) -> Result<Action, Error> {
+    let key = controller::object_key(permission);
+    let applied = permission
+        .status
+        .as_ref()
+        .and_then(|status| status.applied.as_ref());
+    let permission_info = match create_permission_object(&permission.spec) {
+        Ok(permission_info) => permission_info,
+        Err(message) => {
+            warn!("permission {} has an invalid spec: {}", key, message);
+            let result = SyncResult::Failed {
+                exists: applied.is_some(),
+                message: format!("invalid spec: {message}"),
+            };
+            controller::patch_sync_status(api, permission, result).await;
+            return Ok(Action::await_change());
+        }
+    };
+    PERMISSION_STORE.wait_until_ready().await;
+    let timeout = ctx.config.admin_command_timeout;
+    let result = match server::default_sessions().admin() {
+        Ok(session) => match applied {
+            Some(old_spec) if old_spec != &permission.spec => {
+                info!("replacing permission {}", &key);
+                let old_info = create_permission_object(old_spec);
+                old_info
+                    .map_err(std::io::Error::other)
+                    .and_then(|old_info| {
+                        revoke_unused(&session, &key, &old_info, Some(&permission_info), timeout)
+                    })
+                    .map_err(|err| (true, err))
+                    .and_then(|_| {
+                        admin::grant_permission(&session, &permission_info, timeout)
+                            .map(|_| Some("replaced permission".to_owned()))
+                            .map_err(|err| (false, err))
+                    })
+            }
+            Some(_) => {
+                debug!("granting permission {} again", &key);
+                admin::grant_permission(&session, &permission_info, timeout)
+                    .map(|_| None)
+                    .map_err(|err| (true, err))
+            }
+            None => {
+                info!("granting permission {}", &key);
+                admin::grant_permission(&session, &permission_info, timeout)
+                    .map(|_| Some("granted permission".to_owned()))
+                    .map_err(|err| (false, err))
+            }
+        },
+        Err(err) => Err((applied.is_some(), err)),
+    };
+    match result {
+        Ok(note) => {
+            if applied != Some(&permission.spec) {
+                controller::patch_applied(api, permission, Some(&permission.spec)).await;
+            }
+            controller::patch_sync_status(api, permission, SyncResult::Synced(note)).await;
+            Ok(Action::requeue(ctx.requeue_interval()))
+        }
+        Err((exists, err)) => {
+            let message = format!("failed to grant permission: {err}");
+            let result = SyncResult::Failed { exists, message };
+            controller::patch_sync_status(api, permission, result).await;
+            Err(Error::Ems(err))
+        }
+    }
+}
+
+/// revokes the permission on the EMS before kubernetes deletes the object
+///
+/// permissions which another permission object grants to the same principal on the same
+/// destination are kept. A failed revocation keeps the finalizer, so the object stays
+/// until the permission is gone
+async fn cleanup_permission(
+    api: &Api<Permission>,
+    permission: &Permission,
+    ctx: &Context,
+) -> Result<Action, Error> {
+    let key = controller::object_key(permission);
+    if ctx.config.do_not_delete_objects {
+        warn!(
+            "delete event for {} (not executed because of DO_NOT_DELETE_OBJECTS setting)",
+            key
+        );
+        return Ok(Action::await_change());
+    }
+    let spec = match permission
+        .status
+        .as_ref()
+        .and_then(|status| status.applied.as_ref())
+    {
+        Some(applied) => applied,
+        None => &permission.spec,
+    };
+    //an invalid spec was never granted
+    let Ok(permission_info) = create_permission_object(spec) else {
+        return Ok(Action::await_change());
+    };
+    PERMISSION_STORE.wait_until_ready().await;
+    info!("revoking permission {}", &key);
+    let result = server::default_sessions().admin().and_then(|session| {
+        revoke_unused(
+            &session,
+            &key,
+            &permission_info,
+            None,
+            ctx.config.admin_command_timeout,
+        )
+    });
+    match result {
+        Ok(_) => Ok(Action::await_change()),
+        Err(err) => {
+            let message = format!("failed to revoke permission: {err}");
+            let result = SyncResult::Failed {
+                exists: true,
+                message,
+            };
+            controller::patch_sync_status(api, permission, result).await;
+            Err(Error::Ems(err))
+        }
+    }
+}
+
+/// revokes the permissions no longer granted by the object, keeping those of the
+/// replacing spec and of other permission objects
+fn revoke_unused(
+    session: &Session,
+    key: &str,
+    old: &PermissionInfo,
+    new: Option<&PermissionInfo>,
+    timeout: Duration,
+) -> Result<(), std::io::Error> {
+    let mut kept = granted_by_others(key, old);
+    if let Some(new) = new
+        && new.destination == old.destination
+        && new.principal == old.principal
+    {
+        kept |= new.permissions;
+    }
+    let revoked = PermissionInfo {
+        permissions: old.permissions & !kept,
+        ..old.clone()
+    };
+    if revoked.permissions == 0 {
+        debug!("permissions of {} are still granted by other objects", key);
+        return Ok(());
+    }
+    admin::revoke_permission(session, &revoked, timeout)
+}
+
+/// permissions which other permission objects grant to the principal on the destination
+///
+/// objects which are being deleted do not count, they revoke their permissions themselves
+fn granted_by_others(key: &str, permission: &PermissionInfo) -> i64 {
+    PERMISSION_STORE
+        .state()
+        .iter()
+        .filter(|other| other.meta().deletion_timestamp.is_none())
+        .filter(|other| controller::object_key(other.as_ref()) != key)
+        .filter_map(|other| create_permission_object(&other.spec).ok())
+        .filter(|other| {
+            other.destination == permission.destination && other.principal == permission.principal
+        })
+        .fold(0, |granted, other| granted | other.permissions)
+}
+
+/// validates the spec and creates the permission sent to the EMS
+fn create_permission_object(spec: &PermissionSpec) -> Result<PermissionInfo, String> {
+    let mut destination_type = spec.destinationType.clone();
+    destination_type.make_ascii_lowercase();
+    let destination = match destination_type.as_str() {
+        "queue" => Destination::Queue(spec.destinationName.clone()),
+        "topic" => Destination::Topic(spec.destinationName.clone()),
+        _ => {
+            return Err(format!(
+                "destinationType must be queue or topic, got '{}'",
+                spec.destinationType
+            ))
+        }
+    };
+    let principal = match (&spec.user, &spec.group) {
+        (Some(user), None) => Principal::User(user.clone()),
+        (None, Some(group)) => Principal::Group(group.clone()),
+        _ => return Err("either user or group must be set".to_owned()),
+    };
+    if spec.permissions.is_empty() {
+        return Err("at least one permission must be set".to_owned());
+    }
+    let mut permissions = 0;
+    for name in &spec.permissions {
+        let flag = match (&destination, name.to_ascii_lowercase().as_str()) {
+            (Destination::Queue(_), "send") => admin::PERMISSION_SEND,
+            (Destination::Queue(_), "receive") => admin::PERMISSION_RECEIVE,
+            (Destination::Queue(_), "browse") => admin::PERMISSION_BROWSE,
+            (Destination::Topic(_), "publish") => admin::PERMISSION_PUBLISH,
+            (Destination::Topic(_), "subscribe") => admin::PERMISSION_SUBSCRIBE,
+            (Destination::Topic(_), "durable") => admin::PERMISSION_DURABLE,
+            _ => {
+                return Err(format!(
+                    "permission '{name}' is not valid for a {destination_type}"
+                ))
+            }
+        };
+        permissions |= flag;
+    }
+    let permission_info = PermissionInfo {
+        destination,
+        principal,
+        permissions,
+    };
+    // show what we have created in debug mode
+    debug!("{:?}", permission_info);
+    Ok(permission_info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(destination_type: &str, permissions: &[&str]) -> PermissionSpec {
+        PermissionSpec {
+            destinationType: destination_type.to_string(),
+            destinationName: "q.test".to_string(),
+            user: Some("app".to_string()),
+            group: None,
+            permissions: permissions.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn permissions_are_combined() {
+        let permission = create_permission_object(&spec("Queue", &["send", "Browse"])).unwrap();
+        assert_eq!(
+            permission,
+            PermissionInfo {
+                destination: Destination::Queue("q.test".to_string()),
+                principal: Principal::User("app".to_string()),
+                permissions: admin::PERMISSION_SEND | admin::PERMISSION_BROWSE,
+            }
+        );
+    }
+
+    #[test]
+    fn permission_must_match_the_destination_type() {
+        let err = create_permission_object(&spec("queue", &["publish"])).unwrap_err();
+        assert_eq!(err, "permission 'publish' is not valid for a queue");
+    }
+
+    #[test]
+    fn invalid_specs_are_rejected() {
+        assert!(create_permission_object(&spec("bridge", &["send"])).is_err());
+        assert!(create_permission_object(&spec("queue", &[])).is_err());
+        let mut both = spec("topic", &["publish"]);
+        both.group = Some("apps".to_string());
+        assert_eq!(
+            create_permission_object(&both).unwrap_err(),
+            "either user or group must be set"
+        );
+    }
+}
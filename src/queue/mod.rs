@@ -1,23 +1,37 @@
+use super::admin::{ExtendedProperties, QueueProperties};
+use super::config::Config;
+use super::controller::{self, Condition, Context, Error, HasConditions, SyncResult};
+use super::destination::{
+    self, AppliedDestination, Changes, ExtendedSpec, UnreportedProperties, DEFAULT_STORE,
+    NO_FLOW_CONTROL,
+};
+use super::health;
+use super::leader;
 use super::scaler::State;
 use super::scaler::StateTrigger;
-use env_var::env_var;
-use futures::{StreamExt, TryStreamExt};
-use kube::api::WatchEvent;
+use super::server::{self, ServerSessions};
+use super::supervisor;
+use futures::StreamExt;
+use kube::runtime::controller::Action;
+use kube::runtime::finalizer::{finalizer, Event};
 use kube::CustomResource;
 use kube::{
-    api::{Api, PostParams, ResourceExt, WatchParams},
+    api::{Api, Patch, PatchParams, ResourceExt},
     Client,
 };
 use once_cell::sync::Lazy;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::sync::Mutex;
-use tibco_ems::admin::QueueInfo;
-use tibco_ems::Session;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tibco_ems::admin::{OverflowPolicy, QueueInfo};
+use tibco_ems::Destination;
 use tokio::time::{self, Duration};
+use tokio_util::sync::CancellationToken;
 
-#[derive(CustomResource, Serialize, Deserialize, Default, Clone, Debug, JsonSchema)]
+#[derive(CustomResource, Serialize, Deserialize, Default, Clone, Debug, PartialEq, JsonSchema)]
 #[kube(
     group = "tibcoems.apimeister.com",
     version = "v1",
@@ -36,204 +50,388 @@ pub struct QueueSpec {
     pub overflowPolicy: Option<u8>,
     pub prefetch: Option<u32>,
     pub redeliveryDelay: Option<u32>,
+    pub exclusive: Option<bool>,
+    /// additional JNDI names bound to the queue
+    pub jndiNames: Option<Vec<String>>,
+    /// name of the EmsServer object within the namespace, defaults to the server of the operator
+    pub server: Option<String>,
+    #[serde(flatten)]
+    pub extended: ExtendedSpec,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
 #[allow(non_snake_case)]
+#[serde(default)]
 pub struct QueueStatus {
     pub pendingMessages: i64,
     pub consumerCount: i32,
+    /// queue properties as reported by the EMS
+    pub effective: Option<QueueSpec>,
+    pub conditions: Vec<Condition>,
+    /// generation of the spec which was last reconciled
+    pub observedGeneration: Option<i64>,
+    /// last time the queue was successfully reconciled with the EMS
+    pub lastSyncTime: Option<String>,
+    /// result of the last failed admin operation
+    pub message: Option<String>,
+    /// JNDI names last bound to the queue, unbound when dropped from the spec
+    pub applied: Option<AppliedDestination>,
 }
 
-/// registered queues within kubernetes
-pub static KNOWN_QUEUES: Lazy<Mutex<HashMap<String, Queue>>> =
-    Lazy::new(|| Mutex::new(HashMap::new()));
-/// all queues present on the EMS
-pub static QUEUES: Lazy<Mutex<HashMap<String, QueueInfo>>> =
+impl HasConditions for Queue {
+    fn conditions(&self) -> &[Condition] {
+        match &self.status {
+            Some(status) => &status.conditions,
+            None => &[],
+        }
+    }
+}
+
+/// queue objects within kubernetes, filled by the controller
+pub static QUEUE_STORE: controller::Stores<Queue> = controller::Stores::new();
+/// all queues present on the EMS servers, keyed by server and queue name
+///
+/// a server is missing until its queues were retrieved
+pub static QUEUES: Lazy<Mutex<HashMap<String, HashMap<String, QueueInfo>>>> =
     Lazy::new(|| Mutex::new(HashMap::new()));
 
-///used for retrieving queue statistics
-static QUEUE_ADMIN_CONNECTION: Lazy<Mutex<Session>> =
-    Lazy::new(|| Mutex::new(super::init_admin_connection()));
-///used for sending admin operations
-static ADMIN_CONNECTION: Lazy<Mutex<Session>> =
-    Lazy::new(|| Mutex::new(super::init_admin_connection()));
-
-pub async fn watch_queues() -> Result<(), ()> {
-    let crds: Api<Queue> = get_queue_client().await;
-    let updater: Api<Queue> = crds.clone();
-    let mut lp = WatchParams::default();
-
-    let responsible_for = super::RESPONSIBLE_FOR.lock().unwrap().clone();
-    if !responsible_for.is_empty() {
-        info!("subscribing to events of type queues.tibcoems.apimeister.com/v1 for instance {responsible_for}");
-        lp = lp.labels(format!("tibcoems.apimeister.com/owner={responsible_for}").as_str());
-    } else {
-        lp = lp.labels("!tibcoems.apimeister.com/owner");
-        info!("subscribing to events of type queues.tibcoems.apimeister.com/v1");
-    }
+/// number of queues corrected by the drift reconciliation
+pub static QUEUE_DRIFT_CORRECTIONS: AtomicU64 = AtomicU64::new(0);
 
-    let mut last_version = String::from("0");
+pub async fn watch_queues(config: Arc<Config>, token: CancellationToken) -> Result<(), ()> {
+    let client = controller::client().await?;
+    let ctx = controller::Context::new(client, config, token);
+    let controllers = controller::new_controllers(&ctx, "queues");
+    QUEUE_STORE.register(&controllers);
+    controller::run_controllers(controllers, reconcile, ctx)
+        .for_each(|result| async move {
+            match result {
+                Ok((queue, _action)) => trace!("reconciled queue {}", queue.name),
+                Err(err) => debug!("queue reconciliation failed: {:?}", err),
+            }
+        })
+        .await;
+    Ok(())
+}
 
-    loop {
-        debug!("new loop iteration with offset {}", last_version);
-        let watch_result = crds.watch(&lp, &last_version).await;
-        let str_result = match watch_result {
-            Ok(x) => x,
-            Err(_) => continue,
-        };
-        let mut stream = str_result.boxed();
-        loop {
-            let stream_result = stream.try_next().await;
-            let status_obj = match stream_result {
-                Ok(x) => x,
-                Err(e) => {
-                    debug!("error on request loop {:?}", e);
-                    break;
-                }
+/// brings the queue on the EMS in line with the queue object
+async fn reconcile(queue: Arc<Queue>, ctx: Arc<Context>) -> Result<Action, Error> {
+    let key = controller::object_key(queue.as_ref());
+    let api: Api<Queue> = Api::namespaced(ctx.client.clone(), &queue.namespace().unwrap());
+    let action = finalizer(&api, controller::FINALIZER, queue, |event| async {
+        match event {
+            Event::Apply(queue) => apply_queue(&api, &queue, &ctx).await,
+            Event::Cleanup(queue) => cleanup_queue(&api, &queue, &ctx).await,
+        }
+    })
+    .await?;
+    ctx.reset_backoff(&key);
+    Ok(action)
+}
+
+/// creates the queue on the EMS or applies changed properties
+async fn apply_queue(api: &Api<Queue>, queue: &Queue, ctx: &Context) -> Result<Action, Error> {
+    let key = controller::object_key(queue);
+    let server = get_server(queue);
+    let sessions = match server::get_sessions(&server) {
+        Ok(sessions) => sessions,
+        Err(message) => {
+            warn!("queue {} cannot be applied: {}", key, message);
+            let result = SyncResult::Failed {
+                exists: false,
+                message,
             };
-            let status = match status_obj {
-                Some(x) => x,
-                None => {
-                    debug!("request loop returned empty");
-                    break;
-                }
+            controller::patch_sync_status(api, queue, result).await;
+            return Ok(Action::requeue(Duration::from_secs(10)));
+        }
+    };
+    let queue_name = get_queue_name(queue);
+    let current = {
+        let c_map = QUEUES.lock().unwrap();
+        match c_map.get(&server) {
+            Some(queues) => queues.get(&queue_name).cloned(),
+            None => {
+                debug!("waiting for queue information from EMS");
+                return Ok(Action::requeue(Duration::from_secs(5)));
+            }
+        }
+    };
+    let spec = get_queue_properties(queue)
+        .and_then(|properties| Ok((properties, destination::jndi_names(&queue.spec.jndiNames)?)));
+    let (properties, jndi_names) = match spec {
+        Ok(spec) => spec,
+        Err(message) => {
+            warn!("queue {} has an invalid spec: {}", queue_name, message);
+            let result = SyncResult::Failed {
+                exists: current.is_some(),
+                message: format!("invalid spec: {message}"),
             };
-            debug!("new stream item");
-
-            match status {
-                WatchEvent::Added(mut queue) => {
-                    let queue_name = get_queue_name(&queue);
-                    last_version = ResourceExt::resource_version(&queue).unwrap();
-                    {
-                        let mut res = KNOWN_QUEUES.lock().unwrap();
-                        match res.get(&queue_name) {
-                            Some(_queue) => debug!("queue already known {}", &queue_name),
-                            None => {
-                                info!("adding queue {}", &queue_name);
-                                create_queue(&mut queue);
-                                res.insert(queue_name, queue.clone());
-                            }
-                        }
-                    }
-                    if queue.status.is_none() {
-                        let name = ResourceExt::name_any(&queue);
-                        let pp = PostParams::default();
-                        let _result = updater.replace_status(&name, &pp, &queue).await;
-                    }
+            controller::patch_sync_status(api, queue, result).await;
+            return Ok(Action::await_change());
+        }
+    };
+    let previous = queue
+        .status
+        .as_ref()
+        .and_then(|status| status.applied.clone());
+    let mut applied = previous.clone().unwrap_or_else(|| AppliedDestination {
+        destination: queue_name.clone(),
+        ..Default::default()
+    });
+    let note = match current {
+        None => {
+            if queue.status.is_some() && !ctx.is_failing(&key) {
+                warn!("queue {} is missing on EMS, recreating it", queue_name);
+                QUEUE_DRIFT_CORRECTIONS.fetch_add(1, Ordering::Relaxed);
+            } else {
+                info!("adding queue {}", queue_name);
+            }
+            let qinfo = match create_queue(&sessions, &properties, ctx.config.admin_command_timeout)
+            {
+                Ok(qinfo) => qinfo,
+                Err(err) => {
+                    let message = format!("failed to create queue {queue_name}: {err}");
+                    let result = SyncResult::Failed {
+                        exists: false,
+                        message,
+                    };
+                    controller::patch_sync_status(api, queue, result).await;
+                    return Err(Error::Ems(err));
                 }
-                WatchEvent::Deleted(queue) => {
-                    let do_not_delete = env_var!(optional "DO_NOT_DELETE_OBJECTS", default:"FALSE");
-                    let queue_name = get_queue_name(&queue);
-                    if do_not_delete == "TRUE" {
-                        warn!("delete event for {} (not executed because of DO_NOT_DELETE_OBJECTS setting)", queue_name);
-                    } else {
-                        delete_queue(&queue);
-                    }
-                    let mut res = KNOWN_QUEUES.lock().unwrap();
-                    res.remove(&queue_name);
-                    last_version = ResourceExt::resource_version(&queue).unwrap();
+            };
+            //reflect new queue until the next statistics refresh
+            {
+                let mut c_map = QUEUES.lock().unwrap();
+                let queues = c_map.entry(server.clone()).or_default();
+                queues.insert(queue_name.clone(), qinfo);
+            }
+            Some(format!("created queue {queue_name}"))
+        }
+        Some(qinfo) => {
+            let spec_changed = ctx.applied_generation(&key) != queue.metadata.generation;
+            if let Some((delta, changes)) =
+                get_queue_delta(&properties, &qinfo, &applied.unreported)
+            {
+                if !spec_changed && !ctx.is_failing(&key) {
+                    warn!(
+                        "queue {} drifted from its spec ({}), correcting it",
+                        queue_name,
+                        changes.join(", ")
+                    );
+                    QUEUE_DRIFT_CORRECTIONS.fetch_add(1, Ordering::Relaxed);
+                } else {
+                    info!("updating queue {}: {}", queue_name, changes.join(", "));
                 }
-                WatchEvent::Error(e) => {
-                    if e.code == 410 && e.reason == "Expired" {
-                        //fail silently
-                        trace!("resource_version too old, resetting offset to 0");
-                    } else {
-                        error!("Error {:?}", e);
-                        error!("resetting offset to 0");
-                    }
-                    "0".clone_into(&mut last_version);
+                if let Err(err) =
+                    update_queue(&sessions, &server, &delta, ctx.config.admin_command_timeout)
+                {
+                    let message = format!("failed to update {}: {}", changes.join(", "), err);
+                    let result = SyncResult::Failed {
+                        exists: true,
+                        message,
+                    };
+                    controller::patch_sync_status(api, queue, result).await;
+                    return Err(Error::Ems(err));
                 }
-                _ => {}
-            };
+                Some(format!("updated {}", changes.join(", ")))
+            } else {
+                None
+            }
         }
+    };
+    applied.unreported = get_unreported(&properties);
+    let jndi_result = sessions.admin().and_then(|session| {
+        let destination = Destination::Queue(queue_name.clone());
+        destination::sync_jndi_names(
+            &session,
+            &mut applied,
+            &destination,
+            &jndi_names,
+            ctx.config.admin_command_timeout,
+        )
+    });
+    //names bound or unbound before a failure are kept as well
+    if previous.as_ref() != Some(&applied) {
+        controller::patch_applied(api, queue, Some(&applied)).await;
     }
+    let jndi_note = match jndi_result {
+        Ok(jndi_note) => jndi_note,
+        Err(err) => {
+            let message = format!("failed to bind JNDI names of queue {queue_name}: {err}");
+            let result = SyncResult::Failed {
+                exists: true,
+                message,
+            };
+            controller::patch_sync_status(api, queue, result).await;
+            return Err(Error::Ems(err));
+        }
+    };
+    let note = match (note, jndi_note) {
+        (Some(note), Some(jndi_note)) => Some(format!("{note}, {jndi_note}")),
+        (note, jndi_note) => note.or(jndi_note),
+    };
+    controller::patch_sync_status(api, queue, SyncResult::Synced(note)).await;
+    ctx.set_applied_generation(&key, queue.metadata.generation);
+    Ok(Action::requeue(ctx.requeue_interval()))
 }
 
-pub async fn watch_queues_status() -> Result<(), ()> {
-    let status_refresh_in_ms: u64 = env_var!(optional "STATUS_REFRESH_IN_MS", default: "10000")
-        .parse()
-        .unwrap();
-    let read_only = env_var!(optional "READ_ONLY", default:"FALSE");
-    let mut interval = time::interval(Duration::from_millis(status_refresh_in_ms));
-    loop {
-        let result;
-        {
-            let session = QUEUE_ADMIN_CONNECTION.lock().unwrap();
-            result = tibco_ems::admin::list_all_queues(&session);
+/// removes the queue from the EMS before kubernetes deletes the object
+///
+/// a failed deletion keeps the finalizer, so the object stays until the queue is gone
+async fn cleanup_queue(api: &Api<Queue>, queue: &Queue, ctx: &Context) -> Result<Action, Error> {
+    let queue_name = get_queue_name(queue);
+    if ctx.config.do_not_delete_objects {
+        warn!(
+            "delete event for {} (not executed because of DO_NOT_DELETE_OBJECTS setting)",
+            queue_name
+        );
+        return Ok(Action::await_change());
+    }
+    let key = controller::object_key(queue);
+    let server = get_server(queue);
+    let sessions = match server::get_sessions(&server) {
+        Ok(sessions) => sessions,
+        Err(message) => {
+            warn!("queue {} cannot be deleted: {}", key, message);
+            let result = SyncResult::Failed {
+                exists: true,
+                message,
+            };
+            controller::patch_sync_status(api, queue, result).await;
+            return Ok(Action::requeue(Duration::from_secs(10)));
         }
-        let res: Vec<tibco_ems::admin::QueueInfo> = match result {
-            Ok(x) => x,
-            Err(_err) => {
-                panic!("failed to retrieve queue information");
+    };
+    let applied = queue
+        .status
+        .as_ref()
+        .and_then(|status| status.applied.as_ref());
+    let jndi_result = sessions.admin().and_then(|session| {
+        destination::remove_jndi_names(&session, applied, ctx.config.admin_command_timeout)
+    });
+    match jndi_result.and_then(|_| delete_queue(&sessions, queue, ctx.config.admin_command_timeout))
+    {
+        Ok(_) => {
+            let mut c_map = QUEUES.lock().unwrap();
+            if let Some(queues) = c_map.get_mut(&server) {
+                queues.remove(&queue_name);
             }
-        };
+            Ok(Action::await_change())
+        }
+        Err(err) => {
+            let message = format!("failed to delete queue {queue_name}: {err}");
+            let result = SyncResult::Failed {
+                exists: true,
+                message,
+            };
+            controller::patch_sync_status(api, queue, result).await;
+            Err(Error::Ems(err))
+        }
+    }
+}
 
-        for qinfo in res {
-            let pending_messages: i64 = qinfo.pending_messages.unwrap_or(0);
-            let outgoing_total_count: i64 = qinfo.outgoing_total_count.unwrap_or(0);
+pub async fn watch_queues_status(config: Arc<Config>, token: CancellationToken) -> Result<(), ()> {
+    let client = controller::client().await?;
+    let mut interval = time::interval(config.status_refresh);
+    loop {
+        health::heartbeat("queues_status");
+        let all_sessions = server::all_sessions();
+        for (server, sessions) in &all_sessions {
+            let result = sessions.statistics().and_then(|session| {
+                tibco_ems::admin::list_all_queues(&session)
+                    .inspect_err(|err| sessions.mark_broken(&session, err))
+            });
+            let res: Vec<tibco_ems::admin::QueueInfo> = match result {
+                Ok(x) => x,
+                Err(err) => {
+                    error!(
+                        "failed to retrieve queue information from {}: {}",
+                        server::display_name(server),
+                        err
+                    );
+                    continue;
+                }
+            };
+            if server == server::DEFAULT_SERVER {
+                health::listed("queues");
+            }
             //update prometheus
             {
                 let mut c_map = QUEUES.lock().unwrap();
-                c_map.insert(qinfo.name.clone(), qinfo.clone());
-            }
-            //update scaler
-            {
-                let scaling = env_var!(optional "ENABLE_SCALING", default:"FALSE");
-                if scaling == "TRUE" {
-                    scale(&qinfo.name, pending_messages, outgoing_total_count).await;
-                }
+                let queues = res.iter().map(|q| (q.name.clone(), q.clone())).collect();
+                c_map.insert(server.clone(), queues);
             }
+            update_queues_status(&client, server, res, &config).await;
+        }
+        //forget the queues of removed servers
+        {
+            let mut c_map = QUEUES.lock().unwrap();
+            c_map.retain(|server, _| all_sessions.iter().any(|(key, _)| key == server));
+        }
+        if !supervisor::tick(&mut interval, &token).await {
+            return Ok(());
+        }
+    }
+}
 
-            //update k8s state
-            if read_only == "FALSE" {
-                let mut q: Option<Queue> = None;
-                {
-                    let mut res = KNOWN_QUEUES.lock().unwrap();
-                    let queue = match res.get(&qinfo.name) {
-                        Some(x) => x,
-                        None => continue,
-                    };
-                    let consumer_count = qinfo.consumer_count.unwrap_or(0);
-                    let update = match &queue.status {
-                        Some(status) if status.pendingMessages != pending_messages => true,
-                        Some(status) if status.consumerCount != consumer_count => true,
-                        None => true,
-                        _ => false,
-                    };
+/// scales deployments and updates the status of the queue objects of a server
+async fn update_queues_status(client: &Client, server: &str, res: Vec<QueueInfo>, config: &Config) {
+    //queue objects by their EMS name
+    let known_queues: HashMap<String, Arc<Queue>> = QUEUE_STORE
+        .state()
+        .into_iter()
+        .filter(|q| get_server(q) == server)
+        .map(|q| (get_queue_name(&q), q))
+        .collect();
+
+    for qinfo in res {
+        let pending_messages: i64 = qinfo.pending_messages.unwrap_or(0);
+        let outgoing_total_count: i64 = qinfo.outgoing_total_count.unwrap_or(0);
+        //update scaler, which only knows the queues of the default server
+        if server == server::DEFAULT_SERVER && config.enable_scaling && leader::is_leader() {
+            scale(&qinfo.name, pending_messages, outgoing_total_count).await;
+        }
 
-                    if update {
-                        let mut updated_queue = queue.clone();
-                        updated_queue.status = Some(QueueStatus {
-                            pendingMessages: qinfo.pending_messages.unwrap(),
-                            consumerCount: qinfo.consumer_count.unwrap(),
-                        });
-                        q = Some(updated_queue.clone());
-                        res.insert(qinfo.name.to_owned(), updated_queue);
-                    }
+        //update k8s state, which is left to the leader
+        if !config.read_only && leader::is_leader() {
+            let queue = match known_queues.get(&qinfo.name) {
+                Some(x) => x,
+                None => continue,
+            };
+            let consumer_count = qinfo.consumer_count.unwrap_or(0);
+            let effective = get_effective_spec(&qinfo);
+            let update = match &queue.status {
+                Some(status) if status.pendingMessages != pending_messages => true,
+                Some(status) if status.consumerCount != consumer_count => true,
+                Some(status) if status.effective.as_ref() != Some(&effective) => true,
+                None => true,
+                _ => false,
+            };
+            if !update {
+                continue;
+            }
+
+            let obj_name = queue.name_any();
+            debug!("updating queue status for {}", obj_name);
+            let updater: Api<Queue> = Api::namespaced(client.clone(), &queue.namespace().unwrap());
+            let status = serde_json::json!({
+                "status": {
+                    "pendingMessages": pending_messages,
+                    "consumerCount": consumer_count,
+                    "effective": effective,
                 }
-                let mut local_q = match q {
-                    Some(x) => x,
-                    None => continue,
-                };
-
-                let obj_name = get_obj_name_from_queue(&local_q);
-                debug!("updating queue status for {}", obj_name);
-                let updater: Api<Queue> = get_queue_client().await;
-                let latest_queue: Queue = updater.get(&obj_name).await.unwrap();
-                local_q.metadata.resource_version = ResourceExt::resource_version(&latest_queue);
-                let pp = PostParams::default();
-                let result = updater.replace_status(&obj_name, &pp, &local_q).await;
-                match result {
-                    Ok(_ignore) => {}
-                    Err(err) => {
-                        error!("error while updating queue object");
-                        error!("{:?}", err);
-                    }
+            });
+            let pp = PatchParams::default();
+            let result = updater
+                .patch_status(&obj_name, &pp, &Patch::Merge(&status))
+                .await;
+            match result {
+                Ok(_ignore) => {}
+                Err(err) => {
+                    error!("error while updating queue object");
+                    error!("{:?}", err);
                 }
             }
         }
-        interval.tick().await;
     }
 }
 
@@ -284,10 +482,19 @@ async fn scale(queue_name: &str, pending_messages: i64, outgoing_total_count: i6
     }
 }
 
-async fn get_queue_client() -> Api<Queue> {
-    let client = Client::try_default().await.expect("getting default client");
-    let namespace = env_var!(required "KUBERNETES_NAMESPACE");
-    Api::namespaced(client, &namespace)
+/// namespaces of the queue objects of a server by their EMS name
+pub fn get_queue_namespaces(server: &str) -> HashMap<String, String> {
+    QUEUE_STORE
+        .state()
+        .into_iter()
+        .filter(|q| get_server(q) == server)
+        .map(|q| (get_queue_name(&q), q.namespace().unwrap_or_default()))
+        .collect()
+}
+
+/// key of the EMS server the queue belongs to
+fn get_server(queue: &Queue) -> String {
+    server::server_key(&queue.namespace().unwrap_or_default(), &queue.spec.server)
 }
 
 fn get_queue_name(queue: &Queue) -> String {
@@ -305,76 +512,348 @@ fn get_queue_name(queue: &Queue) -> String {
     qname
 }
 
-fn get_obj_name_from_queue(queue: &Queue) -> String {
-    queue.metadata.name.clone().unwrap()
+/// validates the queue spec and collects all properties which are set
+fn get_queue_properties(queue: &Queue) -> Result<QueueProperties, String> {
+    destination::validate_overflow_policy(queue.spec.overflowPolicy)?;
+    Ok(QueueProperties {
+        name: get_queue_name(queue),
+        max_bytes: queue.spec.maxbytes,
+        max_messages: queue.spec.maxmsgs,
+        overflow_policy: queue.spec.overflowPolicy.map(|val| val as i32),
+        global: queue.spec.global,
+        prefetch: queue.spec.prefetch.map(|val| val as i32),
+        expiry_override: queue.spec.expiration.map(|val| val as i64),
+        redelivery_delay: queue.spec.redeliveryDelay.map(|val| val as i64),
+        max_redelivery: queue.spec.maxRedelivery.map(|val| val as i32),
+        exclusive: queue.spec.exclusive,
+        extended: queue.spec.extended.properties()?,
+    })
 }
 
 /// creates a queue within the ems
-fn create_queue(queue: &mut Queue) {
-    let qname = get_queue_name(queue);
-
+///
+/// returns the queue information as sent to the ems
+fn create_queue(
+    sessions: &ServerSessions,
+    properties: &QueueProperties,
+    timeout: Duration,
+) -> Result<QueueInfo, std::io::Error> {
+    {
+        let session = sessions.admin()?;
+        super::admin::create_queue(&session, properties, timeout)?;
+    }
+    debug!("queue created successful");
     let mut queue_info = QueueInfo {
-        name: qname,
-        max_bytes: queue.spec.maxbytes,
-        max_messages: queue.spec.maxmsgs,
-        global: queue.spec.global,
+        name: properties.name.clone(),
+        pending_messages: Some(0),
+        consumer_count: Some(0),
+        incoming_total_count: Some(0),
+        outgoing_total_count: Some(0),
         ..Default::default()
     };
-    if let Some(val) = queue.spec.expiration {
-        queue_info.expiry_override = Some(val as i64);
+    apply_queue_delta(&mut queue_info, properties);
+    Ok(queue_info)
+}
+
+/// applies changed queue properties to the ems
+fn update_queue(
+    sessions: &ServerSessions,
+    server: &str,
+    delta: &QueueProperties,
+    timeout: Duration,
+) -> Result<(), std::io::Error> {
+    let session = sessions.admin()?;
+    super::admin::update_queue(&session, delta, timeout)?;
+    debug!("queue updated successful");
+    //reflect new values until the next statistics refresh
+    let mut c_map = QUEUES.lock().unwrap();
+    if let Some(qinfo) = c_map
+        .get_mut(server)
+        .and_then(|queues| queues.get_mut(&delta.name))
+    {
+        apply_queue_delta(qinfo, delta);
     }
-    if let Some(val) = queue.spec.prefetch {
-        queue_info.prefetch = Some(val as i32);
+    Ok(())
+}
+
+/// computes the properties of the spec which differ from the queue on the ems
+///
+/// returns None if the ems queue already matches the spec, otherwise the delta
+/// together with the names of the changed properties
+fn get_queue_delta(
+    spec: &QueueProperties,
+    qinfo: &QueueInfo,
+    applied: &UnreportedProperties,
+) -> Option<(QueueProperties, Vec<&'static str>)> {
+    let mut changes = Changes::default();
+    let extended = &spec.extended;
+    let overflow_policy = qinfo.overflow_policy.clone().map(|val| val as i32);
+    let delta = QueueProperties {
+        name: qinfo.name.clone(),
+        max_bytes: changes.reported("maxbytes", &spec.max_bytes, &qinfo.max_bytes, 0),
+        max_messages: changes.reported("maxmsgs", &spec.max_messages, &qinfo.max_messages, 0),
+        overflow_policy: changes.reported(
+            "overflowPolicy",
+            &spec.overflow_policy,
+            &overflow_policy,
+            0,
+        ),
+        global: changes.reported("global", &spec.global, &qinfo.global, false),
+        prefetch: changes.reported("prefetch", &spec.prefetch, &qinfo.prefetch, 0),
+        expiry_override: changes.reported(
+            "expiration",
+            &spec.expiry_override,
+            &qinfo.expiry_override,
+            0,
+        ),
+        redelivery_delay: changes.reported(
+            "redeliveryDelay",
+            &spec.redelivery_delay,
+            &qinfo.redelivery_delay,
+            0,
+        ),
+        //the ems does not report these properties, so they are compared with the applied ones
+        max_redelivery: changes.unreported(
+            "maxRedelivery",
+            &spec.max_redelivery,
+            &applied.maxRedelivery,
+            0,
+        ),
+        exclusive: changes.unreported("exclusive", &spec.exclusive, &applied.exclusive, false),
+        extended: ExtendedProperties {
+            failsafe: changes.reported("failsafe", &extended.failsafe, &qinfo.failsafe, false),
+            secure: changes.reported("secure", &extended.secure, &qinfo.secure, false),
+            sender_name: changes.reported(
+                "senderName",
+                &extended.sender_name,
+                &qinfo.sender_name,
+                false,
+            ),
+            sender_name_enforced: changes.reported(
+                "senderNameEnforced",
+                &extended.sender_name_enforced,
+                &qinfo.sender_name_enforced,
+                false,
+            ),
+            flow_control: changes.unreported(
+                "flowControl",
+                &extended.flow_control,
+                &applied.flowControl,
+                NO_FLOW_CONTROL,
+            ),
+            store: changes.unreported(
+                "store",
+                &extended.store,
+                &applied.store,
+                DEFAULT_STORE.to_owned(),
+            ),
+            trace: changes.unreported("trace", &extended.trace, &applied.trace, String::new()),
+            import: changes.unreported("import", &extended.import, &applied.import, String::new()),
+            export: changes.unreported("export", &extended.export, &applied.export, String::new()),
+        },
+    };
+    if changes.0.is_empty() {
+        None
+    } else {
+        Some((delta, changes.0))
     }
-    let session = ADMIN_CONNECTION.lock().unwrap();
-    let result = tibco_ems::admin::create_queue(&session, &queue_info);
-    match result {
-        Ok(_) => {
-            debug!("queue created successful");
-        }
-        Err(err) => {
-            error!("failed to create queue: {:?}", err);
-            panic!("failed to create queue");
-        }
+}
+
+/// properties of the queue which the EMS does not report, as they are sent to it
+fn get_unreported(spec: &QueueProperties) -> UnreportedProperties {
+    UnreportedProperties {
+        maxRedelivery: spec.max_redelivery,
+        exclusive: spec.exclusive,
+        flowControl: spec.extended.flow_control,
+        store: spec.extended.store.clone(),
+        trace: spec.extended.trace.clone(),
+        import: spec.extended.import.clone(),
+        export: spec.extended.export.clone(),
+        ..Default::default()
     }
+}
 
-    //propagate defaults
-    if queue.spec.maxmsgs.is_none() {
-        queue.spec.maxmsgs = Some(0)
+/// copies all properties set on the delta onto the queue info
+fn apply_queue_delta(qinfo: &mut QueueInfo, delta: &QueueProperties) {
+    if delta.max_bytes.is_some() {
+        qinfo.max_bytes = delta.max_bytes;
     }
-    if queue.spec.expiration.is_none() {
-        queue.spec.expiration = Some(0)
+    if delta.max_messages.is_some() {
+        qinfo.max_messages = delta.max_messages;
+    }
+    if let Some(val) = delta.overflow_policy {
+        qinfo.overflow_policy = Some(match val {
+            1 => OverflowPolicy::DiscardOld,
+            2 => OverflowPolicy::RejectIncoming,
+            _ => OverflowPolicy::Default,
+        });
+    }
+    if delta.global.is_some() {
+        qinfo.global = delta.global;
+    }
+    if delta.prefetch.is_some() {
+        qinfo.prefetch = delta.prefetch;
+    }
+    if delta.expiry_override.is_some() {
+        qinfo.expiry_override = delta.expiry_override;
+    }
+    if delta.redelivery_delay.is_some() {
+        qinfo.redelivery_delay = delta.redelivery_delay;
+    }
+    let extended = &delta.extended;
+    if extended.failsafe.is_some() {
+        qinfo.failsafe = extended.failsafe;
+    }
+    if extended.secure.is_some() {
+        qinfo.secure = extended.secure;
+    }
+    if extended.sender_name.is_some() {
+        qinfo.sender_name = extended.sender_name;
+    }
+    if extended.sender_name_enforced.is_some() {
+        qinfo.sender_name_enforced = extended.sender_name_enforced;
+    }
+}
+
+/// the queue properties as reported by the ems
+///
+/// the max redelivery count, exclusive flag, flow control, store, trace and
+/// transports are not part of the ems queue information
+fn get_effective_spec(qinfo: &QueueInfo) -> QueueSpec {
+    QueueSpec {
+        name: Some(qinfo.name.clone()),
+        expiration: qinfo.expiry_override.map(|val| val as u32),
+        global: qinfo.global,
+        maxbytes: qinfo.max_bytes,
+        maxmsgs: qinfo.max_messages,
+        maxRedelivery: None,
+        overflowPolicy: qinfo.overflow_policy.clone().map(|val| val as u8),
+        prefetch: qinfo.prefetch.map(|val| val as u32),
+        redeliveryDelay: qinfo.redelivery_delay.map(|val| val as u32),
+        exclusive: None,
+        jndiNames: None,
+        server: None,
+        extended: ExtendedSpec {
+            failsafe: qinfo.failsafe,
+            secure: qinfo.secure,
+            senderName: qinfo.sender_name,
+            senderNameEnforced: qinfo.sender_name_enforced,
+            ..Default::default()
+        },
     }
-    queue.spec.overflowPolicy = Some(0);
-    if let Some(val) = queue.spec.prefetch {
-        queue.spec.prefetch = Some(val);
-    } else {
-        queue.spec.prefetch = Some(0);
-    }
-    queue.spec.global = Some(false);
-    queue.spec.maxbytes = Some(0);
-    queue.spec.redeliveryDelay = Some(0);
-    queue.spec.maxRedelivery = Some(0);
-    let status = QueueStatus {
-        pendingMessages: 0,
-        consumerCount: 0,
-    };
-    queue.status = Some(status);
 }
 
 /// deletes a queue within the ems
-fn delete_queue(queue: &Queue) {
+fn delete_queue(
+    sessions: &ServerSessions,
+    queue: &Queue,
+    timeout: Duration,
+) -> Result<(), std::io::Error> {
     let qname = get_queue_name(queue);
     info!("deleting queue {}", qname);
-    let session = ADMIN_CONNECTION.lock().unwrap();
-    let result = tibco_ems::admin::delete_queue(&session, &qname);
-    match result {
-        Ok(_) => {
-            debug!("queue deleted");
-        }
-        Err(err) => {
-            error!("failed to delete queue: {:?}", err);
-            panic!("failed to delete queue");
+    let session = sessions.admin()?;
+    match super::admin::delete_queue(&session, &qname, timeout) {
+        Ok(_) => debug!("queue deleted"),
+        Err(err) if err.kind() == ErrorKind::NotFound => debug!("queue does not exist"),
+        Err(err) => return Err(err),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn qinfo() -> QueueInfo {
+        QueueInfo {
+            name: "q.test".to_string(),
+            max_bytes: Some(1024),
+            overflow_policy: Some(OverflowPolicy::Default),
+            failsafe: Some(false),
+            ..Default::default()
         }
     }
+
+    #[test]
+    fn matching_queue_has_no_delta() {
+        let spec = QueueProperties {
+            name: "q.test".to_string(),
+            max_bytes: Some(1024),
+            ..Default::default()
+        };
+        assert_eq!(
+            get_queue_delta(&spec, &qinfo(), &UnreportedProperties::default()),
+            None
+        );
+    }
+
+    #[test]
+    fn changed_properties_are_sent() {
+        let spec = QueueProperties {
+            name: "q.test".to_string(),
+            max_bytes: Some(2048),
+            overflow_policy: Some(2),
+            ..Default::default()
+        };
+        let (delta, changes) =
+            get_queue_delta(&spec, &qinfo(), &UnreportedProperties::default()).unwrap();
+        assert_eq!(delta.max_bytes, Some(2048));
+        assert_eq!(delta.overflow_policy, Some(2));
+        assert_eq!(delta.max_messages, None);
+        assert_eq!(changes, vec!["maxbytes", "overflowPolicy"]);
+    }
+
+    #[test]
+    fn removed_property_is_reset_to_the_default() {
+        let spec = QueueProperties {
+            name: "q.test".to_string(),
+            ..Default::default()
+        };
+        let (delta, changes) =
+            get_queue_delta(&spec, &qinfo(), &UnreportedProperties::default()).unwrap();
+        assert_eq!(delta.max_bytes, Some(0));
+        assert_eq!(changes, vec!["maxbytes"]);
+    }
+
+    #[test]
+    fn unreported_properties_are_only_sent_when_not_applied() {
+        let spec = QueueProperties {
+            name: "q.test".to_string(),
+            max_bytes: Some(1024),
+            max_redelivery: Some(3),
+            ..Default::default()
+        };
+        let applied = get_unreported(&spec);
+        assert_eq!(get_queue_delta(&spec, &qinfo(), &applied), None);
+        let (delta, changes) =
+            get_queue_delta(&spec, &qinfo(), &UnreportedProperties::default()).unwrap();
+        assert_eq!(delta.max_redelivery, Some(3));
+        assert_eq!(changes, vec!["maxRedelivery"]);
+    }
+
+    #[test]
+    fn removed_unreported_properties_are_reset() {
+        let spec = QueueProperties {
+            name: "q.test".to_string(),
+            max_bytes: Some(1024),
+            ..Default::default()
+        };
+        let applied = UnreportedProperties {
+            maxRedelivery: Some(3),
+            exclusive: Some(true),
+            trace: Some("trace,body".to_string()),
+            import: Some("rv".to_string()),
+            ..Default::default()
+        };
+        let (delta, changes) = get_queue_delta(&spec, &qinfo(), &applied).unwrap();
+        assert_eq!(delta.max_redelivery, Some(0));
+        assert_eq!(delta.exclusive, Some(false));
+        assert_eq!(delta.extended.trace.as_deref(), Some(""));
+        assert_eq!(delta.extended.import.as_deref(), Some(""));
+        assert_eq!(delta.extended.store, None);
+        assert_eq!(
+            changes,
+            vec!["maxRedelivery", "exclusive", "trace", "import"]
+        );
+    }
 }
@@ -0,0 +1,496 @@
+use super::admin::{self, RouteInfo, RouteSelector};
+use super::config::Config;
+use super::controller::{self, Condition, Context, Error, HasConditions, SyncResult};
+use super::health;
+use super::leader;
+use super::server;
+use super::supervisor;
+use futures::StreamExt;
+use kube::api::{Api, Patch, PatchParams, ResourceExt};
+use kube::runtime::controller::Action;
+use kube::runtime::finalizer::{finalizer, Event};
+use kube::{Client, CustomResource};
+use once_cell::sync::Lazy;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+use tokio::time::{self, Duration};
+use tokio_util::sync::CancellationToken;
+
+#[derive(CustomResource, Serialize, Deserialize, Default, Clone, Debug, PartialEq, JsonSchema)]
+#[kube(
+    group = "tibcoems.apimeister.com",
+    version = "v1",
+    kind = "Route",
+    status = "RouteStatus",
+    namespaced
+)]
+#[allow(non_snake_case)]
+pub struct RouteSpec {
+    /// name of the remote server, defaults to the object name
+    pub name: Option<String>,
+    /// url of the remote server
+    pub url: String,
+    pub zone: Option<ZoneSpec>,
+    /// selectors restricting the messages forwarded per topic
+    #[serde(default)]
+    pub selectors: Vec<SelectorSpec>,
+    /// name of the EmsServer object within the namespace, defaults to the server of the operator
+    pub server: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, JsonSchema)]
+#[allow(non_snake_case)]
+pub struct ZoneSpec {
+    pub name: String,
+    /// either 1hop or mhop, defaults to 1hop
+    pub r#type: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, JsonSchema)]
+#[allow(non_snake_case)]
+pub struct SelectorSpec {
+    /// either incoming or outgoing
+    pub direction: String,
+    pub topic: String,
+    pub selector: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[allow(non_snake_case)]
+#[serde(default)]
+pub struct RouteStatus {
+    /// whether the route is connected to the remote server
+    pub connected: Option<bool>,
+    pub conditions: Vec<Condition>,
+    /// generation of the spec which was last reconciled
+    pub observedGeneration: Option<i64>,
+    /// last time the route was successfully reconciled with the EMS
+    pub lastSyncTime: Option<String>,
+    /// result of the last failed admin operation
+    pub message: Option<String>,
+    /// spec of the route last applied to the EMS, used to rename, move and delete it
+    pub applied: Option<RouteSpec>,
+}
+
+impl HasConditions for Route {
+    fn conditions(&self) -> &[Condition] {
+        match &self.status {
+            Some(status) => &status.conditions,
+            None => &[],
+        }
+    }
+}
+
+/// route objects within kubernetes, filled by the controller
+pub static ROUTE_STORE: controller::Stores<Route> = controller::Stores::new();
+/// all routes present on the EMS servers, keyed by server and route name
+///
+/// a server is missing until its routes were retrieved
+static ROUTES: Lazy<Mutex<HashMap<String, HashMap<String, RouteInfo>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub async fn watch_routes(config: Arc<Config>, token: CancellationToken) -> Result<(), ()> {
+    let client = controller::client().await?;
+    let ctx = controller::Context::new(client, config, token);
+    let controllers = controller::new_controllers(&ctx, "routes");
+    ROUTE_STORE.register(&controllers);
+    controller::run_controllers(controllers, reconcile, ctx)
+        .for_each(|result| async move {
+            match result {
+                Ok((route, _action)) => trace!("reconciled route {}", route.name),
+                Err(err) => debug!("route reconciliation failed: {:?}", err),
+            }
+        })
+        .await;
+    Ok(())
+}
+
+/// brings the route on the EMS in line with the route object
+async fn reconcile(route: Arc<Route>, ctx: Arc<Context>) -> Result<Action, Error> {
+    let key = controller::object_key(route.as_ref());
+    let api: Api<Route> = Api::namespaced(ctx.client.clone(), &route.namespace().unwrap());
+    let action = finalizer(&api, controller::FINALIZER, route, |event| async {
+        match event {
+            Event::Apply(route) => apply_route(&api, &route, &ctx).await,
+            Event::Cleanup(route) => cleanup_route(&api, &route, &ctx).await,
+        }
+    })
+    .await?;
+    ctx.reset_backoff(&key);
+    Ok(action)
+}
+
+/// creates the route on the EMS or updates its url and selectors
+///
+/// the zone of a route cannot be changed, so the route is deleted and created again.
+/// The route last applied is kept in the status, so a renamed or moved route is removed
+/// after a restart too
+async fn apply_route(api: &Api<Route>, route: &Route, ctx: &Context) -> Result<Action, Error> {
+    let key = controller::object_key(route);
+    let namespace = route.namespace().unwrap_or_default();
+    let applied = route
+        .status
+        .as_ref()
+        .and_then(|status| status.applied.as_ref());
+    let server = get_server(&namespace, &route.spec);
+    let sessions = match server::get_sessions(&server) {
+        Ok(sessions) => sessions,
+        Err(message) => {
+            warn!("route {} cannot be applied: {}", key, message);
+            let result = SyncResult::Failed {
+                exists: applied.is_some(),
+                message,
+            };
+            controller::patch_sync_status(api, route, result).await;
+            return Ok(Action::requeue(Duration::from_secs(10)));
+        }
+    };
+    let route_info = match create_route_object(route, &route.spec) {
+        Ok(route_info) => route_info,
+        Err(message) => {
+            warn!("route {} has an invalid spec: {}", key, message);
+            let result = SyncResult::Failed {
+                exists: applied.is_some(),
+                message: format!("invalid spec: {message}"),
+            };
+            controller::patch_sync_status(api, route, result).await;
+            return Ok(Action::await_change());
+        }
+    };
+    let route_name = route_info.name.clone();
+    let current = {
+        let r_map = ROUTES.lock().unwrap();
+        match r_map.get(&server) {
+            Some(routes) => routes.get(&route_name).cloned(),
+            None => {
+                debug!("waiting for route information from EMS");
+                return Ok(Action::requeue(Duration::from_secs(5)));
+            }
+        }
+    };
+    let previous = applied.and_then(|old| {
+        let previous = create_route_object(route, old).ok()?;
+        Some((get_server(&namespace, old), previous))
+    });
+    let timeout = ctx.config.admin_command_timeout;
+    let result = match sessions.admin() {
+        Ok(session) => {
+            let mut result = Ok(None);
+            //the route was renamed or moved, the new route is created below
+            match &previous {
+                Some((previous_server, previous)) if previous_server != &server => {
+                    info!(
+                        "moving route {} to {}",
+                        route_name,
+                        server::display_name(&server)
+                    );
+                    remove_moved_route(previous_server, &previous.name, timeout);
+                }
+                Some((_, previous)) if previous.name != route_name => {
+                    info!("removing route {} after rename", previous.name);
+                    result = match admin::delete_route(&session, &previous.name, timeout) {
+                        Err(err) if err.kind() != ErrorKind::NotFound => Err((true, err)),
+                        _ => Ok(None),
+                    };
+                }
+                _ => {}
+            }
+            //selectors are not listed, so they are compared with the route last applied
+            let previous = previous
+                .filter(|(previous_server, _)| previous_server == &server)
+                .map(|(_, previous)| previous);
+            if result.is_ok() {
+                result = match &current {
+                    None => {
+                        info!("adding route {}", route_name);
+                        admin::create_route(&session, &route_info, timeout)
+                            .map(|_| Some(format!("created route {route_name}")))
+                            .map_err(|err| (false, err))
+                    }
+                    //routes without a zone in the spec keep the default zone of the EMS
+                    Some(current)
+                        if route_info.zone_name.is_some()
+                            && (current.zone_name != route_info.zone_name
+                                || current.zone_type != route_info.zone_type) =>
+                    {
+                        info!("replacing route {} to change its zone", route_name);
+                        admin::delete_route(&session, &route_name, timeout)
+                            .map_err(|err| (true, err))
+                            .and_then(|_| {
+                                admin::create_route(&session, &route_info, timeout)
+                                    .map_err(|err| (false, err))
+                            })
+                            .map(|_| Some(format!("replaced route {route_name}")))
+                    }
+                    Some(current)
+                        if current.url != route_info.url
+                            || previous.as_ref() != Some(&route_info) =>
+                    {
+                        info!("updating route {}", route_name);
+                        admin::update_route(&session, &route_info, timeout)
+                            .map(|_| Some(format!("updated route {route_name}")))
+                            .map_err(|err| (true, err))
+                    }
+                    Some(_) => Ok(None),
+                };
+            }
+            result
+        }
+        Err(err) => Err((current.is_some(), err)),
+    };
+    match result {
+        Ok(note) => {
+            if note.is_some() {
+                //reflect new route until the next refresh
+                let mut r_map = ROUTES.lock().unwrap();
+                let connected = current.and_then(|current| current.connected);
+                let route_info = RouteInfo {
+                    connected,
+                    ..route_info
+                };
+                let routes = r_map.entry(server).or_default();
+                routes.insert(route_name, route_info);
+            }
+            if applied != Some(&route.spec) {
+                controller::patch_applied(api, route, Some(&route.spec)).await;
+            }
+            controller::patch_sync_status(api, route, SyncResult::Synced(note)).await;
+            Ok(Action::requeue(ctx.requeue_interval()))
+        }
+        Err((exists, err)) => {
+            let message = format!("failed to apply route {route_name}: {err}");
+            let result = SyncResult::Failed { exists, message };
+            controller::patch_sync_status(api, route, result).await;
+            Err(Error::Ems(err))
+        }
+    }
+}
+
+/// removes a route from the server it was moved away from
+///
+/// a failure only leaves the route behind on the previous server, so it is logged
+fn remove_moved_route(server: &str, route_name: &str, timeout: Duration) {
+    let result = server::get_sessions(server)
+        .map_err(std::io::Error::other)
+        .and_then(|sessions| {
+            let session = sessions.admin()?;
+            admin::delete_route(&session, route_name, timeout)
+        });
+    match result {
+        Err(err) if err.kind() != ErrorKind::NotFound => warn!(
+            "failed to remove route {} from previous server {}: {}",
+            route_name,
+            server::display_name(server),
+            err
+        ),
+        _ => {}
+    }
+}
+
+/// removes the route last applied from the EMS before kubernetes deletes the object
+///
+/// a failed deletion keeps the finalizer, so the object stays until the route is gone
+async fn cleanup_route(api: &Api<Route>, route: &Route, ctx: &Context) -> Result<Action, Error> {
+    let key = controller::object_key(route);
+    if ctx.config.do_not_delete_objects {
+        warn!(
+            "delete event for {} (not executed because of DO_NOT_DELETE_OBJECTS setting)",
+            key
+        );
+        return Ok(Action::await_change());
+    }
+    let spec = match route
+        .status
+        .as_ref()
+        .and_then(|status| status.applied.as_ref())
+    {
+        Some(applied) => applied,
+        None => &route.spec,
+    };
+    let server = get_server(&route.namespace().unwrap_or_default(), spec);
+    let sessions = match server::get_sessions(&server) {
+        Ok(sessions) => sessions,
+        Err(message) => {
+            warn!("route {} cannot be deleted: {}", key, message);
+            let result = SyncResult::Failed {
+                exists: true,
+                message,
+            };
+            controller::patch_sync_status(api, route, result).await;
+            return Ok(Action::requeue(Duration::from_secs(10)));
+        }
+    };
+    let route_name = get_route_name(route, spec);
+    info!("deleting route {}", route_name);
+    let result = sessions.admin().and_then(|session| {
+        admin::delete_route(&session, &route_name, ctx.config.admin_command_timeout)
+    });
+    match result {
+        Err(err) if err.kind() != ErrorKind::NotFound => {
+            let message = format!("failed to delete route {route_name}: {err}");
+            let result = SyncResult::Failed {
+                exists: true,
+                message,
+            };
+            controller::patch_sync_status(api, route, result).await;
+            Err(Error::Ems(err))
+        }
+        _ => {
+            if let Some(routes) = ROUTES.lock().unwrap().get_mut(&server) {
+                routes.remove(&route_name);
+            }
+            Ok(Action::await_change())
+        }
+    }
+}
+
+pub async fn watch_routes_status(config: Arc<Config>, token: CancellationToken) -> Result<(), ()> {
+    let client = controller::client().await?;
+    let mut interval = time::interval(config.status_refresh);
+    loop {
+        if !supervisor::tick(&mut interval, &token).await {
+            return Ok(());
+        }
+        health::heartbeat("routes_status");
+        let all_sessions = server::all_sessions();
+        for (server, sessions) in &all_sessions {
+            let result = sessions.statistics().and_then(|session| {
+                admin::list_routes(&session, config.admin_command_timeout)
+                    .inspect_err(|err| sessions.mark_broken(&session, err))
+            });
+            let res = match result {
+                Ok(res) => res,
+                Err(err) => {
+                    error!(
+                        "failed to retrieve route information from {}: {}",
+                        server::display_name(server),
+                        err
+                    );
+                    continue;
+                }
+            };
+            let routes: HashMap<String, RouteInfo> =
+                res.into_iter().map(|r| (r.name.clone(), r)).collect();
+            ROUTES
+                .lock()
+                .unwrap()
+                .insert(server.clone(), routes.clone());
+            if !config.read_only && leader::is_leader() {
+                update_routes_status(&client, server, &routes).await;
+            }
+        }
+        //forget the routes of removed servers
+        {
+            let mut r_map = ROUTES.lock().unwrap();
+            r_map.retain(|server, _| all_sessions.iter().any(|(key, _)| key == server));
+        }
+    }
+}
+
+/// updates the connection state of the route objects of a server
+async fn update_routes_status(client: &Client, server: &str, routes: &HashMap<String, RouteInfo>) {
+    for route in ROUTE_STORE.state() {
+        if get_server(&route.namespace().unwrap_or_default(), &route.spec) != server {
+            continue;
+        }
+        let Some(rinfo) = routes.get(&get_route_name(&route, &route.spec)) else {
+            continue;
+        };
+        let update = match &route.status {
+            Some(status) => status.connected != rinfo.connected,
+            None => true,
+        };
+        if !update {
+            continue;
+        }
+        let obj_name = route.name_any();
+        debug!("updating route status for {}", obj_name);
+        let updater: Api<Route> = Api::namespaced(client.clone(), &route.namespace().unwrap());
+        let status = serde_json::json!({
+            "status": {
+                "connected": rinfo.connected,
+            }
+        });
+        let pp = PatchParams::default();
+        if let Err(err) = updater
+            .patch_status(&obj_name, &pp, &Patch::Merge(&status))
+            .await
+        {
+            error!("error while updating route object");
+            error!("{:?}", err);
+        }
+    }
+}
+
+/// key of the EMS server the route belongs to
+fn get_server(namespace: &str, spec: &RouteSpec) -> String {
+    server::server_key(namespace, &spec.server)
+}
+
+/// name of the remote server for the given spec, defaults to the object name
+fn get_route_name(route: &Route, spec: &RouteSpec) -> String {
+    match &spec.name {
+        Some(name) => name.clone(),
+        None => route.name_any(),
+    }
+}
+
+/// validates the spec and creates the route sent to the EMS
+fn create_route_object(route: &Route, spec: &RouteSpec) -> Result<RouteInfo, String> {
+    if !spec.url.starts_with("tcp://") && !spec.url.starts_with("ssl://") {
+        return Err(format!(
+            "url must start with tcp:// or ssl://, got '{}'",
+            spec.url
+        ));
+    }
+    let (zone_name, zone_type) = match &spec.zone {
+        Some(zone) => {
+            let zone_type = zone.r#type.clone().unwrap_or_else(|| "1hop".to_owned());
+            if zone_type != "1hop" && zone_type != "mhop" {
+                return Err(format!(
+                    "zone type must be either 1hop or mhop, got '{zone_type}'"
+                ));
+            }
+            (Some(zone.name.clone()), Some(zone_type))
+        }
+        None => (None, None),
+    };
+    let mut selectors = Vec::new();
+    for selector in &spec.selectors {
+        let incoming = match selector.direction.to_ascii_lowercase().as_str() {
+            "incoming" => true,
+            "outgoing" => false,
+            _ => {
+                return Err(format!(
+                    "selector direction must be incoming or outgoing, got '{}'",
+                    selector.direction
+                ))
+            }
+        };
+        if selectors
+            .iter()
+            .any(|s: &RouteSelector| s.incoming == incoming && s.topic == selector.topic)
+        {
+            return Err(format!(
+                "topic {} has more than one {} selector",
+                selector.topic, selector.direction
+            ));
+        }
+        selectors.push(RouteSelector {
+            incoming,
+            topic: selector.topic.clone(),
+            selector: selector.selector.clone(),
+        });
+    }
+    Ok(RouteInfo {
+        name: get_route_name(route, spec),
+        url: spec.url.clone(),
+        zone_name,
+        zone_type,
+        selectors,
+        connected: None,
+    })
+}
@@ -1,16 +1,19 @@
+use super::config::Config;
+use super::supervisor;
 use k8s_openapi::api::apps::v1::Deployment;
 use kube::api::PatchParams;
 use kube::core::subresource::Scale;
 use kube::Error;
 use kube::{
-    api::{Api, ListParams, Patch, ResourceExt},
+    api::{Api, ListParams, Patch},
     Client,
 };
 use once_cell::sync::Lazy;
 use std::collections::HashMap;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use std::time::SystemTime;
 use tokio::time::{self, Duration};
+use tokio_util::sync::CancellationToken;
 
 /// period to wait before a scale down can be performed
 const COOLDOWN_PERIOD_SECONDS: u64 = 60;
@@ -211,10 +214,13 @@ impl State {
     }
 }
 
+/// scales the deployment, the name is prefixed with its namespace
 async fn scale_to_target(deployment_name: &str, replicas: u32) -> Result<Scale, Error> {
-    let client = Client::try_default().await.expect("getting default client");
-    let namespace = env_var!(required "KUBERNETES_NAMESPACE");
-    let deployments: Api<Deployment> = Api::namespaced(client, &namespace);
+    let client = Client::try_default().await?;
+    let (namespace, deployment_name) = deployment_name
+        .split_once('/')
+        .expect("deployment name contains the namespace");
+    let deployments: Api<Deployment> = Api::namespaced(client, namespace);
     let scale_spec = serde_json::json!({
       "spec": { "replicas": replicas }
     });
@@ -232,13 +238,12 @@ pub fn get_epoch_seconds() -> u64 {
 }
 
 /// watches for k8s Deployments with scaling labels present
-pub async fn run() {
-    let client = Client::try_default().await.expect("getting default client");
-    let namespace = env_var!(required "KUBERNETES_NAMESPACE");
-    let deployments: Api<Deployment> = Api::namespaced(client, &namespace);
+pub async fn run(config: Arc<Config>, token: CancellationToken) -> Result<(), ()> {
+    let client = super::controller::client().await?;
+    let apis: Vec<Api<Deployment>> = super::controller::namespaced_apis(client, &config);
     let mut lp = ListParams::default().labels("tibcoems.apimeister.com/scaling=true");
 
-    let responsible_for = super::RESPONSIBLE_FOR.lock().unwrap().clone();
+    let responsible_for = &config.responsible_for;
     if !responsible_for.is_empty() {
         info!("scaling Deployments for instance {responsible_for}");
         lp = lp.labels(format!("tibcoems.apimeister.com/owner={responsible_for}").as_str());
@@ -251,9 +256,19 @@ pub async fn run() {
     interval.tick().await;
 
     loop {
-        interval.tick().await;
-        for deployment in deployments.list(&lp).await.unwrap() {
-            let deployment_name = ResourceExt::name_any(&deployment).clone();
+        if !supervisor::tick(&mut interval, &token).await {
+            return Ok(());
+        }
+        let mut deployments = Vec::new();
+        for api in &apis {
+            match api.list(&lp).await {
+                Ok(list) => deployments.extend(list),
+                Err(err) => error!("failed to list deployments: {}", err),
+            }
+        }
+        for deployment in deployments {
+            //deployments of different namespaces may share a name
+            let deployment_name = super::controller::object_key(&deployment);
             //acquire shared objects
             let mut known_scalings = KNOWN_STATES.lock().unwrap();
             let mut scale_targets = SCALE_TARGETS.lock().unwrap();
@@ -295,10 +310,13 @@ pub async fn run() {
                 let mut max_scale = 10u32;
                 for (key, val) in labels {
                     if key.starts_with("tibcoems.apimeister.com/queue") {
-                        //check queues on EMS Server
+                        //check queues on EMS Server, only the default server is scaled
                         let queue_name = val;
                         let all_queues = super::queue::QUEUES.lock().unwrap();
-                        if all_queues.contains_key(&queue_name) {
+                        let known_queue = all_queues
+                            .get(super::server::DEFAULT_SERVER)
+                            .is_some_and(|queues| queues.contains_key(&queue_name));
+                        if known_queue {
                             //known queue
                             if scale_targets.contains_key(&queue_name) {
                                 let mut x: Vec<String> =
@@ -0,0 +1,693 @@
+use super::bridge::BRIDGE_STORE;
+use super::config::Config;
+use super::controller::{self, Condition, Context, Error, HasConditions, SyncResult};
+use super::durable::DURABLE_STORE;
+use super::queue::QUEUE_STORE;
+use super::route::ROUTE_STORE;
+use super::topic::TOPIC_STORE;
+use super::user::USER_STORE;
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::Secret;
+use kube::runtime::controller::Action;
+use kube::runtime::finalizer::{finalizer, Event};
+use kube::CustomResource;
+use kube::{
+    api::{Api, Patch, PatchParams, ResourceExt},
+    Client,
+};
+use once_cell::sync::{Lazy, OnceCell};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::ErrorKind;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+use tibco_ems::Session;
+use tokio::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// key of the EMS server configured by SERVER_URL, USERNAME and PASSWORD
+pub const DEFAULT_SERVER: &str = "";
+
+/// delay before the first reconnect attempt, doubled on every failed attempt
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// upper bound of the reconnect delay
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(CustomResource, Serialize, Deserialize, Default, Clone, Debug, JsonSchema)]
+#[kube(
+    group = "tibcoems.apimeister.com",
+    version = "v1",
+    kind = "EmsServer",
+    plural = "emsservers",
+    status = "EmsServerStatus",
+    namespaced
+)]
+#[allow(non_snake_case)]
+pub struct EmsServerSpec {
+    /// server url, the urls of a fault tolerant pair are separated by comma
+    pub url: String,
+    /// secret holding username and password of the admin user
+    pub credentialsSecretRef: CredentialsSecretRef,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug, JsonSchema)]
+#[allow(non_snake_case)]
+pub struct CredentialsSecretRef {
+    /// name of the secret
+    pub name: String,
+    /// key of the username within the secret, defaults to username
+    pub usernameKey: Option<String>,
+    /// key of the password within the secret, defaults to password
+    pub passwordKey: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[allow(non_snake_case)]
+#[serde(default)]
+pub struct EmsServerStatus {
+    pub conditions: Vec<Condition>,
+    /// generation of the spec which was last reconciled
+    pub observedGeneration: Option<i64>,
+    /// last time the admin sessions were verified
+    pub lastSyncTime: Option<String>,
+    /// result of the last failed connection attempt
+    pub message: Option<String>,
+    /// whether the admin sessions are connected
+    pub connected: bool,
+    /// url of the active server of a fault tolerant pair
+    pub activeUrl: Option<String>,
+}
+
+impl HasConditions for EmsServer {
+    fn conditions(&self) -> &[Condition] {
+        match &self.status {
+            Some(status) => &status.conditions,
+            None => &[],
+        }
+    }
+}
+
+/// connection details of an EMS server
+#[derive(Clone, PartialEq)]
+struct Credentials {
+    url: String,
+    username: String,
+    password: String,
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("url", &self.url)
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+/// state of the connection to an EMS server
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionState {
+    /// no session was requested yet
+    Pending,
+    /// connected to the active server of the url list
+    Connected { active_url: String },
+    /// the connection failed, it is retried with increasing delay
+    Disconnected { error: String, failures: u32 },
+}
+
+/// connection state shared by the sessions of a server
+#[derive(Debug)]
+struct Link {
+    state: ConnectionState,
+    /// incremented whenever the connection breaks, older sessions are replaced
+    generation: u64,
+    /// no connection attempt is made before this point in time
+    retry_at: Option<Instant>,
+}
+
+/// session of a server together with the connection generation it belongs to
+#[derive(Debug, Default)]
+struct Slot {
+    session: Option<Session>,
+    generation: u64,
+}
+
+/// locked and connected session of a server
+pub struct SessionGuard<'a>(MutexGuard<'a, Slot>);
+
+impl Deref for SessionGuard<'_> {
+    type Target = Session;
+
+    fn deref(&self) -> &Session {
+        self.0.session.as_ref().expect("connected session")
+    }
+}
+
+/// admin sessions of one EMS server
+///
+/// the sessions connect on first use, a broken connection is re-established with
+/// increasing delay on the next use
+#[derive(Debug)]
+pub struct ServerSessions {
+    credentials: Credentials,
+    link: Mutex<Link>,
+    ///used for sending admin operations
+    admin: Mutex<Slot>,
+    ///used for retrieving statistics
+    statistics: Mutex<Slot>,
+}
+
+impl ServerSessions {
+    fn new(credentials: Credentials) -> ServerSessions {
+        ServerSessions {
+            credentials,
+            link: Mutex::new(Link {
+                state: ConnectionState::Pending,
+                generation: 0,
+                retry_at: None,
+            }),
+            admin: Mutex::new(Slot::default()),
+            statistics: Mutex::new(Slot::default()),
+        }
+    }
+
+    /// session used for sending admin operations
+    pub fn admin(&self) -> Result<SessionGuard<'_>, std::io::Error> {
+        self.lock(&self.admin)
+    }
+
+    /// session used for retrieving statistics
+    pub fn statistics(&self) -> Result<SessionGuard<'_>, std::io::Error> {
+        self.lock(&self.statistics)
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.link.lock().unwrap().state.clone()
+    }
+
+    /// closes the sessions, waiting for operations which still hold one
+    ///
+    /// the sessions connect again on their next use
+    fn close(&self) {
+        for slot in [&self.admin, &self.statistics] {
+            let mut slot = slot.lock().unwrap();
+            //dropping the session closes it
+            slot.session = None;
+        }
+        let mut link = self.link.lock().unwrap();
+        link.state = ConnectionState::Pending;
+        link.generation += 1;
+        link.retry_at = None;
+    }
+
+    /// marks the connection of the session as broken, the sessions reconnect on their next use
+    ///
+    /// called when a list command fails, as admin commands also fail for other reasons
+    pub fn mark_broken(&self, session: &SessionGuard, err: &std::io::Error) {
+        let mut link = self.link.lock().unwrap();
+        //the connection was already replaced or marked by another session
+        if session.0.generation != link.generation {
+            return;
+        }
+        self.fail(&mut link, err);
+    }
+
+    /// delays the next connection attempt and invalidates the current sessions
+    fn fail(&self, link: &mut Link, err: &std::io::Error) {
+        let failures = match &link.state {
+            ConnectionState::Disconnected { failures, .. } => failures + 1,
+            _ => {
+                error!("lost connection to {}: {}", self.credentials.url, err);
+                1
+            }
+        };
+        let delay = RECONNECT_DELAY
+            .saturating_mul(2u32.saturating_pow(failures - 1))
+            .min(MAX_RECONNECT_DELAY);
+        debug!(
+            "next connection attempt to {} in {:?}",
+            self.credentials.url, delay
+        );
+        link.state = ConnectionState::Disconnected {
+            error: err.to_string(),
+            failures,
+        };
+        link.generation += 1;
+        link.retry_at = Some(Instant::now() + delay);
+    }
+
+    fn lock<'a>(&'a self, slot: &'a Mutex<Slot>) -> Result<SessionGuard<'a>, std::io::Error> {
+        let mut slot = slot.lock().unwrap();
+        let generation = {
+            let link = self.link.lock().unwrap();
+            if slot.session.is_some() && slot.generation == link.generation {
+                return Ok(SessionGuard(slot));
+            }
+            if let Some(retry_at) = link.retry_at
+                && Instant::now() < retry_at
+            {
+                let message = format!(
+                    "not connected to {}, next attempt in {}s",
+                    self.credentials.url,
+                    (retry_at - Instant::now()).as_secs() + 1
+                );
+                return Err(std::io::Error::new(ErrorKind::NotConnected, message));
+            }
+            link.generation
+        };
+        //close the session of the broken connection before connecting again
+        slot.session = None;
+        match self.connect() {
+            Ok((session, active_url)) => {
+                let mut link = self.link.lock().unwrap();
+                if link.state
+                    != (ConnectionState::Connected {
+                        active_url: active_url.clone(),
+                    })
+                {
+                    info!("connected to {}", active_url);
+                }
+                link.state = ConnectionState::Connected { active_url };
+                link.retry_at = None;
+                slot.session = Some(session);
+                slot.generation = generation;
+                Ok(SessionGuard(slot))
+            }
+            Err(err) => {
+                let mut link = self.link.lock().unwrap();
+                self.fail(&mut link, &err);
+                Err(err)
+            }
+        }
+    }
+
+    /// connects to the active server of the url list
+    fn connect(&self) -> Result<(Session, String), std::io::Error> {
+        let credentials = &self.credentials;
+        info!("creating admin connection to {}", credentials.url);
+        let conn = tibco_ems::admin::connect(
+            &credentials.url,
+            &credentials.username,
+            &credentials.password,
+        )?;
+        let active_url = match conn.get_active_url() {
+            Ok(url) => url.trim_start_matches("<$admin>:").to_owned(),
+            Err(_) => credentials.url.clone(),
+        };
+        let session = conn.session()?;
+        Ok((session, active_url))
+    }
+}
+
+/// sessions of the EMS server configured by SERVER_URL, set by configure
+///
+/// replaced by configure and watch_credentials when the credentials change, operations
+/// already running finish on the previous sessions
+static DEFAULT_SESSIONS: OnceCell<Mutex<Arc<ServerSessions>>> = OnceCell::new();
+
+/// how often the credential files of the default server are checked for changes
+const CREDENTIALS_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// creates the sessions of the default server, they connect on first use
+///
+/// called again after the settings were reloaded, the sessions are only replaced if the
+/// connection settings changed. Fails if the credentials cannot be read
+pub fn configure(config: &Config) -> Result<(), String> {
+    let credentials = default_credentials(config)?;
+    match DEFAULT_SESSIONS.get() {
+        None => {
+            let sessions = Mutex::new(Arc::new(ServerSessions::new(credentials)));
+            let _ignore = DEFAULT_SESSIONS.set(sessions);
+        }
+        Some(slot) => {
+            let mut sessions = slot.lock().unwrap();
+            if sessions.credentials != credentials {
+                info!("connection settings of the default server changed, reconnecting");
+                *sessions = Arc::new(ServerSessions::new(credentials));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// closes the sessions of all servers, called on shutdown once the tasks are stopped
+pub fn close_sessions() {
+    for (server, sessions) in all_sessions() {
+        debug!("closing sessions of {}", display_name(&server));
+        sessions.close();
+    }
+}
+
+/// re-creates the sessions of the default server when its credential files change
+///
+/// kubernetes updates mounted secrets by replacing the files, so the content is compared
+pub async fn watch_credentials(config: Arc<Config>, token: CancellationToken) -> Result<(), ()> {
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(CREDENTIALS_CHECK_INTERVAL) => {}
+            _ = token.cancelled() => return Ok(()),
+        }
+        if let Err(err) = configure(&config) {
+            error!("keeping the current sessions of the default server: {err}");
+        }
+    }
+}
+
+fn default_sessions_slot() -> &'static Mutex<Arc<ServerSessions>> {
+    DEFAULT_SESSIONS
+        .get()
+        .expect("sessions of the default server are initialized at startup")
+}
+
+/// connection settings of the default server
+///
+/// USERNAME_FILE and PASSWORD_FILE take precedence over USERNAME and PASSWORD
+fn default_credentials(config: &Config) -> Result<Credentials, String> {
+    let url = normalize_url(&config.server_url)?;
+    let username = read_setting("USERNAME", &config.username_file)?;
+    let password = read_setting("PASSWORD", &config.password_file)?;
+    Ok(Credentials {
+        url,
+        username,
+        password,
+    })
+}
+
+/// value of the file, or of the environment variable if no file is configured
+fn read_setting(name: &str, file: &Option<String>) -> Result<String, String> {
+    match read_file(file.as_deref().unwrap_or_default())? {
+        //files usually end with a newline which is not part of the value
+        Some(value) => Ok(value.trim_end_matches(['\r', '\n']).to_owned()),
+        None => std::env::var(name).map_err(|_| format!("{name} or {name}_FILE is required")),
+    }
+}
+
+/// content of a file, None if no path is given
+fn read_file(path: &str) -> Result<Option<String>, String> {
+    if path.is_empty() {
+        return Ok(None);
+    }
+    std::fs::read_to_string(path)
+        .map(Some)
+        .map_err(|err| format!("cannot read {path}: {err}"))
+}
+
+/// sessions of the EmsServer objects, keyed by namespace and object name
+static SERVERS: Lazy<Mutex<HashMap<String, Arc<ServerSessions>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub async fn watch_servers(config: Arc<Config>, token: CancellationToken) -> Result<(), ()> {
+    let client = controller::client().await?;
+    let ctx = controller::Context::new(client, config, token);
+    let controllers = controller::new_controllers(&ctx, "emsservers");
+    controller::run_controllers(controllers, reconcile, ctx)
+        .for_each(|result| async move {
+            match result {
+                Ok((server, _action)) => trace!("reconciled server {}", server.name),
+                Err(err) => debug!("server reconciliation failed: {:?}", err),
+            }
+        })
+        .await;
+    Ok(())
+}
+
+/// key of the server referenced by an object in the given namespace
+///
+/// objects without a server reference use the default server
+pub fn server_key(namespace: &str, server: &Option<String>) -> String {
+    match server {
+        Some(name) if !name.is_empty() => format!("{namespace}/{name}"),
+        _ => DEFAULT_SERVER.to_owned(),
+    }
+}
+
+/// name of the server used in logs and status messages
+pub fn display_name(server: &str) -> &str {
+    if server == DEFAULT_SERVER {
+        "default server"
+    } else {
+        server
+    }
+}
+
+/// sessions of a server, fails if the server is not connected
+pub fn get_sessions(server: &str) -> Result<Arc<ServerSessions>, String> {
+    if server == DEFAULT_SERVER {
+        return Ok(default_sessions());
+    }
+    let servers = SERVERS.lock().unwrap();
+    match servers.get(server) {
+        Some(sessions) => Ok(sessions.clone()),
+        None => Err(format!("EMS server {server} is not configured")),
+    }
+}
+
+/// sessions of the server configured by environment variables
+pub fn default_sessions() -> Arc<ServerSessions> {
+    default_sessions_slot().lock().unwrap().clone()
+}
+
+/// sessions of the default server and of all EmsServer objects
+pub fn all_sessions() -> Vec<(String, Arc<ServerSessions>)> {
+    let mut sessions = vec![(DEFAULT_SERVER.to_owned(), default_sessions())];
+    let servers = SERVERS.lock().unwrap();
+    sessions.extend(servers.iter().map(|(key, s)| (key.clone(), s.clone())));
+    sessions
+}
+
+/// keeps the admin sessions of the server in line with the server object
+async fn reconcile(server: Arc<EmsServer>, ctx: Arc<Context>) -> Result<Action, Error> {
+    let key = controller::object_key(server.as_ref());
+    let api: Api<EmsServer> = Api::namespaced(ctx.client.clone(), &server.namespace().unwrap());
+    let action = finalizer(&api, controller::FINALIZER, server, |event| async {
+        match event {
+            Event::Apply(server) => apply_server(&api, &server, &ctx).await,
+            Event::Cleanup(server) => cleanup_server(&api, &server).await,
+        }
+    })
+    .await?;
+    ctx.reset_backoff(&key);
+    Ok(action)
+}
+
+/// connects to the server, or reconnects if the url or the credentials changed
+///
+/// the secret is read on every reconciliation, so changed credentials are applied
+/// within DRIFT_RECONCILE_INTERVAL_IN_MS
+async fn apply_server(
+    api: &Api<EmsServer>,
+    server: &EmsServer,
+    ctx: &Context,
+) -> Result<Action, Error> {
+    let key = controller::object_key(server);
+    let current = {
+        let servers = SERVERS.lock().unwrap();
+        servers.get(&key).cloned()
+    };
+    let url = match normalize_url(&server.spec.url) {
+        Ok(url) => url,
+        Err(message) => {
+            warn!("server {} has an invalid spec: {}", key, message);
+            let result = SyncResult::Failed {
+                exists: current.is_some(),
+                message: format!("invalid spec: {message}"),
+            };
+            controller::patch_sync_status(api, server, result).await;
+            return Ok(Action::await_change());
+        }
+    };
+    let credentials = match get_credentials(ctx.client.clone(), server, url).await {
+        Ok(credentials) => credentials,
+        Err(err) => {
+            let result = SyncResult::Failed {
+                exists: current.is_some(),
+                message: format!("failed to get credentials: {err}"),
+            };
+            controller::patch_sync_status(api, server, result).await;
+            return Err(err);
+        }
+    };
+    let (sessions, note) = match current {
+        Some(sessions) if sessions.credentials == credentials => (sessions, None),
+        current => {
+            let sessions = Arc::new(ServerSessions::new(credentials));
+            let mut servers = SERVERS.lock().unwrap();
+            servers.insert(key, sessions.clone());
+            match current {
+                Some(_) => (sessions, Some("reconnected to server".to_owned())),
+                None => (sessions, Some("connected to server".to_owned())),
+            }
+        }
+    };
+    //establishes the connection of new sessions
+    let result = sessions.admin().map(|_| ());
+    let (connected, active_url) = match sessions.state() {
+        ConnectionState::Connected { active_url } => (true, Some(active_url)),
+        _ => (false, None),
+    };
+    let status = serde_json::json!({
+        "status": {
+            "connected": connected,
+            "activeUrl": active_url,
+        }
+    });
+    if let Err(err) = api
+        .patch_status(
+            &server.name_any(),
+            &PatchParams::default(),
+            &Patch::Merge(&status),
+        )
+        .await
+    {
+        error!("error while updating server object");
+        error!("{:?}", err);
+    }
+    match result {
+        Ok(_) => {
+            controller::patch_sync_status(api, server, SyncResult::Synced(note)).await;
+            Ok(Action::requeue(ctx.requeue_interval()))
+        }
+        Err(err) => {
+            let message = format!("failed to connect to {}: {err}", server.spec.url);
+            let result = SyncResult::Failed {
+                exists: true,
+                message,
+            };
+            controller::patch_sync_status(api, server, result).await;
+            Err(Error::Ems(err))
+        }
+    }
+}
+
+/// drops the admin sessions once no queue, topic, bridge, user, durable or route uses the server
+///
+/// the referencing objects need the sessions to remove their EMS objects, so the
+/// server object stays until they are gone
+async fn cleanup_server(api: &Api<EmsServer>, server: &EmsServer) -> Result<Action, Error> {
+    let key = controller::object_key(server);
+    let references = count_references(&key);
+    if references > 0 {
+        let message = format!("server is still referenced by {references} objects");
+        info!("not removing server {}: {}", key, message);
+        let result = SyncResult::Failed {
+            exists: true,
+            message,
+        };
+        controller::patch_sync_status(api, server, result).await;
+        return Ok(Action::requeue(Duration::from_secs(10)));
+    }
+    info!("removing admin sessions of server {}", key);
+    let mut servers = SERVERS.lock().unwrap();
+    servers.remove(&key);
+    Ok(Action::await_change())
+}
+
+/// number of queues, topics, bridges, users, durables and routes which use the server
+fn count_references(key: &str) -> usize {
+    let queues = QUEUE_STORE
+        .state()
+        .into_iter()
+        .filter(|q| server_key(&q.namespace().unwrap_or_default(), &q.spec.server) == key)
+        .count();
+    let topics = TOPIC_STORE
+        .state()
+        .into_iter()
+        .filter(|t| server_key(&t.namespace().unwrap_or_default(), &t.spec.server) == key)
+        .count();
+    let bridges = BRIDGE_STORE
+        .state()
+        .into_iter()
+        .filter(|b| server_key(&b.namespace().unwrap_or_default(), &b.spec.server) == key)
+        .count();
+    let users = USER_STORE
+        .state()
+        .into_iter()
+        .filter(|u| server_key(&u.namespace().unwrap_or_default(), &u.spec.server) == key)
+        .count();
+    let durables = DURABLE_STORE
+        .state()
+        .into_iter()
+        .filter(|d| server_key(&d.namespace().unwrap_or_default(), &d.spec.server) == key)
+        .count();
+    let routes = ROUTE_STORE
+        .state()
+        .into_iter()
+        .filter(|r| server_key(&r.namespace().unwrap_or_default(), &r.spec.server) == key)
+        .count();
+    queues + topics + bridges + users + durables + routes
+}
+
+/// reads username and password of the server from its secret
+async fn get_credentials(
+    client: Client,
+    server: &EmsServer,
+    url: String,
+) -> Result<Credentials, Error> {
+    let secret_ref = &server.spec.credentialsSecretRef;
+    let secrets: Api<Secret> = Api::namespaced(client, &server.namespace().unwrap());
+    let secret = secrets.get(&secret_ref.name).await?;
+    let read_key = |secret_key: &str| -> Result<String, Error> {
+        let value = secret
+            .data
+            .as_ref()
+            .and_then(|data| data.get(secret_key))
+            .ok_or_else(|| {
+                Error::Secret(format!(
+                    "key {secret_key} is missing in secret {}",
+                    secret_ref.name
+                ))
+            })?;
+        String::from_utf8(value.0.clone()).map_err(|_| {
+            Error::Secret(format!(
+                "key {secret_key} of secret {} is not valid UTF-8",
+                secret_ref.name
+            ))
+        })
+    };
+    let username = read_key(secret_ref.usernameKey.as_deref().unwrap_or("username"))?;
+    let password = read_key(secret_ref.passwordKey.as_deref().unwrap_or("password"))?;
+    Ok(Credentials {
+        url,
+        username,
+        password,
+    })
+}
+
+/// validates a url list of a fault tolerant pair and removes blanks around the urls
+///
+/// ssl:// urls connect with the defaults of the EMS client library, certificates cannot be configured
+pub fn normalize_url(url: &str) -> Result<String, String> {
+    let urls: Vec<&str> = url.split(',').map(str::trim).collect();
+    for url in &urls {
+        if !url.starts_with("tcp://") && !url.starts_with("ssl://") {
+            return Err(format!("url must start with tcp:// or ssl://, got '{url}'"));
+        }
+    }
+    Ok(urls.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_url_trims_fault_tolerant_pairs() {
+        assert_eq!(
+            normalize_url(" tcp://ems1:7222 , tcp://ems2:7222").unwrap(),
+            "tcp://ems1:7222,tcp://ems2:7222"
+        );
+        assert_eq!(normalize_url("ssl://ems:7243").unwrap(), "ssl://ems:7243");
+    }
+
+    #[test]
+    fn normalize_url_rejects_other_schemes() {
+        assert_eq!(
+            normalize_url("tcp://ems1:7222,http://ems2:7222").unwrap_err(),
+            "url must start with tcp:// or ssl://, got 'http://ems2:7222'"
+        );
+        assert!(normalize_url("tcp://ems:7222,").is_err());
+    }
+}
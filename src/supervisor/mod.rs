@@ -0,0 +1,190 @@
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Duration, Interval};
+use tokio_util::sync::CancellationToken;
+
+/// delay before the first restart of a failed task, doubled on every consecutive failure
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+/// upper bound of the restart delay
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+/// a task running at least this long before it fails starts over with the first delay
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+/// state of a supervised task
+#[derive(Debug, Clone, PartialEq)]
+pub enum TaskState {
+    Running,
+    /// the task failed and is restarted after a delay
+    Restarting {
+        failures: u32,
+    },
+}
+
+/// states of the supervised tasks by name, stopped tasks are removed
+static TASKS: Lazy<Mutex<HashMap<&'static str, TaskState>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// states of all supervised tasks
+pub fn tasks() -> Vec<(&'static str, TaskState)> {
+    let tasks = TASKS.lock().unwrap();
+    tasks
+        .iter()
+        .map(|(name, state)| (*name, state.clone()))
+        .collect()
+}
+
+/// tasks which are stopped together
+///
+/// a task which returns or panics before the group is stopped is restarted with increasing delay
+#[derive(Default)]
+pub struct TaskGroup {
+    token: CancellationToken,
+    handles: Vec<(&'static str, JoinHandle<()>)>,
+}
+
+impl TaskGroup {
+    /// runs the task until the group is stopped, the task is given the token of the group
+    /// and is expected to return once it is cancelled
+    pub fn spawn<F, Fut>(&mut self, name: &'static str, task: F)
+    where
+        F: Fn(CancellationToken) -> Fut + Send + 'static,
+        Fut: Future + Send + 'static,
+        Fut::Output: Send + 'static,
+    {
+        let token = self.token.clone();
+        let handle = tokio::spawn(supervise(name, task, token));
+        self.handles.push((name, handle));
+    }
+
+    /// cancels the tasks and waits for them to finish, tasks still running after the
+    /// timeout are aborted
+    pub async fn stop(self, timeout: Duration) {
+        self.token.cancel();
+        let deadline = time::Instant::now() + timeout;
+        for (name, mut handle) in self.handles {
+            if time::timeout_at(deadline, &mut handle).await.is_err() {
+                warn!("task {name} did not stop within {timeout:?}, aborting");
+                handle.abort();
+                //the supervisor is dropped, which aborts the task it runs
+                let _ignore = handle.await;
+            }
+            let mut tasks = TASKS.lock().unwrap();
+            tasks.remove(name);
+        }
+    }
+}
+
+/// runs the task and restarts it until the token is cancelled
+async fn supervise<F, Fut>(name: &'static str, task: F, token: CancellationToken)
+where
+    F: Fn(CancellationToken) -> Fut,
+    Fut: Future + Send + 'static,
+    Fut::Output: Send + 'static,
+{
+    let mut failures = 0;
+    loop {
+        set_state(name, TaskState::Running);
+        let started = Instant::now();
+        //a separate task, so a panic ends the task instead of the supervisor
+        let result = AbortOnDrop(tokio::spawn(task(token.clone()))).await;
+        if token.is_cancelled() {
+            debug!("task {name} stopped");
+            return;
+        }
+        match result {
+            Ok(_) => error!("task {name} finished unexpectedly"),
+            Err(err) => error!("task {name} failed: {err}"),
+        }
+        if started.elapsed() >= STABLE_AFTER {
+            failures = 0;
+        }
+        failures += 1;
+        let delay = RESTART_DELAY
+            .saturating_mul(2u32.saturating_pow(failures - 1))
+            .min(MAX_RESTART_DELAY);
+        info!("restarting task {name} in {delay:?}");
+        set_state(name, TaskState::Restarting { failures });
+        tokio::select! {
+            _ = time::sleep(delay) => {}
+            _ = token.cancelled() => return,
+        }
+    }
+}
+
+/// handle of a task which is aborted when the handle is dropped
+///
+/// aborting the supervisor drops the handle, so the task does not outlive its supervisor
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Future for AbortOnDrop<T> {
+    type Output = <JoinHandle<T> as Future>::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(cx)
+    }
+}
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+fn set_state(name: &'static str, state: TaskState) {
+    let mut tasks = TASKS.lock().unwrap();
+    tasks.insert(name, state);
+}
+
+/// waits for the next tick of the interval, returns false once the token is cancelled
+pub async fn tick(interval: &mut Interval, token: &CancellationToken) -> bool {
+    tokio::select! {
+        _ = interval.tick() => true,
+        _ = token.cancelled() => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn stop_aborts_tasks_ignoring_the_token() {
+        let running = Arc::new(());
+        let mut group = TaskGroup::default();
+        let held = running.clone();
+        group.spawn("stubborn", move |_token| {
+            let held = held.clone();
+            async move {
+                let _held = held;
+                loop {
+                    time::sleep(Duration::from_millis(10)).await;
+                }
+            }
+        });
+        time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(Arc::strong_count(&running), 3);
+        group.stop(Duration::from_millis(50)).await;
+        //the aborted task is dropped the next time the runtime schedules it
+        time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(Arc::strong_count(&running), 1);
+        assert!(!tasks().iter().any(|(name, _)| *name == "stubborn"));
+    }
+
+    #[tokio::test]
+    async fn stop_waits_for_tasks_honouring_the_token() {
+        let mut group = TaskGroup::default();
+        group.spawn("polite", |token: CancellationToken| async move {
+            token.cancelled().await;
+        });
+        time::timeout(Duration::from_secs(1), group.stop(Duration::from_secs(5)))
+            .await
+            .expect("stopped before the timeout");
+    }
+}
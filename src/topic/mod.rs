@@ -1,19 +1,33 @@
-use env_var::env_var;
-use futures::{StreamExt, TryStreamExt};
-use kube::api::{WatchEvent, WatchParams};
+use super::admin::{ExtendedProperties, TopicProperties};
+use super::config::Config;
+use super::controller::{self, Condition, Context, Error, HasConditions, SyncResult};
+use super::destination::{
+    self, AppliedDestination, Changes, ExtendedSpec, UnreportedProperties, DEFAULT_STORE,
+    NO_FLOW_CONTROL,
+};
+use super::health;
+use super::leader;
+use super::server::{self, ServerSessions};
+use super::supervisor;
+use futures::StreamExt;
+use kube::runtime::controller::Action;
+use kube::runtime::finalizer::{finalizer, Event};
 use kube::CustomResource;
 use kube::{
-    api::{Api, PostParams, ResourceExt},
+    api::{Api, Patch, PatchParams, ResourceExt},
     Client,
 };
 use once_cell::sync::Lazy;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::sync::Mutex;
-use tibco_ems::admin::TopicInfo;
-use tibco_ems::Session;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tibco_ems::admin::{OverflowPolicy, TopicInfo};
+use tibco_ems::Destination;
 use tokio::time::{self, Duration};
+use tokio_util::sync::CancellationToken;
 
 #[derive(CustomResource, Serialize, Deserialize, Default, Clone, Debug, JsonSchema)]
 #[kube(
@@ -32,208 +46,395 @@ pub struct TopicSpec {
     pub maxmsgs: Option<i64>,
     pub overflowPolicy: Option<u8>,
     pub prefetch: Option<u32>,
+    /// additional JNDI names bound to the topic
+    pub jndiNames: Option<Vec<String>>,
+    /// name of the EmsServer object within the namespace, defaults to the server of the operator
+    pub server: Option<String>,
+    #[serde(flatten)]
+    pub extended: ExtendedSpec,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
 #[allow(non_snake_case)]
+#[serde(default)]
 pub struct TopicStatus {
     pub pendingMessages: i64,
     pub subscribers: i32,
     pub durables: i32,
+    pub conditions: Vec<Condition>,
+    /// generation of the spec which was last reconciled
+    pub observedGeneration: Option<i64>,
+    /// last time the topic was successfully reconciled with the EMS
+    pub lastSyncTime: Option<String>,
+    /// result of the last failed admin operation
+    pub message: Option<String>,
+    /// JNDI names last bound to the topic, unbound when dropped from the spec
+    pub applied: Option<AppliedDestination>,
 }
 
-pub static KNOWN_TOPICS: Lazy<Mutex<HashMap<String, Topic>>> =
-    Lazy::new(|| Mutex::new(HashMap::new()));
+impl HasConditions for Topic {
+    fn conditions(&self) -> &[Condition] {
+        match &self.status {
+            Some(status) => &status.conditions,
+            None => &[],
+        }
+    }
+}
 
-pub static TOPICS: Lazy<Mutex<HashMap<String, TopicInfo>>> =
+/// topic objects within kubernetes, filled by the controller
+pub static TOPIC_STORE: controller::Stores<Topic> = controller::Stores::new();
+/// all topics present on the EMS servers, keyed by server and topic name
+///
+/// a server is missing until its topics were retrieved
+pub static TOPICS: Lazy<Mutex<HashMap<String, HashMap<String, TopicInfo>>>> =
     Lazy::new(|| Mutex::new(HashMap::new()));
 
-///used for retrieving queue statistics
-static TOPIC_ADMIN_CONNECTION: Lazy<Mutex<Session>> =
-    Lazy::new(|| Mutex::new(super::init_admin_connection()));
-///used for sending admin operations
-static ADMIN_CONNECTION: Lazy<Mutex<Session>> =
-    Lazy::new(|| Mutex::new(super::init_admin_connection()));
+/// number of topics corrected by the drift reconciliation
+pub static TOPIC_DRIFT_CORRECTIONS: AtomicU64 = AtomicU64::new(0);
 
-pub async fn watch_topics() -> Result<(), ()> {
-    let crds: Api<Topic> = get_topic_client().await;
-    let updater: Api<Topic> = crds.clone();
-    let mut lp = WatchParams::default();
+pub async fn watch_topics(config: Arc<Config>, token: CancellationToken) -> Result<(), ()> {
+    let client = controller::client().await?;
+    let ctx = controller::Context::new(client, config, token);
+    let controllers = controller::new_controllers(&ctx, "topics");
+    TOPIC_STORE.register(&controllers);
+    controller::run_controllers(controllers, reconcile, ctx)
+        .for_each(|result| async move {
+            match result {
+                Ok((topic, _action)) => trace!("reconciled topic {}", topic.name),
+                Err(err) => debug!("topic reconciliation failed: {:?}", err),
+            }
+        })
+        .await;
+    Ok(())
+}
 
-    let responsible_for = super::RESPONSIBLE_FOR.lock().unwrap().clone();
-    if !responsible_for.is_empty() {
-        info!("subscribing to events of type topics.tibcoems.apimeister.com/v1 for instance {responsible_for}");
-        lp = lp.labels(format!("tibcoems.apimeister.com/owner={responsible_for}").as_str());
-    } else {
-        lp = lp.labels("!tibcoems.apimeister.com/owner");
-        info!("subscribing to events of type topics.tibcoems.apimeister.com/v1");
-    }
+/// brings the topic on the EMS in line with the topic object
+async fn reconcile(topic: Arc<Topic>, ctx: Arc<Context>) -> Result<Action, Error> {
+    let key = controller::object_key(topic.as_ref());
+    let api: Api<Topic> = Api::namespaced(ctx.client.clone(), &topic.namespace().unwrap());
+    let action = finalizer(&api, controller::FINALIZER, topic, |event| async {
+        match event {
+            Event::Apply(topic) => apply_topic(&api, &topic, &ctx).await,
+            Event::Cleanup(topic) => cleanup_topic(&api, &topic, &ctx).await,
+        }
+    })
+    .await?;
+    ctx.reset_backoff(&key);
+    Ok(action)
+}
 
-    let mut last_version: String = "0".to_owned();
-    loop {
-        debug!("new loop iteration with offset {}", last_version);
-        let watch_result = crds.watch(&lp, &last_version).await;
-        let str_result = match watch_result {
-            Ok(x) => x,
-            Err(_) => continue,
-        };
-        let mut stream = str_result.boxed();
-        loop {
-            let stream_result = stream.try_next().await;
-            let status_obj = match stream_result {
-                Ok(x) => x,
-                Err(e) => {
-                    debug!("error on request loop {:?}", e);
-                    break;
-                }
+/// creates the topic on the EMS or applies changed properties
+async fn apply_topic(api: &Api<Topic>, topic: &Topic, ctx: &Context) -> Result<Action, Error> {
+    let key = controller::object_key(topic);
+    let server = get_server(topic);
+    let sessions = match server::get_sessions(&server) {
+        Ok(sessions) => sessions,
+        Err(message) => {
+            warn!("topic {} cannot be applied: {}", key, message);
+            let result = SyncResult::Failed {
+                exists: false,
+                message,
             };
-            let status = match status_obj {
-                Some(x) => x,
-                None => {
-                    debug!("request loop returned empty");
-                    break;
-                }
+            controller::patch_sync_status(api, topic, result).await;
+            return Ok(Action::requeue(Duration::from_secs(10)));
+        }
+    };
+    let topic_name = get_topic_name(topic);
+    let current = {
+        let c_map = TOPICS.lock().unwrap();
+        match c_map.get(&server) {
+            Some(topics) => topics.get(&topic_name).cloned(),
+            None => {
+                debug!("waiting for topic information from EMS");
+                return Ok(Action::requeue(Duration::from_secs(5)));
+            }
+        }
+    };
+    let spec = get_topic_properties(topic)
+        .and_then(|properties| Ok((properties, destination::jndi_names(&topic.spec.jndiNames)?)));
+    let (properties, jndi_names) = match spec {
+        Ok(spec) => spec,
+        Err(message) => {
+            warn!("topic {} has an invalid spec: {}", topic_name, message);
+            let result = SyncResult::Failed {
+                exists: current.is_some(),
+                message: format!("invalid spec: {message}"),
             };
-            debug!("new stream item");
-
-            match status {
-                WatchEvent::Added(mut topic) => {
-                    let topic_name = get_topic_name(&topic);
-                    last_version = ResourceExt::resource_version(&topic).unwrap();
-                    {
-                        let mut res = KNOWN_TOPICS.lock().unwrap();
-                        match res.get(&topic_name) {
-                            Some(_topic) => debug!("topic already known {}", &topic_name),
-                            None => {
-                                info!("adding topic {}", &topic_name);
-                                create_topic(&mut topic);
-                                res.insert(topic_name, topic.clone());
-                            }
-                        }
-                    }
-                    if topic.status.is_none() {
-                        let name = ResourceExt::name_any(&topic);
-                        let pp = PostParams::default();
-                        let _result = updater.replace_status(&name, &pp, &topic).await;
-                    }
+            controller::patch_sync_status(api, topic, result).await;
+            return Ok(Action::await_change());
+        }
+    };
+    let previous = topic
+        .status
+        .as_ref()
+        .and_then(|status| status.applied.clone());
+    let mut applied = previous.clone().unwrap_or_else(|| AppliedDestination {
+        destination: topic_name.clone(),
+        ..Default::default()
+    });
+    let note = match current {
+        None => {
+            if topic.status.is_some() && !ctx.is_failing(&key) {
+                warn!("topic {} is missing on EMS, recreating it", topic_name);
+                TOPIC_DRIFT_CORRECTIONS.fetch_add(1, Ordering::Relaxed);
+            } else {
+                info!("adding topic {}", topic_name);
+            }
+            let tinfo = match create_topic(&sessions, &properties, ctx.config.admin_command_timeout)
+            {
+                Ok(tinfo) => tinfo,
+                Err(err) => {
+                    let message = format!("failed to create topic {topic_name}: {err}");
+                    let result = SyncResult::Failed {
+                        exists: false,
+                        message,
+                    };
+                    controller::patch_sync_status(api, topic, result).await;
+                    return Err(Error::Ems(err));
                 }
-                WatchEvent::Deleted(topic) => {
-                    let do_not_delete = env_var!(optional "DO_NOT_DELETE_OBJECTS", default:"FALSE");
-                    let topic_name = get_topic_name(&topic);
-                    if do_not_delete == "TRUE" {
-                        warn!("delete event for {} (not executed because of DO_NOT_DELETE_OBJECTS setting)", topic_name);
-                    } else {
-                        delete_topic(&topic);
-                    }
-                    let mut res = KNOWN_TOPICS.lock().unwrap();
-                    res.remove(&topic_name);
-                    last_version = ResourceExt::resource_version(&topic).unwrap();
+            };
+            //reflect new topic until the next statistics refresh
+            {
+                let mut c_map = TOPICS.lock().unwrap();
+                let topics = c_map.entry(server.clone()).or_default();
+                topics.insert(topic_name.clone(), tinfo);
+            }
+            Some(format!("created topic {topic_name}"))
+        }
+        Some(tinfo) => {
+            let spec_changed = ctx.applied_generation(&key) != topic.metadata.generation;
+            if let Some((delta, changes)) =
+                get_topic_delta(&properties, &tinfo, &applied.unreported)
+            {
+                if !spec_changed && !ctx.is_failing(&key) {
+                    warn!(
+                        "topic {} drifted from its spec ({}), correcting it",
+                        topic_name,
+                        changes.join(", ")
+                    );
+                    TOPIC_DRIFT_CORRECTIONS.fetch_add(1, Ordering::Relaxed);
+                } else {
+                    info!("updating topic {}: {}", topic_name, changes.join(", "));
                 }
-                WatchEvent::Error(e) => {
-                    if e.code == 410 && e.reason == "Expired" {
-                        //fail silently
-                        trace!("resource_version too old, resetting offset to 0");
-                    } else {
-                        error!("Error {:?}", e);
-                        error!("resetting offset to 0");
-                    }
-                    "0".clone_into(&mut last_version);
+                if let Err(err) =
+                    update_topic(&sessions, &server, &delta, ctx.config.admin_command_timeout)
+                {
+                    let message = format!("failed to update {}: {}", changes.join(", "), err);
+                    let result = SyncResult::Failed {
+                        exists: true,
+                        message,
+                    };
+                    controller::patch_sync_status(api, topic, result).await;
+                    return Err(Error::Ems(err));
                 }
-                _ => {}
-            };
+                Some(format!("updated {}", changes.join(", ")))
+            } else {
+                None
+            }
         }
+    };
+    applied.unreported = get_unreported(&properties);
+    let jndi_result = sessions.admin().and_then(|session| {
+        let destination = Destination::Topic(topic_name.clone());
+        destination::sync_jndi_names(
+            &session,
+            &mut applied,
+            &destination,
+            &jndi_names,
+            ctx.config.admin_command_timeout,
+        )
+    });
+    //names bound or unbound before a failure are kept as well
+    if previous.as_ref() != Some(&applied) {
+        controller::patch_applied(api, topic, Some(&applied)).await;
     }
+    let jndi_note = match jndi_result {
+        Ok(jndi_note) => jndi_note,
+        Err(err) => {
+            let message = format!("failed to bind JNDI names of topic {topic_name}: {err}");
+            let result = SyncResult::Failed {
+                exists: true,
+                message,
+            };
+            controller::patch_sync_status(api, topic, result).await;
+            return Err(Error::Ems(err));
+        }
+    };
+    let note = match (note, jndi_note) {
+        (Some(note), Some(jndi_note)) => Some(format!("{note}, {jndi_note}")),
+        (note, jndi_note) => note.or(jndi_note),
+    };
+    controller::patch_sync_status(api, topic, SyncResult::Synced(note)).await;
+    ctx.set_applied_generation(&key, topic.metadata.generation);
+    Ok(Action::requeue(ctx.requeue_interval()))
 }
 
-pub async fn watch_topics_status() -> Result<(), ()> {
-    let status_refresh_in_ms: u64 = env_var!(optional "STATUS_REFRESH_IN_MS", default: "10000")
-        .parse()
-        .unwrap();
-    let read_only = env_var!(optional "READ_ONLY", default:"FALSE");
-    let mut interval = time::interval(Duration::from_millis(status_refresh_in_ms));
-    loop {
-        let result;
-        {
-            let session = TOPIC_ADMIN_CONNECTION.lock().unwrap();
-            result = tibco_ems::admin::list_all_topics(&session);
+/// removes the topic from the EMS before kubernetes deletes the object
+///
+/// a failed deletion keeps the finalizer, so the object stays until the topic is gone
+async fn cleanup_topic(api: &Api<Topic>, topic: &Topic, ctx: &Context) -> Result<Action, Error> {
+    let topic_name = get_topic_name(topic);
+    if ctx.config.do_not_delete_objects {
+        warn!(
+            "delete event for {} (not executed because of DO_NOT_DELETE_OBJECTS setting)",
+            topic_name
+        );
+        return Ok(Action::await_change());
+    }
+    let key = controller::object_key(topic);
+    let server = get_server(topic);
+    let sessions = match server::get_sessions(&server) {
+        Ok(sessions) => sessions,
+        Err(message) => {
+            warn!("topic {} cannot be deleted: {}", key, message);
+            let result = SyncResult::Failed {
+                exists: true,
+                message,
+            };
+            controller::patch_sync_status(api, topic, result).await;
+            return Ok(Action::requeue(Duration::from_secs(10)));
         }
-        let res: Vec<tibco_ems::admin::TopicInfo> = match result {
-            Ok(x) => x,
-            Err(_err) => {
-                panic!("failed to retrieve topic information");
+    };
+    let applied = topic
+        .status
+        .as_ref()
+        .and_then(|status| status.applied.as_ref());
+    let jndi_result = sessions.admin().and_then(|session| {
+        destination::remove_jndi_names(&session, applied, ctx.config.admin_command_timeout)
+    });
+    match jndi_result.and_then(|_| delete_topic(&sessions, topic, ctx.config.admin_command_timeout))
+    {
+        Ok(_) => {
+            let mut c_map = TOPICS.lock().unwrap();
+            if let Some(topics) = c_map.get_mut(&server) {
+                topics.remove(&topic_name);
             }
-        };
+            Ok(Action::await_change())
+        }
+        Err(err) => {
+            let message = format!("failed to delete topic {topic_name}: {err}");
+            let result = SyncResult::Failed {
+                exists: true,
+                message,
+            };
+            controller::patch_sync_status(api, topic, result).await;
+            Err(Error::Ems(err))
+        }
+    }
+}
 
-        for tinfo in res {
+pub async fn watch_topics_status(config: Arc<Config>, token: CancellationToken) -> Result<(), ()> {
+    let client = controller::client().await?;
+    let mut interval = time::interval(config.status_refresh);
+    loop {
+        health::heartbeat("topics_status");
+        let all_sessions = server::all_sessions();
+        for (server, sessions) in &all_sessions {
+            let result = sessions.statistics().and_then(|session| {
+                tibco_ems::admin::list_all_topics(&session)
+                    .inspect_err(|err| sessions.mark_broken(&session, err))
+            });
+            let res: Vec<tibco_ems::admin::TopicInfo> = match result {
+                Ok(x) => x,
+                Err(err) => {
+                    error!(
+                        "failed to retrieve topic information from {}: {}",
+                        server::display_name(server),
+                        err
+                    );
+                    continue;
+                }
+            };
+            if server == server::DEFAULT_SERVER {
+                health::listed("topics");
+            }
             //update prometheus
             {
                 let mut c_map = TOPICS.lock().unwrap();
-                c_map.insert(tinfo.name.clone(), tinfo.clone());
+                let topics = res.iter().map(|t| (t.name.clone(), t.clone())).collect();
+                c_map.insert(server.clone(), topics);
             }
-            //update k8s state
-            if read_only == "FALSE" {
-                let mut t: Option<Topic> = None;
-                {
-                    let mut res = KNOWN_TOPICS.lock().unwrap();
-                    let topic = match res.get(&tinfo.name) {
-                        Some(x) => x,
-                        None => continue,
-                    };
-                    let update = match &topic.status {
-                        Some(status)
-                            if status.pendingMessages != tinfo.pending_messages.unwrap() =>
-                        {
-                            true
-                        }
-                        Some(status) if status.subscribers != tinfo.subscriber_count.unwrap() => {
-                            true
-                        }
-                        Some(status) if status.durables != tinfo.durable_count.unwrap() => true,
-                        None => true,
-                        _ => false,
-                    };
+            if !config.read_only && leader::is_leader() {
+                update_topics_status(&client, server, res).await;
+            }
+        }
+        //forget the topics of removed servers
+        {
+            let mut c_map = TOPICS.lock().unwrap();
+            c_map.retain(|server, _| all_sessions.iter().any(|(key, _)| key == server));
+        }
+        if !supervisor::tick(&mut interval, &token).await {
+            return Ok(());
+        }
+    }
+}
 
-                    if update {
-                        let mut updated_topic = topic.clone();
-                        updated_topic.status = Some(TopicStatus {
-                            pendingMessages: tinfo.pending_messages.unwrap(),
-                            subscribers: tinfo.subscriber_count.unwrap(),
-                            durables: tinfo.durable_count.unwrap(),
-                        });
-                        t = Some(updated_topic.clone());
-                        res.insert(tinfo.name.to_owned(), updated_topic);
-                    }
-                }
-                let mut local_topic = match t {
-                    Some(x) => x,
-                    None => continue,
-                };
+/// updates the status of the topic objects of a server
+async fn update_topics_status(client: &Client, server: &str, res: Vec<TopicInfo>) {
+    //topic objects by their EMS name
+    let known_topics: HashMap<String, Arc<Topic>> = TOPIC_STORE
+        .state()
+        .into_iter()
+        .filter(|t| get_server(t) == server)
+        .map(|t| (get_topic_name(&t), t))
+        .collect();
 
-                let obj_name = get_obj_name_from_topic(&local_topic);
-                debug!("updating topic status for {}", obj_name);
-                let updater: Api<Topic> = get_topic_client().await;
-                let latest_topic: Topic = updater.get(&obj_name).await.unwrap();
-                local_topic.metadata.resource_version =
-                    ResourceExt::resource_version(&latest_topic);
-                let pp = PostParams::default();
-                let result = updater.replace_status(&obj_name, &pp, &local_topic).await;
-                match result {
-                    Ok(_ignore) => {}
-                    Err(err) => {
-                        error!("error while updating topic object");
-                        error!("{:?}", err);
-                    }
-                }
+    for tinfo in res {
+        let topic = match known_topics.get(&tinfo.name) {
+            Some(x) => x,
+            None => continue,
+        };
+        let pending_messages = tinfo.pending_messages.unwrap_or(0);
+        let subscribers = tinfo.subscriber_count.unwrap_or(0);
+        let durables = tinfo.durable_count.unwrap_or(0);
+        let update = match &topic.status {
+            Some(status) if status.pendingMessages != pending_messages => true,
+            Some(status) if status.subscribers != subscribers => true,
+            Some(status) if status.durables != durables => true,
+            None => true,
+            _ => false,
+        };
+        if !update {
+            continue;
+        }
+
+        let obj_name = topic.name_any();
+        debug!("updating topic status for {}", obj_name);
+        let updater: Api<Topic> = Api::namespaced(client.clone(), &topic.namespace().unwrap());
+        let status = serde_json::json!({
+            "status": {
+                "pendingMessages": pending_messages,
+                "subscribers": subscribers,
+                "durables": durables,
+            }
+        });
+        let pp = PatchParams::default();
+        let result = updater
+            .patch_status(&obj_name, &pp, &Patch::Merge(&status))
+            .await;
+        match result {
+            Ok(_ignore) => {}
+            Err(err) => {
+                error!("error while updating topic object");
+                error!("{:?}", err);
             }
         }
-        interval.tick().await;
     }
 }
 
-async fn get_topic_client() -> Api<Topic> {
-    let client = Client::try_default().await.expect("getting default client");
-    let namespace = env_var!(required "KUBERNETES_NAMESPACE");
-    Api::namespaced(client, &namespace)
+/// namespaces of the topic objects of a server by their EMS name
+pub fn get_topic_namespaces(server: &str) -> HashMap<String, String> {
+    TOPIC_STORE
+        .state()
+        .into_iter()
+        .filter(|t| get_server(t) == server)
+        .map(|t| (get_topic_name(&t), t.namespace().unwrap_or_default()))
+        .collect()
+}
+
+/// key of the EMS server the topic belongs to
+fn get_server(topic: &Topic) -> String {
+    server::server_key(&topic.namespace().unwrap_or_default(), &topic.spec.server)
 }
 
 fn get_topic_name(topic: &Topic) -> String {
@@ -251,75 +452,283 @@ fn get_topic_name(topic: &Topic) -> String {
     tname
 }
 
-fn get_obj_name_from_topic(topic: &Topic) -> String {
-    topic.metadata.name.clone().unwrap()
-}
-
-fn create_topic(topic: &mut Topic) {
-    let tname = get_topic_name(topic);
-
-    let mut topic_info = TopicInfo {
-        name: tname,
+/// validates the topic spec and collects all properties which are set
+fn get_topic_properties(topic: &Topic) -> Result<TopicProperties, String> {
+    destination::validate_overflow_policy(topic.spec.overflowPolicy)?;
+    Ok(TopicProperties {
+        name: get_topic_name(topic),
         max_bytes: topic.spec.maxbytes,
         max_messages: topic.spec.maxmsgs,
+        overflow_policy: topic.spec.overflowPolicy.map(|val| val as i32),
         global: topic.spec.global,
+        prefetch: topic.spec.prefetch.map(|val| val as i32),
+        expiry_override: topic.spec.expiration.map(|val| val as i64),
+        extended: topic.spec.extended.properties()?,
+    })
+}
+
+/// creates a topic within the ems
+///
+/// returns the topic information as sent to the ems
+fn create_topic(
+    sessions: &ServerSessions,
+    properties: &TopicProperties,
+    timeout: Duration,
+) -> Result<TopicInfo, std::io::Error> {
+    {
+        let session = sessions.admin()?;
+        super::admin::create_topic(&session, properties, timeout)?;
+    }
+    debug!("topic created successful");
+    let mut topic_info = TopicInfo {
+        name: properties.name.clone(),
+        pending_messages: Some(0),
+        subscriber_count: Some(0),
+        durable_count: Some(0),
+        incoming_total_count: Some(0),
+        outgoing_total_count: Some(0),
         ..Default::default()
     };
+    apply_topic_delta(&mut topic_info, properties);
+    Ok(topic_info)
+}
 
-    if let Some(val) = topic.spec.expiration {
-        topic_info.expiry_override = Some(val as i64);
-    };
-    if let Some(val) = topic.spec.prefetch {
-        topic_info.prefetch = Some(val as i32);
-    }
-    let session = ADMIN_CONNECTION.lock().unwrap();
-    let result = tibco_ems::admin::create_topic(&session, &topic_info);
-    match result {
-        Ok(_) => {
-            debug!("topic created successful");
-        }
-        Err(err) => {
-            error!("failed to create topic: {:?}", err);
-            panic!("failed to create topic");
-        }
+/// applies changed topic properties to the ems
+fn update_topic(
+    sessions: &ServerSessions,
+    server: &str,
+    delta: &TopicProperties,
+    timeout: Duration,
+) -> Result<(), std::io::Error> {
+    let session = sessions.admin()?;
+    super::admin::update_topic(&session, delta, timeout)?;
+    debug!("topic updated successful");
+    //reflect new values until the next statistics refresh
+    let mut c_map = TOPICS.lock().unwrap();
+    if let Some(tinfo) = c_map
+        .get_mut(server)
+        .and_then(|topics| topics.get_mut(&delta.name))
+    {
+        apply_topic_delta(tinfo, delta);
     }
+    Ok(())
+}
 
-    //propagate defaults
-    if topic.spec.maxmsgs.is_none() {
-        topic.spec.maxmsgs = Some(0)
-    };
-    if topic.spec.expiration.is_none() {
-        topic.spec.expiration = Some(0)
+/// computes the properties of the spec which differ from the topic on the ems
+///
+/// returns None if the ems topic already matches the spec, otherwise the delta
+/// together with the names of the changed properties
+fn get_topic_delta(
+    spec: &TopicProperties,
+    tinfo: &TopicInfo,
+    applied: &UnreportedProperties,
+) -> Option<(TopicProperties, Vec<&'static str>)> {
+    let mut changes = Changes::default();
+    let extended = &spec.extended;
+    let overflow_policy = tinfo.overflow_policy.clone().map(|val| val as i32);
+    let delta = TopicProperties {
+        name: tinfo.name.clone(),
+        max_bytes: changes.reported("maxbytes", &spec.max_bytes, &tinfo.max_bytes, 0),
+        max_messages: changes.reported("maxmsgs", &spec.max_messages, &tinfo.max_messages, 0),
+        overflow_policy: changes.reported(
+            "overflowPolicy",
+            &spec.overflow_policy,
+            &overflow_policy,
+            0,
+        ),
+        expiry_override: changes.reported(
+            "expiration",
+            &spec.expiry_override,
+            &tinfo.expiry_override,
+            0,
+        ),
+        prefetch: changes.reported("prefetch", &spec.prefetch, &tinfo.prefetch, 0),
+        global: changes.reported("global", &spec.global, &tinfo.global, false),
+        //the ems does not report the extended properties of topics, so they are compared
+        //with the applied ones
+        extended: ExtendedProperties {
+            failsafe: changes.unreported("failsafe", &extended.failsafe, &applied.failsafe, false),
+            secure: changes.unreported("secure", &extended.secure, &applied.secure, false),
+            flow_control: changes.unreported(
+                "flowControl",
+                &extended.flow_control,
+                &applied.flowControl,
+                NO_FLOW_CONTROL,
+            ),
+            store: changes.unreported(
+                "store",
+                &extended.store,
+                &applied.store,
+                DEFAULT_STORE.to_owned(),
+            ),
+            trace: changes.unreported("trace", &extended.trace, &applied.trace, String::new()),
+            sender_name: changes.unreported(
+                "senderName",
+                &extended.sender_name,
+                &applied.senderName,
+                false,
+            ),
+            sender_name_enforced: changes.unreported(
+                "senderNameEnforced",
+                &extended.sender_name_enforced,
+                &applied.senderNameEnforced,
+                false,
+            ),
+            import: changes.unreported("import", &extended.import, &applied.import, String::new()),
+            export: changes.unreported("export", &extended.export, &applied.export, String::new()),
+        },
     };
-    topic.spec.overflowPolicy = Some(0);
-    if let Some(val) = topic.spec.prefetch {
-        topic.spec.prefetch = Some(val);
+    if changes.0.is_empty() {
+        None
     } else {
-        topic.spec.prefetch = Some(0);
+        Some((delta, changes.0))
+    }
+}
+
+/// properties of the topic which the EMS does not report, as they are sent to it
+fn get_unreported(spec: &TopicProperties) -> UnreportedProperties {
+    let extended = &spec.extended;
+    UnreportedProperties {
+        failsafe: extended.failsafe,
+        secure: extended.secure,
+        senderName: extended.sender_name,
+        senderNameEnforced: extended.sender_name_enforced,
+        flowControl: extended.flow_control,
+        store: extended.store.clone(),
+        trace: extended.trace.clone(),
+        import: extended.import.clone(),
+        export: extended.export.clone(),
+        ..Default::default()
     }
-    topic.spec.global = Some(false);
-    topic.spec.maxbytes = Some(0);
-    let status = TopicStatus {
-        pendingMessages: 0,
-        subscribers: 0,
-        durables: 0,
-    };
-    topic.status = Some(status);
 }
 
-fn delete_topic(topic: &Topic) {
+/// copies all properties set on the delta onto the topic info
+fn apply_topic_delta(tinfo: &mut TopicInfo, delta: &TopicProperties) {
+    if delta.max_bytes.is_some() {
+        tinfo.max_bytes = delta.max_bytes;
+    }
+    if delta.max_messages.is_some() {
+        tinfo.max_messages = delta.max_messages;
+    }
+    if let Some(val) = delta.overflow_policy {
+        tinfo.overflow_policy = Some(match val {
+            1 => OverflowPolicy::DiscardOld,
+            2 => OverflowPolicy::RejectIncoming,
+            _ => OverflowPolicy::Default,
+        });
+    }
+    if delta.expiry_override.is_some() {
+        tinfo.expiry_override = delta.expiry_override;
+    }
+    if delta.prefetch.is_some() {
+        tinfo.prefetch = delta.prefetch;
+    }
+    if delta.global.is_some() {
+        tinfo.global = delta.global;
+    }
+}
+
+fn delete_topic(
+    sessions: &ServerSessions,
+    topic: &Topic,
+    timeout: Duration,
+) -> Result<(), std::io::Error> {
     let tname = get_topic_name(topic);
     info!("deleting topic {}", tname);
 
-    let session = ADMIN_CONNECTION.lock().unwrap();
-    let result = tibco_ems::admin::delete_topic(&session, &tname);
-    match result {
-        Ok(_) => {
-            debug!("topic deleted");
-        }
-        Err(err) => {
-            error!("failed to delete topic: {:?}", err);
-            panic!("failed to delete topic");
+    let session = sessions.admin()?;
+    match super::admin::delete_topic(&session, &tname, timeout) {
+        Ok(_) => debug!("topic deleted"),
+        Err(err) if err.kind() == ErrorKind::NotFound => debug!("topic does not exist"),
+        Err(err) => return Err(err),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tinfo() -> TopicInfo {
+        TopicInfo {
+            name: "t.test".to_string(),
+            global: Some(true),
+            prefetch: Some(5),
+            ..Default::default()
         }
     }
+
+    #[test]
+    fn matching_topic_has_no_delta() {
+        let spec = TopicProperties {
+            name: "t.test".to_string(),
+            global: Some(true),
+            prefetch: Some(5),
+            ..Default::default()
+        };
+        assert_eq!(
+            get_topic_delta(&spec, &tinfo(), &UnreportedProperties::default()),
+            None
+        );
+    }
+
+    #[test]
+    fn removed_properties_are_reset_to_the_default() {
+        let spec = TopicProperties {
+            name: "t.test".to_string(),
+            prefetch: Some(5),
+            max_messages: Some(100),
+            ..Default::default()
+        };
+        let (delta, changes) =
+            get_topic_delta(&spec, &tinfo(), &UnreportedProperties::default()).unwrap();
+        assert_eq!(delta.global, Some(false));
+        assert_eq!(delta.max_messages, Some(100));
+        assert_eq!(delta.prefetch, None);
+        assert_eq!(changes, vec!["maxmsgs", "global"]);
+    }
+
+    #[test]
+    fn extended_properties_are_only_sent_when_not_applied() {
+        let spec = TopicProperties {
+            name: "t.test".to_string(),
+            global: Some(true),
+            prefetch: Some(5),
+            extended: ExtendedProperties {
+                secure: Some(true),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let applied = get_unreported(&spec);
+        assert_eq!(get_topic_delta(&spec, &tinfo(), &applied), None);
+        let (delta, changes) =
+            get_topic_delta(&spec, &tinfo(), &UnreportedProperties::default()).unwrap();
+        assert_eq!(delta.extended.secure, Some(true));
+        assert_eq!(changes, vec!["secure"]);
+    }
+
+    #[test]
+    fn removed_extended_properties_are_reset() {
+        let spec = TopicProperties {
+            name: "t.test".to_string(),
+            global: Some(true),
+            prefetch: Some(5),
+            ..Default::default()
+        };
+        let applied = UnreportedProperties {
+            secure: Some(true),
+            flowControl: Some(1024),
+            store: Some("$sys.failsafe".to_string()),
+            export: Some("rv".to_string()),
+            ..Default::default()
+        };
+        let (delta, changes) = get_topic_delta(&spec, &tinfo(), &applied).unwrap();
+        assert_eq!(delta.extended.secure, Some(false));
+        assert_eq!(delta.extended.flow_control, Some(NO_FLOW_CONTROL));
+        assert_eq!(delta.extended.store.as_deref(), Some(DEFAULT_STORE));
+        assert_eq!(delta.extended.export.as_deref(), Some(""));
+        assert_eq!(delta.extended.trace, None);
+        assert_eq!(changes, vec!["secure", "flowControl", "store", "export"]);
+    }
 }
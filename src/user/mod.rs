@@ -0,0 +1,367 @@
+use super::admin::{self, UserInfo};
+use super::config::Config;
+use super::controller::{self, Condition, Context, Error, HasConditions, SyncResult};
+use super::server;
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::Secret;
+use kube::api::{ObjectMeta, Patch, PatchParams, PostParams};
+use kube::runtime::controller::Action;
+use kube::runtime::finalizer::{finalizer, Event};
+use kube::{
+    api::{Api, ResourceExt},
+    Client,
+};
+use kube::{CustomResource, Resource};
+use rand::distr::{Alphanumeric, SampleString};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use tibco_ems::Session;
+use tokio::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// length of generated passwords
+const PASSWORD_LENGTH: usize = 32;
+
+#[derive(CustomResource, Serialize, Deserialize, Default, Clone, Debug, PartialEq, JsonSchema)]
+#[kube(
+    group = "tibcoems.apimeister.com",
+    version = "v1",
+    kind = "User",
+    status = "UserStatus",
+    namespaced
+)]
+#[allow(non_snake_case)]
+pub struct UserSpec {
+    /// name of the user on the EMS, defaults to the object name
+    pub name: Option<String>,
+    pub description: Option<String>,
+    /// secret holding the password, it is created with a generated password if missing
+    pub passwordSecretRef: Option<SecretKeyRef>,
+    /// name of the EmsServer object within the namespace, defaults to the server of the operator
+    pub server: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SecretKeyRef {
+    /// name of the secret, defaults to <object name>-ems-user
+    pub name: Option<String>,
+    /// key within the secret, defaults to password
+    pub key: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[allow(non_snake_case)]
+#[serde(default)]
+pub struct UserStatus {
+    pub conditions: Vec<Condition>,
+    /// generation of the spec which was last reconciled
+    pub observedGeneration: Option<i64>,
+    /// last time the user was successfully reconciled with the EMS
+    pub lastSyncTime: Option<String>,
+    /// result of the last failed admin operation
+    pub message: Option<String>,
+    /// spec of the user last applied to the EMS, used to rename, move and delete it
+    pub applied: Option<UserSpec>,
+}
+
+impl HasConditions for User {
+    fn conditions(&self) -> &[Condition] {
+        match &self.status {
+            Some(status) => &status.conditions,
+            None => &[],
+        }
+    }
+}
+
+/// user objects within kubernetes, filled by the controller
+pub static USER_STORE: controller::Stores<User> = controller::Stores::new();
+
+pub async fn watch_users(config: Arc<Config>, token: CancellationToken) -> Result<(), ()> {
+    let client = controller::client().await?;
+    let ctx = controller::Context::new(client, config, token);
+    let controllers = controller::new_controllers(&ctx, "users");
+    USER_STORE.register(&controllers);
+    controller::run_controllers(controllers, reconcile, ctx)
+        .for_each(|result| async move {
+            match result {
+                Ok((user, _action)) => trace!("reconciled user {}", user.name),
+                Err(err) => debug!("user reconciliation failed: {:?}", err),
+            }
+        })
+        .await;
+    Ok(())
+}
+
+/// brings the user on the EMS in line with the user object
+async fn reconcile(user: Arc<User>, ctx: Arc<Context>) -> Result<Action, Error> {
+    let key = controller::object_key(user.as_ref());
+    let api: Api<User> = Api::namespaced(ctx.client.clone(), &user.namespace().unwrap());
+    let action = finalizer(&api, controller::FINALIZER, user, |event| async {
+        match event {
+            Event::Apply(user) => apply_user(&api, &user, &ctx).await,
+            Event::Cleanup(user) => cleanup_user(&api, &user, &ctx).await,
+        }
+    })
+    .await?;
+    ctx.reset_backoff(&key);
+    Ok(action)
+}
+
+/// creates the user on the EMS or updates its password and description
+///
+/// the password is sent on every reconciliation, as the EMS does not report it, so a
+/// changed secret is applied within DRIFT_RECONCILE_INTERVAL_IN_MS. The user last applied
+/// is kept in the status, so a renamed or moved user is removed after a restart too
+async fn apply_user(api: &Api<User>, user: &User, ctx: &Context) -> Result<Action, Error> {
+    let key = controller::object_key(user);
+    let namespace = user.namespace().unwrap_or_default();
+    let applied = user
+        .status
+        .as_ref()
+        .and_then(|status| status.applied.as_ref());
+    let server = get_server(&namespace, &user.spec);
+    let sessions = match server::get_sessions(&server) {
+        Ok(sessions) => sessions,
+        Err(message) => {
+            warn!("user {} cannot be applied: {}", key, message);
+            let result = SyncResult::Failed {
+                exists: applied.is_some(),
+                message,
+            };
+            controller::patch_sync_status(api, user, result).await;
+            return Ok(Action::requeue(Duration::from_secs(10)));
+        }
+    };
+    let password = match get_password(ctx.client.clone(), user).await {
+        Ok(password) => password,
+        Err(err) => {
+            let result = SyncResult::Failed {
+                exists: applied.is_some(),
+                message: format!("failed to get password: {err}"),
+            };
+            controller::patch_sync_status(api, user, result).await;
+            return Err(err);
+        }
+    };
+    let user_info = UserInfo {
+        name: get_user_name(user, &user.spec),
+        description: user.spec.description.clone(),
+        password,
+    };
+    let timeout = ctx.config.admin_command_timeout;
+    let result = match sessions.admin() {
+        Ok(session) => match applied {
+            Some(old) if get_server(&namespace, old) != server => {
+                info!("moving user {} to {}", &key, server::display_name(&server));
+                create_or_update_user(&session, &user_info, timeout)
+                    .map(|_| {
+                        let old_server = get_server(&namespace, old);
+                        remove_moved_user(&old_server, &get_user_name(user, old), timeout);
+                        Some("moved user".to_owned())
+                    })
+                    .map_err(|err| (false, err))
+            }
+            Some(old) if get_user_name(user, old) != user_info.name => {
+                info!("renaming user {}", &key);
+                create_or_update_user(&session, &user_info, timeout)
+                    .and_then(|_| {
+                        let old_name = get_user_name(user, old);
+                        match admin::delete_user(&session, &old_name, timeout) {
+                            Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+                            _ => Ok(Some("replaced user".to_owned())),
+                        }
+                    })
+                    .map_err(|err| (true, err))
+            }
+            Some(old) => {
+                debug!("updating user {}", &key);
+                let note = if old == &user.spec {
+                    None
+                } else {
+                    Some("updated user".to_owned())
+                };
+                admin::update_user(&session, &user_info, timeout)
+                    .map(|_| note)
+                    .map_err(|err| (true, err))
+            }
+            None => {
+                info!("adding user {}", &key);
+                create_or_update_user(&session, &user_info, timeout).map_err(|err| (false, err))
+            }
+        },
+        Err(err) => Err((applied.is_some(), err)),
+    };
+    match result {
+        Ok(note) => {
+            if applied != Some(&user.spec) {
+                controller::patch_applied(api, user, Some(&user.spec)).await;
+            }
+            controller::patch_sync_status(api, user, SyncResult::Synced(note)).await;
+            Ok(Action::requeue(ctx.requeue_interval()))
+        }
+        Err((exists, err)) => {
+            let message = format!("failed to apply user: {err}");
+            let result = SyncResult::Failed { exists, message };
+            controller::patch_sync_status(api, user, result).await;
+            Err(Error::Ems(err))
+        }
+    }
+}
+
+/// creates the user, a user which already exists is updated instead
+///
+/// the user might have been created by hand or by a run whose status update got lost
+fn create_or_update_user(
+    session: &Session,
+    user_info: &UserInfo,
+    timeout: Duration,
+) -> Result<Option<String>, std::io::Error> {
+    match admin::create_user(session, user_info, timeout) {
+        Ok(_) => Ok(Some("created user".to_owned())),
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+            debug!("user {} already exists, updating it", user_info.name);
+            admin::update_user(session, user_info, timeout).map(|_| Some("updated user".to_owned()))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// removes a user from the server it was moved away from
+///
+/// a failure only leaves the user behind on the previous server, so it is logged
+fn remove_moved_user(server: &str, user_name: &str, timeout: Duration) {
+    let result = server::get_sessions(server)
+        .map_err(std::io::Error::other)
+        .and_then(|sessions| {
+            let session = sessions.admin()?;
+            admin::delete_user(&session, user_name, timeout)
+        });
+    match result {
+        Err(err) if err.kind() != ErrorKind::NotFound => warn!(
+            "failed to remove user {} from previous server {}: {}",
+            user_name,
+            server::display_name(server),
+            err
+        ),
+        _ => {}
+    }
+}
+
+/// removes the user last applied from the EMS before kubernetes deletes the object
+///
+/// a generated password secret is owned by the user object and removed by kubernetes
+async fn cleanup_user(api: &Api<User>, user: &User, ctx: &Context) -> Result<Action, Error> {
+    let key = controller::object_key(user);
+    if ctx.config.do_not_delete_objects {
+        warn!(
+            "delete event for {} (not executed because of DO_NOT_DELETE_OBJECTS setting)",
+            key
+        );
+        return Ok(Action::await_change());
+    }
+    let spec = match user
+        .status
+        .as_ref()
+        .and_then(|status| status.applied.as_ref())
+    {
+        Some(applied) => applied,
+        None => &user.spec,
+    };
+    let server = get_server(&user.namespace().unwrap_or_default(), spec);
+    let sessions = match server::get_sessions(&server) {
+        Ok(sessions) => sessions,
+        Err(message) => {
+            warn!("user {} cannot be deleted: {}", key, message);
+            let result = SyncResult::Failed {
+                exists: true,
+                message,
+            };
+            controller::patch_sync_status(api, user, result).await;
+            return Ok(Action::requeue(Duration::from_secs(10)));
+        }
+    };
+    let user_name = get_user_name(user, spec);
+    info!("deleting user {}", user_name);
+    let result = sessions.admin().and_then(|session| {
+        admin::delete_user(&session, &user_name, ctx.config.admin_command_timeout)
+    });
+    match result {
+        Err(err) if err.kind() != ErrorKind::NotFound => {
+            let message = format!("failed to delete user {user_name}: {err}");
+            let result = SyncResult::Failed {
+                exists: true,
+                message,
+            };
+            controller::patch_sync_status(api, user, result).await;
+            Err(Error::Ems(err))
+        }
+        _ => Ok(Action::await_change()),
+    }
+}
+
+/// key of the EMS server the user belongs to
+fn get_server(namespace: &str, spec: &UserSpec) -> String {
+    server::server_key(namespace, &spec.server)
+}
+
+/// name of the user on the EMS for the given spec, defaults to the object name
+fn get_user_name(user: &User, spec: &UserSpec) -> String {
+    match &spec.name {
+        Some(name) => name.clone(),
+        None => user.name_any(),
+    }
+}
+
+/// reads the password from the referenced secret
+///
+/// a missing secret is created and a missing key is added, both with a generated password
+async fn get_password(client: Client, user: &User) -> Result<String, Error> {
+    let secret_ref = user.spec.passwordSecretRef.clone().unwrap_or_default();
+    let secret_name = secret_ref
+        .name
+        .unwrap_or_else(|| format!("{}-ems-user", user.name_any()));
+    let secret_key = secret_ref.key.unwrap_or_else(|| "password".to_owned());
+    let secrets: Api<Secret> = Api::namespaced(client, &user.namespace().unwrap());
+    let secret = secrets.get_opt(&secret_name).await?;
+    if let Some(secret) = &secret
+        && let Some(value) = secret.data.as_ref().and_then(|data| data.get(&secret_key))
+    {
+        return String::from_utf8(value.0.clone()).map_err(|_| {
+            Error::Secret(format!(
+                "key {secret_key} of secret {secret_name} is not valid UTF-8"
+            ))
+        });
+    }
+    let password = Alphanumeric.sample_string(&mut rand::rng(), PASSWORD_LENGTH);
+    let string_data = BTreeMap::from([
+        (secret_key.clone(), password.clone()),
+        ("username".to_owned(), get_user_name(user, &user.spec)),
+    ]);
+    match secret {
+        Some(_) => {
+            info!("adding generated password to secret {}", secret_name);
+            let patch = serde_json::json!({ "stringData": { secret_key: password } });
+            let pp = PatchParams::default();
+            secrets
+                .patch(&secret_name, &pp, &Patch::Merge(&patch))
+                .await?;
+        }
+        None => {
+            info!("creating secret {} with a generated password", secret_name);
+            let secret = Secret {
+                metadata: ObjectMeta {
+                    name: Some(secret_name),
+                    owner_references: user.controller_owner_ref(&()).map(|owner| vec![owner]),
+                    ..Default::default()
+                },
+                string_data: Some(string_data),
+                ..Default::default()
+            };
+            secrets.create(&PostParams::default(), &secret).await?;
+        }
+    }
+    Ok(password)
+}